@@ -0,0 +1,17 @@
+CREATE TABLE sessions (
+  -- SHA-256 hash of the session ID, like API tokens.
+  session_id string UNIQUE NOT NULL,
+  username   string NOT NULL,
+
+  -- Creation and last seen times in seconds since the epoch.
+  created    INTEGER NOT NULL,
+  updated    INTEGER NOT NULL,
+
+  -- The role of a session not backed by a user, such as a login when only a
+  -- username is required. Sessions of users take their role from the users
+  -- table, and a session with neither has no access.
+  role       string
+);
+
+CREATE INDEX sessions_username_index
+  ON sessions (username);
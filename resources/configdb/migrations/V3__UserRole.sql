@@ -0,0 +1,3 @@
+-- The role of the user. Existing users keep full access as an admin, agent
+-- users may only submit events.
+ALTER TABLE users ADD COLUMN role string NOT NULL DEFAULT 'admin';
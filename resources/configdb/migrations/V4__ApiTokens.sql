@@ -0,0 +1,15 @@
+CREATE TABLE api_tokens (
+  uuid        string UNIQUE NOT NULL,
+  username    string NOT NULL,
+  description string,
+
+  -- SHA-256 of the token, the token itself is not stored.
+  token_hash  string UNIQUE NOT NULL,
+
+  -- Creation and last used times in seconds since the epoch.
+  created     INTEGER NOT NULL,
+  last_used   INTEGER
+);
+
+CREATE INDEX api_tokens_username_index
+  ON api_tokens (username);
@@ -0,0 +1,6 @@
+-- The identity of users logging in with OpenID Connect.
+ALTER TABLE users ADD COLUMN oidc_issuer string;
+ALTER TABLE users ADD COLUMN oidc_subject string;
+
+CREATE UNIQUE INDEX users_oidc_index
+  ON users (oidc_issuer, oidc_subject);
@@ -0,0 +1,4 @@
+-- The TOTP secret of users with two-factor authentication enabled, and the
+-- last time step a code was used for so a code can't be replayed.
+ALTER TABLE users ADD COLUMN totp_secret string;
+ALTER TABLE users ADD COLUMN totp_step integer;
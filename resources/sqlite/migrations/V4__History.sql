@@ -0,0 +1,22 @@
+-- Per event history, such as comments.
+CREATE TABLE history (
+  -- The rowid of the event this entry belongs to.
+  event_id  INTEGER NOT NULL,
+
+  -- Timestamp in nanoseconds since the epoch.
+  timestamp INTEGER NOT NULL,
+
+  username  TEXT NOT NULL,
+  action    TEXT NOT NULL,
+  comment   TEXT
+);
+
+CREATE INDEX history_event_id_index
+  ON history (event_id);
+
+-- Remove the history along with the event, for example by retention.
+CREATE TRIGGER events_delete_history
+AFTER DELETE ON events
+BEGIN
+  DELETE FROM history WHERE event_id = old.rowid;
+END;
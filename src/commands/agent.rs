@@ -6,16 +6,18 @@ use crate::agent::client::Client;
 use crate::agent::importer::EveboxImporter;
 use crate::bookmark;
 use crate::config::Config;
-use crate::eve::filters::{AddRuleFilter, EveFilter};
+use crate::eve::filters::{AddRuleFilter, EveFilter, ReloadableFilters};
 use crate::importer::Importer;
+use crate::shutdown::CancellationToken;
 use clap::{Arg, Command};
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
 use tracing::debug;
+use tracing::error;
 use tracing::info;
 use tracing::warn;
 
@@ -62,13 +64,6 @@ pub fn command() -> clap::Command<'static> {
 }
 
 pub async fn main(args: &clap::ArgMatches) -> anyhow::Result<()> {
-    tokio::spawn(async move {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("Failed to register CTRL-C handler");
-        std::process::exit(0);
-    });
-
     let config_filename = match args.value_of("config") {
         Some(v) => Some(v),
         None => find_config_filename(),
@@ -78,6 +73,12 @@ pub async fn main(args: &clap::ArgMatches) -> anyhow::Result<()> {
     }
     let config = Config::new(args, config_filename)?;
 
+    let shutdown = CancellationToken::new();
+    crate::shutdown::cancel_on_signal(
+        shutdown.clone(),
+        crate::shutdown::timeout_from_config(&config)?,
+    );
+
     let server_url = config
         .get_string("server.url")
         .unwrap_or_else(|| "http://localhost:5636".to_string());
@@ -90,53 +91,19 @@ pub async fn main(args: &clap::ArgMatches) -> anyhow::Result<()> {
         .unwrap_or(false);
 
     // Collect eve filenames.
-    let eve_filenames = get_eve_filenames(&config)?;
+    let mut eve_filenames = get_eve_filenames(&config)?;
     if eve_filenames.is_empty() {
         bail!("No EVE log files provided. Exiting as there is nothing to do.");
     }
 
-    let enable_geoip = args.occurrences_of("geoip.enabled") > 0;
-
-    // Get additional fields to add to events.
-    let additional_fields = get_additional_fields(&config)?;
-
-    let rule_filenames = get_rule_filenames(&config)?;
+    let filters = ReloadableFilters::new(build_filters(&config, &shutdown)?);
 
-    let mut filters: Vec<EveFilter> = vec![];
+    // The running log readers, by filename.
+    let mut log_runners: HashMap<String, CancellationToken> = HashMap::new();
 
-    if enable_geoip {
-        match crate::geoip::GeoIP::open(None) {
-            Err(err) => {
-                warn!("Failed to open GeoIP database: {}", err);
-            }
-            Ok(geoipdb) => {
-                filters.push(crate::eve::filters::EveFilter::GeoIP(geoipdb));
-            }
-        }
-    }
-
-    if !rule_filenames.is_empty() {
-        let rule_collection = Arc::new(crate::rules::load_rules(&rule_filenames));
-        filters.push(crate::eve::filters::EveFilter::AddRuleFilter(
-            AddRuleFilter {
-                map: rule_collection.clone(),
-            },
-        ));
-        crate::rules::watch_rules(rule_collection);
-    }
-
-    if let Some(custom_fields) = additional_fields {
-        for (field, value) in custom_fields {
-            info!("Adding custom field: {} -> {:?}", field, value);
-            let filter = crate::eve::filters::CustomFieldFilter {
-                field: field.to_string(),
-                value: value.to_string(),
-            };
-            filters.push(crate::eve::filters::EveFilter::CustomFieldFilter(filter));
-        }
-    }
-
-    let mut log_runners: HashMap<String, bool> = HashMap::new();
+    // Readers stopped by a reload that may still be committing their last
+    // events. A file is not read again until its old reader has finished.
+    let mut stopping: HashSet<String> = HashSet::new();
 
     let client = Client::new(
         &server_url,
@@ -158,19 +125,22 @@ pub async fn main(args: &clap::ArgMatches) -> anyhow::Result<()> {
     };
 
     let mut tasks = FuturesUnordered::new();
+    let mut hangup = crate::reload::Hangup::new()?;
 
     loop {
         for path in &eve_filenames {
             for path in crate::path::expand(path)? {
                 let path = path.display().to_string();
-                if !log_runners.contains_key(&path) {
+                if !log_runners.contains_key(&path) && !stopping.contains(&path) {
                     info!("Found EVE log file {:?}", &path);
-                    log_runners.insert(path.clone(), true);
+                    let runner_shutdown = shutdown.child_token();
+                    log_runners.insert(path.clone(), runner_shutdown.clone());
                     let task = start_runner(
                         &path,
                         client.clone(),
                         bookmark_directory.clone(),
                         filters.clone(),
+                        runner_shutdown,
                     );
                     tasks.push(task);
                 }
@@ -178,19 +148,125 @@ pub async fn main(args: &clap::ArgMatches) -> anyhow::Result<()> {
         }
         tokio::select! {
             _ = tokio::time::sleep(std::time::Duration::from_secs(60)) => {}
-            _ = tasks.select_next_some() => {
-                bail!("A log processing task unexpectedly aborted");
+            _ = shutdown.cancelled() => break,
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading configuration");
+                match reload(args, config_filename, &shutdown) {
+                    Err(err) => {
+                        error!("Failed to reload configuration, keeping current configuration: {:?}", err);
+                    }
+                    Ok((filenames, new_filters)) => {
+                        filters.swap(new_filters);
+                        eve_filenames = filenames;
+                        log_runners.retain(|path, runner_shutdown| {
+                            let configured = eve_filenames
+                                .iter()
+                                .any(|pattern| crate::path::matches(pattern, path));
+                            if !configured {
+                                info!("Stopping reader for {}", path);
+                                runner_shutdown.cancel();
+                                stopping.insert(path.clone());
+                            }
+                            configured
+                        });
+                        info!("Configuration reloaded");
+                    }
+                }
+            }
+            result = tasks.select_next_some() => {
+                if let Ok(path) = &result {
+                    if stopping.remove(path) {
+                        continue;
+                    }
+                }
+                if !shutdown.is_cancelled() {
+                    bail!("A log processing task unexpectedly aborted");
+                }
+                break;
+            }
+        }
+    }
+
+    // Wait for the readers to commit pending events and write bookmarks.
+    while tasks.next().await.is_some() {}
+    info!("Shutdown complete");
+    Ok(())
+}
+
+/// Re-read the configuration, returning the EVE filenames and filters.
+fn reload(
+    args: &clap::ArgMatches,
+    config_filename: Option<&str>,
+    shutdown: &CancellationToken,
+) -> anyhow::Result<(Vec<String>, Vec<EveFilter>)> {
+    let config = Config::new(args, config_filename)?;
+    let eve_filenames = get_eve_filenames(&config)?;
+    if eve_filenames.is_empty() {
+        bail!("No EVE log files provided");
+    }
+    let filters = build_filters(&config, shutdown)?;
+    Ok((eve_filenames, filters))
+}
+
+/// Build the filters run on each event before it is sent to the server.
+fn build_filters(config: &Config, shutdown: &CancellationToken) -> anyhow::Result<Vec<EveFilter>> {
+    let enable_geoip = config.args.occurrences_of("geoip.enabled") > 0;
+
+    // Get additional fields to add to events.
+    let additional_fields = get_additional_fields(config)?;
+
+    let rule_filenames = get_rule_filenames(config)?;
+
+    let mut filters: Vec<EveFilter> = vec![];
+
+    if enable_geoip {
+        match crate::geoip::GeoIP::open(None) {
+            Err(err) => {
+                warn!("Failed to open GeoIP database: {}", err);
+            }
+            Ok(geoipdb) => {
+                filters.push(crate::eve::filters::EveFilter::GeoIP(geoipdb));
             }
         }
     }
+
+    if !rule_filenames.is_empty() {
+        let rule_collection = Arc::new(crate::rules::load_rules(&rule_filenames));
+        filters.push(crate::eve::filters::EveFilter::AddRuleFilter(
+            AddRuleFilter {
+                map: rule_collection.clone(),
+            },
+        ));
+        crate::rules::watch_rules(rule_collection, shutdown.clone());
+    }
+
+    if let Some(custom_fields) = additional_fields {
+        for (field, value) in custom_fields {
+            info!("Adding custom field: {} -> {:?}", field, value);
+            let filter = crate::eve::filters::CustomFieldFilter {
+                field: field.to_string(),
+                value: value.into(),
+            };
+            filters.push(crate::eve::filters::EveFilter::CustomFieldFilter(filter));
+        }
+    }
+
+    let user_filters = crate::eve::userfilters::from_config(config)?;
+    if !user_filters.is_empty() {
+        info!("Loaded {} event filters", user_filters.len());
+        filters.push(EveFilter::UserFilters(Arc::new(user_filters)));
+    }
+
+    Ok(filters)
 }
 
 fn start_runner(
     filename: &str,
     client: Client,
     bookmark_directory: Option<String>,
-    mut filters: Vec<EveFilter>,
-) -> JoinHandle<()> {
+    filters: ReloadableFilters,
+    shutdown: CancellationToken,
+) -> JoinHandle<String> {
     let mut end = false;
     let reader = crate::eve::reader::EveReader::new(filename);
     let importer = EveboxImporter::new(client);
@@ -204,17 +280,23 @@ fn start_runner(
     let mut processor = crate::eve::Processor::new(reader, Importer::EveBox(importer));
     processor.end = end;
 
-    filters.push(crate::eve::filters::EveFilter::EveBoxMetadataFilter(
-        crate::eve::filters::EveBoxMetadataFilter {
-            filename: Some(filename.to_string()),
-        },
-    ));
+    let filters = vec![
+        EveFilter::Reloadable(filters),
+        crate::eve::filters::EveFilter::EveBoxMetadataFilter(
+            crate::eve::filters::EveBoxMetadataFilter {
+                filename: Some(filename.to_string()),
+            },
+        ),
+    ];
 
     processor.filters = Arc::new(filters);
     processor.report_interval = std::time::Duration::from_secs(60);
     processor.bookmark_filename = bookmark_filename;
+    processor.shutdown = shutdown;
+    let filename = filename.to_string();
     tokio::spawn(async move {
         processor.run().await;
+        filename
     })
 }
 
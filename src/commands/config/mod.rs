@@ -19,15 +19,19 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+pub mod tokens;
 pub mod users;
 
 pub fn config_subcommand() -> clap::Command<'static> {
-    clap::Command::new("config").subcommand(users::users_subcommand())
+    clap::Command::new("config")
+        .subcommand(users::users_subcommand())
+        .subcommand(tokens::tokens_subcommand())
 }
 
 pub fn main(args: &clap::ArgMatches) -> anyhow::Result<()> {
     match args.subcommand() {
         Some(("users", args)) => users::main(args),
+        Some(("tokens", args)) => tokens::main(args),
         _ => return Err(anyhow!("no subcommand provided")),
     }
 }
@@ -0,0 +1,99 @@
+// Copyright (C) 2022 Jason Ish
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use anyhow::Result;
+use clap::Arg;
+use clap::Command;
+
+use super::users::open_config_repo;
+
+pub fn tokens_subcommand() -> clap::Command<'static> {
+    clap::Command::new("tokens")
+        .about("Manage API tokens")
+        .subcommand(Command::new("list").alias("ls").about("List API tokens"))
+        .subcommand(
+            Command::new("add")
+                .about("Add API token")
+                .arg(
+                    Arg::new("username")
+                        .long("username")
+                        .short('u')
+                        .value_name("USERNAME")
+                        .required(true)
+                        .help("User the token authenticates as"),
+                )
+                .arg(
+                    Arg::new("description")
+                        .long("description")
+                        .short('d')
+                        .value_name("DESCRIPTION")
+                        .help("Description of what the token is for"),
+                ),
+        )
+        .subcommand(
+            Command::new("rm")
+                .about("Remove API token")
+                .arg(Arg::new("id").required(true)),
+        )
+}
+
+pub fn main(args: &clap::ArgMatches) -> Result<()> {
+    match args.subcommand() {
+        Some(("list", args)) => list(args),
+        Some(("add", args)) => add(args),
+        Some(("rm", args)) => remove(args),
+        _ => {
+            return Err(anyhow!("config tokens: no subcommand provided"));
+        }
+    }
+}
+
+fn list(args: &clap::ArgMatches) -> Result<()> {
+    let repo = open_config_repo(args.value_of("data-directory"))?;
+    let tokens = repo.get_tokens()?;
+    for token in tokens {
+        println!("{}", serde_json::to_string(&token).unwrap());
+    }
+    Ok(())
+}
+
+fn add(args: &clap::ArgMatches) -> Result<()> {
+    let repo = open_config_repo(args.value_of("data-directory"))?;
+    let username = args.value_of("username").unwrap();
+    let (token_id, token) = repo.add_token(username, args.value_of("description"))?;
+    println!(
+        "Token added: id=\"{}\", username=\"{}\"",
+        token_id, username
+    );
+    println!("{}", token);
+    println!("Store the token now, it can not be shown again.");
+    Ok(())
+}
+
+fn remove(args: &clap::ArgMatches) -> Result<()> {
+    let repo = open_config_repo(args.value_of("data-directory"))?;
+    let token_id = args.value_of("id").unwrap();
+    if repo.remove_token(token_id)? == 0 {
+        return Err(anyhow!("token does not exist"));
+    }
+    println!("Token removed: id=\"{}\"", token_id);
+    Ok(())
+}
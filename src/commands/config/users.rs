@@ -26,7 +26,7 @@ use anyhow::Result;
 use clap::Arg;
 use clap::Command;
 
-use crate::sqlite::configrepo::ConfigRepo;
+use crate::sqlite::configrepo::{ConfigRepo, ROLE_ADMIN, ROLE_AGENT, ROLE_ANALYST, ROLE_VIEWER};
 
 pub fn users_subcommand() -> clap::Command<'static> {
     clap::Command::new("users")
@@ -47,6 +47,19 @@ pub fn users_subcommand() -> clap::Command<'static> {
                         .short('p')
                         .value_name("PASSWORD")
                         .help("Password"),
+                )
+                .arg(
+                    Arg::new("role")
+                        .long("role")
+                        .value_name("ROLE")
+                        .possible_values([ROLE_VIEWER, ROLE_ANALYST, ROLE_ADMIN, ROLE_AGENT])
+                        .default_value(ROLE_ADMIN)
+                        .help("Role of the user")
+                        .long_help(
+                            "Role of the user: viewers can only view events, analysts can \
+                             also archive, escalate and comment on events, admins have \
+                             full access, and agents may only submit events",
+                        ),
                 ),
         )
         .subcommand(
@@ -54,12 +67,31 @@ pub fn users_subcommand() -> clap::Command<'static> {
                 .about("Remove user")
                 .arg(Arg::new("username").required(true)),
         )
+        .subcommand(
+            Command::new("logout")
+                .about("Log out all sessions for user")
+                .arg(Arg::new("username").required(true)),
+        )
         .subcommand(
             Command::new("passwd")
                 .alias("password")
                 .about("Change password for user")
                 .arg(Arg::new("username").required(true)),
         )
+        .subcommand(
+            Command::new("totp")
+                .about("Manage two-factor authentication for user")
+                .subcommand(
+                    Command::new("enable")
+                        .about("Enable TOTP two-factor authentication for user")
+                        .arg(Arg::new("username").required(true)),
+                )
+                .subcommand(
+                    Command::new("disable")
+                        .about("Disable TOTP two-factor authentication for user")
+                        .arg(Arg::new("username").required(true)),
+                ),
+        )
 }
 
 pub fn main(args: &clap::ArgMatches) -> Result<()> {
@@ -67,14 +99,20 @@ pub fn main(args: &clap::ArgMatches) -> Result<()> {
         Some(("list", args)) => list(args),
         Some(("add", args)) => add(args),
         Some(("rm", args)) => remove(args),
+        Some(("logout", args)) => logout(args),
         Some(("passwd", args)) => password(args),
+        Some(("totp", args)) => match args.subcommand() {
+            Some(("enable", args)) => totp_enable(args),
+            Some(("disable", args)) => totp_disable(args),
+            _ => Err(anyhow!("config users totp: no subcommand provided")),
+        },
         _ => {
             return Err(anyhow!("config users: no subcommand provided"));
         }
     }
 }
 
-fn open_config_repo(data_directory: Option<&str>) -> Result<ConfigRepo> {
+pub(crate) fn open_config_repo(data_directory: Option<&str>) -> Result<ConfigRepo> {
     if data_directory.is_none() {
         return Err(anyhow!("--data-directory required"));
     }
@@ -125,8 +163,9 @@ fn add(args: &clap::ArgMatches) -> Result<()> {
         password
     };
 
-    repo.add_user(&username, &password)?;
-    println!("User added: username=\"{}\"", username);
+    let role = args.value_of("role").unwrap();
+    repo.add_user_with_role(&username, &password, role)?;
+    println!("User added: username=\"{}\", role=\"{}\"", username, role);
     Ok(())
 }
 
@@ -140,6 +179,14 @@ fn remove(args: &clap::ArgMatches) -> Result<()> {
     Ok(())
 }
 
+fn logout(args: &clap::ArgMatches) -> Result<()> {
+    let username = args.value_of("username").unwrap();
+    let repo = open_config_repo(args.value_of("data-directory"))?;
+    let n = repo.delete_sessions_by_username(username)?;
+    println!("Logged out {} sessions for username=\"{}\"", n, username);
+    Ok(())
+}
+
 fn password(args: &clap::ArgMatches) -> Result<()> {
     let username = args.value_of("username").unwrap();
     let repo = open_config_repo(args.value_of("data-directory"))?;
@@ -153,9 +200,49 @@ fn password(args: &clap::ArgMatches) -> Result<()> {
         return Err(anyhow!("passwords to not match"));
     }
     if repo.update_password_by_id(&user.uuid, &password)? {
+        repo.delete_sessions_by_username(username)?;
         println!("Password has been updated.");
         Ok(())
     } else {
         Err(anyhow!("Failed to update password, user does not exist"))
     }
 }
+
+fn totp_enable(args: &clap::ArgMatches) -> Result<()> {
+    let username = args.value_of("username").unwrap();
+    let repo = open_config_repo(args.value_of("data-directory"))?;
+    repo.get_user_by_name(username)?;
+    let secret = crate::totp::generate_secret();
+    println!("Add this secret to an authenticator app: {}", secret);
+    println!(
+        "Or as a URL: {}",
+        crate::totp::url("EveBox", username, &secret)
+    );
+
+    // Make sure the app was setup correctly before enabling it.
+    let code = get_input("Enter the code from the authenticator app: ")?;
+    let now = chrono::Utc::now().timestamp() as u64;
+    if crate::totp::verify(&secret, &code, now).is_none() {
+        return Err(anyhow!("bad code, two-factor authentication not enabled"));
+    }
+    repo.set_totp_secret(username, Some(&secret))?;
+    repo.delete_sessions_by_username(username)?;
+    println!(
+        "Two-factor authentication enabled for username=\"{}\"",
+        username
+    );
+    Ok(())
+}
+
+fn totp_disable(args: &clap::ArgMatches) -> Result<()> {
+    let username = args.value_of("username").unwrap();
+    let repo = open_config_repo(args.value_of("data-directory"))?;
+    if !repo.set_totp_secret(username, None)? {
+        return Err(anyhow!("user does not exist"));
+    }
+    println!(
+        "Two-factor authentication disabled for username=\"{}\"",
+        username
+    );
+    Ok(())
+}
@@ -15,6 +15,7 @@ use crate::eve;
 use crate::eve::filters::{AddRuleFilter, EveFilter};
 use crate::eve::Processor;
 use crate::importer::Importer;
+use crate::shutdown::CancellationToken;
 
 pub const DEFAULT_BATCH_SIZE: u64 = 300;
 pub const NO_CHECK_CERTIFICATE: &str = "no-check-certificate";
@@ -179,6 +180,9 @@ pub async fn main(args: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Err
 
     let mut filters = Vec::new();
 
+    // Stops the rule watcher once the import is done.
+    let shutdown = CancellationToken::new();
+
     match loader.get_strings("rules") {
         Ok(Some(rules)) => {
             if !rules.is_empty() {
@@ -189,7 +193,7 @@ pub async fn main(args: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Err
                         map: rulemap.clone(),
                     },
                 ));
-                crate::rules::watch_rules(rulemap);
+                crate::rules::watch_rules(rulemap, shutdown.clone());
             }
         }
         Ok(None) => {}
@@ -198,6 +202,12 @@ pub async fn main(args: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Err
         }
     }
 
+    let user_filters = crate::eve::userfilters::from_config(&loader)?;
+    if !user_filters.is_empty() {
+        info!("Loaded {} event filters", user_filters.len());
+        filters.push(EveFilter::UserFilters(Arc::new(user_filters)));
+    }
+
     let filters = Arc::new(filters);
 
     let is_oneshot = config.oneshot;
@@ -263,6 +273,7 @@ pub async fn main(args: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Err
         done_rx.recv().await;
     }
 
+    shutdown.cancel();
     Ok(())
 }
 
@@ -11,6 +11,7 @@ use crate::config::Config;
 use crate::eve;
 use crate::geoip;
 use crate::prelude::*;
+use crate::shutdown::CancellationToken;
 use crate::sqlite;
 
 pub async fn main(args: &clap::ArgMatches) -> anyhow::Result<()> {
@@ -52,9 +53,13 @@ pub async fn main(args: &clap::ArgMatches) -> anyhow::Result<()> {
 
     let (port_tx, mut port_rx) = sync::mpsc::unbounded_channel::<u16>();
 
+    let shutdown = CancellationToken::new();
+    crate::shutdown::cancel_on_signal(shutdown.clone(), crate::shutdown::DEFAULT_TIMEOUT);
+
     let server = {
         let db_filename = db_filename.clone();
         let host = host.clone();
+        let shutdown = shutdown.clone();
         tokio::spawn(async move {
             let mut port = 5636;
             loop {
@@ -74,7 +79,10 @@ pub async fn main(args: &clap::ArgMatches) -> anyhow::Result<()> {
                     ..crate::server::ServerConfig::default()
                 };
                 let context = match crate::server::build_context(config.clone(), ds).await {
-                    Ok(context) => Arc::new(context),
+                    Ok(mut context) => {
+                        context.shutdown = shutdown.clone();
+                        Arc::new(context)
+                    }
                     Err(err) => {
                         error!("Failed to build server context: {}", err);
                         std::process::exit(1);
@@ -85,7 +93,10 @@ pub async fn main(args: &clap::ArgMatches) -> anyhow::Result<()> {
                     Ok(server) => {
                         debug!("Looks like a successful bind to port {}", port);
                         port_tx.send(port).unwrap();
-                        server.await.unwrap();
+                        server
+                            .with_graceful_shutdown(shutdown.cancelled())
+                            .await
+                            .unwrap();
                         break;
                     }
                     Err(_) => {
@@ -122,18 +133,10 @@ pub async fn main(args: &clap::ArgMatches) -> anyhow::Result<()> {
         );
     }
 
-    tokio::spawn(async move {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("Failed to register CTRL-C handler");
-        info!("Got CTRL-C, exiting");
-        let _ = std::fs::remove_file(&db_filename);
-        let _ = std::fs::remove_file(&format!("{}-shm", &db_filename));
-        let _ = std::fs::remove_file(&format!("{}-wal", &db_filename));
-        std::process::exit(0);
-    });
-
     server.await?;
+    let _ = std::fs::remove_file(&db_filename);
+    let _ = std::fs::remove_file(&format!("{}-shm", &db_filename));
+    let _ = std::fs::remove_file(&format!("{}-wal", &db_filename));
     Ok(())
 }
 
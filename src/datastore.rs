@@ -21,9 +21,12 @@
 
 use crate::elastic;
 use crate::importer::Importer;
+use crate::metrics;
 use crate::server::api;
 use crate::server::session::Session;
+use crate::sqlite;
 use crate::sqlite::eventstore::SQLiteEventStore;
+use prometheus::HistogramTimer;
 use serde_json::Value as JsonValue;
 use std::sync::Arc;
 use thiserror::Error;
@@ -99,6 +102,14 @@ pub struct StatsAggQueryParams {
 
 #[allow(unreachable_patterns)]
 impl Datastore {
+    fn query_timer(&self, method: &str) -> HistogramTimer {
+        let datastore = match self {
+            Datastore::Elastic(_) => "elastic",
+            Datastore::SQLite(_) => "sqlite",
+        };
+        metrics::datastore_timer(datastore, method)
+    }
+
     pub fn get_importer(&self) -> Option<Importer> {
         match self {
             Datastore::Elastic(ds) => Some(Importer::Elastic(ds.get_importer())),
@@ -107,26 +118,59 @@ impl Datastore {
         }
     }
 
-    pub async fn archive_event_by_id(&self, event_id: &str) -> Result<(), DatastoreError> {
+    /// Check that the datastore is reachable and supported.
+    pub async fn ping(&self) -> Result<(), DatastoreError> {
+        match self {
+            Datastore::Elastic(ds) => {
+                let version = ds
+                    .client
+                    .fetch_version()
+                    .await
+                    .map_err(|err| DatastoreError::ElasticSearchError(err.to_string()))?;
+                if version.major < 6 {
+                    return Err(DatastoreError::ElasticSearchError(format!(
+                        "unsupported Elasticsearch version {}, 6 or newer is required",
+                        version.version
+                    )));
+                }
+                Ok(())
+            }
+            Datastore::SQLite(ds) => Ok(ds.ping().await?),
+        }
+    }
+
+    pub async fn archive_event_by_id(
+        &self,
+        event_id: &str,
+        session: Arc<Session>,
+    ) -> Result<(), DatastoreError> {
         match self {
-            Datastore::Elastic(ds) => ds.archive_event_by_id(event_id).await,
-            Datastore::SQLite(ds) => ds.archive_event_by_id(event_id).await,
+            Datastore::Elastic(ds) => ds.archive_event_by_id(event_id, session).await,
+            Datastore::SQLite(ds) => ds.archive_event_by_id(event_id, session).await,
             _ => Err(DatastoreError::Unimplemented),
         }
     }
 
-    pub async fn escalate_event_by_id(&self, event_id: &str) -> Result<(), DatastoreError> {
+    pub async fn escalate_event_by_id(
+        &self,
+        event_id: &str,
+        session: Arc<Session>,
+    ) -> Result<(), DatastoreError> {
         match self {
-            Datastore::Elastic(ds) => ds.escalate_event_by_id(event_id).await,
-            Datastore::SQLite(ds) => ds.escalate_event_by_id(event_id).await,
+            Datastore::Elastic(ds) => ds.escalate_event_by_id(event_id, session).await,
+            Datastore::SQLite(ds) => ds.escalate_event_by_id(event_id, session).await,
             _ => Err(DatastoreError::Unimplemented),
         }
     }
 
-    pub async fn deescalate_event_by_id(&self, event_id: &str) -> Result<(), DatastoreError> {
+    pub async fn deescalate_event_by_id(
+        &self,
+        event_id: &str,
+        session: Arc<Session>,
+    ) -> Result<(), DatastoreError> {
         match self {
-            Datastore::Elastic(ds) => ds.deescalate_event_by_id(event_id).await,
-            Datastore::SQLite(ds) => ds.deescalate_event_by_id(event_id).await,
+            Datastore::Elastic(ds) => ds.deescalate_event_by_id(event_id, session).await,
+            Datastore::SQLite(ds) => ds.deescalate_event_by_id(event_id, session).await,
             _ => Err(DatastoreError::Unimplemented),
         }
     }
@@ -135,6 +179,7 @@ impl Datastore {
         &self,
         event_id: String,
     ) -> Result<Option<serde_json::Value>, DatastoreError> {
+        let _timer = self.query_timer("get_event_by_id");
         match self {
             Datastore::Elastic(ds) => ds.get_event_by_id(event_id).await,
             Datastore::SQLite(ds) => ds.get_event_by_id(event_id).await,
@@ -146,6 +191,7 @@ impl Datastore {
         &self,
         options: elastic::AlertQueryOptions,
     ) -> Result<serde_json::Value, DatastoreError> {
+        let _timer = self.query_timer("alert_query");
         match self {
             Datastore::Elastic(ds) => ds.alert_query(options).await,
             Datastore::SQLite(ds) => ds.alert_query(options).await,
@@ -156,10 +202,11 @@ impl Datastore {
     pub async fn archive_by_alert_group(
         &self,
         alert_group: api::AlertGroupSpec,
+        session: Arc<Session>,
     ) -> Result<(), DatastoreError> {
         match self {
-            Datastore::Elastic(ds) => ds.archive_by_alert_group(alert_group).await,
-            Datastore::SQLite(ds) => ds.archive_by_alert_group(alert_group).await,
+            Datastore::Elastic(ds) => ds.archive_by_alert_group(alert_group, session).await,
+            Datastore::SQLite(ds) => ds.archive_by_alert_group(alert_group, session).await,
             _ => Err(DatastoreError::Unimplemented),
         }
     }
@@ -171,7 +218,7 @@ impl Datastore {
     ) -> Result<(), DatastoreError> {
         match self {
             Datastore::Elastic(ds) => ds.escalate_by_alert_group(alert_group, session).await,
-            Datastore::SQLite(ds) => ds.escalate_by_alert_group(alert_group).await,
+            Datastore::SQLite(ds) => ds.escalate_by_alert_group(alert_group, session).await,
             _ => Err(DatastoreError::Unimplemented),
         }
     }
@@ -179,10 +226,11 @@ impl Datastore {
     pub async fn deescalate_by_alert_group(
         &self,
         alert_group: api::AlertGroupSpec,
+        session: Arc<Session>,
     ) -> Result<(), DatastoreError> {
         match self {
-            Datastore::Elastic(ds) => ds.deescalate_by_alert_group(alert_group).await,
-            Datastore::SQLite(ds) => ds.deescalate_by_alert_group(alert_group).await,
+            Datastore::Elastic(ds) => ds.deescalate_by_alert_group(alert_group, session).await,
+            Datastore::SQLite(ds) => ds.deescalate_by_alert_group(alert_group, session).await,
             _ => Err(DatastoreError::Unimplemented),
         }
     }
@@ -191,11 +239,15 @@ impl Datastore {
         &self,
         alert_group: api::AlertGroupSpec,
         comment: String,
-        username: &str,
+        session: Arc<Session>,
     ) -> Result<(), DatastoreError> {
         match self {
             Datastore::Elastic(ds) => {
-                ds.comment_by_alert_group(alert_group, comment, username)
+                ds.comment_by_alert_group(alert_group, comment, session)
+                    .await
+            }
+            Datastore::SQLite(ds) => {
+                ds.comment_by_alert_group(alert_group, comment, session)
                     .await
             }
             _ => Err(DatastoreError::Unimplemented),
@@ -206,6 +258,7 @@ impl Datastore {
         &self,
         params: EventQueryParams,
     ) -> Result<serde_json::Value, DatastoreError> {
+        let _timer = self.query_timer("event_query");
         match self {
             Datastore::Elastic(ds) => ds.event_query(params).await,
             Datastore::SQLite(ds) => ds.event_query(params).await,
@@ -217,10 +270,11 @@ impl Datastore {
         &self,
         event_id: &str,
         comment: String,
-        username: &str,
+        session: Arc<Session>,
     ) -> Result<(), DatastoreError> {
         match self {
-            Datastore::Elastic(ds) => ds.comment_event_by_id(event_id, comment, username).await,
+            Datastore::Elastic(ds) => ds.comment_event_by_id(event_id, comment, session).await,
+            Datastore::SQLite(ds) => ds.comment_event_by_id(event_id, comment, session).await,
             _ => Err(DatastoreError::Unimplemented),
         }
     }
@@ -229,15 +283,19 @@ impl Datastore {
         &self,
         params: HistogramParameters,
     ) -> Result<serde_json::Value, DatastoreError> {
+        let _timer = self.query_timer("histogram");
         match self {
             Datastore::Elastic(ds) => ds.histogram(params).await,
+            Datastore::SQLite(ds) => ds.histogram(params).await,
             _ => Err(DatastoreError::Unimplemented),
         }
     }
 
     pub async fn agg(&self, params: AggParameters) -> Result<JsonValue, DatastoreError> {
+        let _timer = self.query_timer("agg");
         match self {
             Datastore::Elastic(ds) => ds.agg(params).await,
+            Datastore::SQLite(ds) => ds.agg(params).await,
             _ => Err(DatastoreError::Unimplemented),
         }
     }
@@ -246,8 +304,10 @@ impl Datastore {
         &self,
         params: FlowHistogramParameters,
     ) -> Result<JsonValue, DatastoreError> {
+        let _timer = self.query_timer("flow_histogram");
         match self {
             Datastore::Elastic(ds) => ds.flow_histogram(params).await,
+            Datastore::SQLite(ds) => ds.flow_histogram(params).await,
             _ => Err(DatastoreError::Unimplemented),
         }
     }
@@ -257,8 +317,10 @@ impl Datastore {
         what: &str,
         params: &EventQueryParams,
     ) -> Result<JsonValue, DatastoreError> {
+        let _timer = self.query_timer("report_dhcp");
         match self {
             Datastore::Elastic(ds) => elastic::report::dhcp::dhcp_report(ds, what, params).await,
+            Datastore::SQLite(ds) => sqlite::report::dhcp::dhcp_report(ds, what, params).await,
             _ => Err(DatastoreError::Unimplemented),
         }
     }
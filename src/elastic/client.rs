@@ -8,7 +8,7 @@ use serde::Serialize;
 use serde_json::Value;
 use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ClientError {
@@ -34,26 +34,14 @@ impl From<serde_json::error::Error> for ClientError {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Client {
     pub url: String,
     disable_certificate_validation: bool,
     username: Option<String>,
     password: Option<String>,
-    pub version: RwLock<Option<Version>>,
-}
-
-impl Clone for Client {
-    fn clone(&self) -> Self {
-        let version = self.version.read().unwrap();
-        Self {
-            url: self.url.clone(),
-            disable_certificate_validation: self.disable_certificate_validation,
-            username: self.username.clone(),
-            password: self.password.clone(),
-            version: RwLock::new(version.clone()),
-        }
-    }
+    /// The cached server version, shared with clones of the client.
+    pub version: Arc<RwLock<Option<Version>>>,
 }
 
 impl Client {
@@ -121,7 +109,12 @@ impl Client {
                 return Ok(version.clone());
             }
         }
+        self.fetch_version().await
+    }
 
+    /// Request the version from the server, bypassing the cached version, so
+    /// can be used to check that the server is reachable.
+    pub async fn fetch_version(&self) -> Result<Version, ClientError> {
         let r = self.get("")?.send().await?;
         let status_code = r.status();
         if status_code != StatusCode::OK {
@@ -275,7 +268,7 @@ impl ClientBuilder {
             disable_certificate_validation: self.disable_certificate_validation,
             username: self.username.clone(),
             password: self.password.clone(),
-            version: RwLock::new(None),
+            version: Arc::new(RwLock::new(None)),
         }
     }
 }
@@ -332,4 +325,12 @@ mod test {
         assert!(Version::parse("7.7.1").unwrap() <= Version::parse("7.7.1").unwrap());
         assert!(Version::parse("7.7.1").unwrap() == Version::parse("7.7.1").unwrap());
     }
+
+    #[tokio::test]
+    async fn test_version_cache_shared() {
+        let client = ClientBuilder::new("http://127.0.0.1:1").build();
+        let clone = client.clone();
+        *clone.version.write().unwrap() = Some(Version::parse("7.17.0").unwrap());
+        assert_eq!(client.get_version().await.unwrap().major, 7);
+    }
 }
@@ -269,7 +269,11 @@ impl EventStore {
         self.remove_tags_by_query(query, tags, action).await
     }
 
-    pub async fn archive_event_by_id(&self, event_id: &str) -> Result<(), DatastoreError> {
+    pub async fn archive_event_by_id(
+        &self,
+        event_id: &str,
+        session: Arc<Session>,
+    ) -> Result<(), DatastoreError> {
         let query = json!({
             "bool": {
                 "filter": {
@@ -278,7 +282,7 @@ impl EventStore {
             }
         });
         let action = HistoryEntry {
-            username: "anonymous".to_string(),
+            username: session.username().to_string(),
             timestamp: format_timestamp(chrono::Utc::now()),
             action: ACTION_ARCHIVED.to_string(),
             comment: None,
@@ -286,7 +290,11 @@ impl EventStore {
         self.add_tag_by_query(query, TAG_ARCHIVED, &action).await
     }
 
-    pub async fn escalate_event_by_id(&self, event_id: &str) -> Result<(), DatastoreError> {
+    pub async fn escalate_event_by_id(
+        &self,
+        event_id: &str,
+        session: Arc<Session>,
+    ) -> Result<(), DatastoreError> {
         let query = json!({
             "bool": {
                 "filter": {
@@ -295,7 +303,7 @@ impl EventStore {
             }
         });
         let action = HistoryEntry {
-            username: "anonymous".to_string(),
+            username: session.username().to_string(),
             timestamp: format_timestamp(chrono::Utc::now()),
             action: ACTION_ESCALATED.to_string(),
             comment: None,
@@ -303,7 +311,11 @@ impl EventStore {
         self.add_tag_by_query(query, TAG_ESCALATED, &action).await
     }
 
-    pub async fn deescalate_event_by_id(&self, event_id: &str) -> Result<(), DatastoreError> {
+    pub async fn deescalate_event_by_id(
+        &self,
+        event_id: &str,
+        session: Arc<Session>,
+    ) -> Result<(), DatastoreError> {
         let query = json!({
             "bool": {
                 "filter": {
@@ -312,7 +324,7 @@ impl EventStore {
             }
         });
         let action = HistoryEntry {
-            username: "anonymous".to_string(),
+            username: session.username().to_string(),
             timestamp: format_timestamp(chrono::Utc::now()),
             action: ACTION_DEESCALATED.to_string(),
             comment: None,
@@ -325,7 +337,7 @@ impl EventStore {
         &self,
         event_id: &str,
         comment: String,
-        username: &str,
+        session: Arc<Session>,
     ) -> Result<(), DatastoreError> {
         let query = json!({
             "bool": {
@@ -335,7 +347,7 @@ impl EventStore {
             }
         });
         let action = HistoryEntry {
-            username: username.to_string(),
+            username: session.username().to_string(),
             timestamp: format_timestamp(chrono::Utc::now()),
             action: ACTION_COMMENT.to_string(),
             comment: Some(comment),
@@ -515,9 +527,10 @@ impl EventStore {
     pub async fn archive_by_alert_group(
         &self,
         alert_group: api::AlertGroupSpec,
+        session: Arc<Session>,
     ) -> Result<(), DatastoreError> {
         let action = HistoryEntry {
-            username: "anonymous".to_string(),
+            username: session.username().to_string(),
             timestamp: format_timestamp(chrono::offset::Utc::now()),
             action: ACTION_ARCHIVED.to_string(),
             comment: None,
@@ -533,7 +546,6 @@ impl EventStore {
     ) -> Result<(), DatastoreError> {
         let action = HistoryEntry {
             username: session.username().to_string(),
-            //username: "anonymous".to_string(),
             timestamp: format_timestamp(chrono::offset::Utc::now()),
             action: ACTION_ESCALATED.to_string(),
             comment: None,
@@ -545,9 +557,10 @@ impl EventStore {
     pub async fn deescalate_by_alert_group(
         &self,
         alert_group: api::AlertGroupSpec,
+        session: Arc<Session>,
     ) -> Result<(), DatastoreError> {
         let action = HistoryEntry {
-            username: "anonymous".to_string(),
+            username: session.username().to_string(),
             timestamp: format_timestamp(chrono::offset::Utc::now()),
             action: ACTION_DEESCALATED.to_string(),
             comment: None,
@@ -609,10 +622,10 @@ impl EventStore {
         &self,
         alert_group: api::AlertGroupSpec,
         comment: String,
-        username: &str,
+        session: Arc<Session>,
     ) -> Result<(), DatastoreError> {
         let entry = HistoryEntry {
-            username: username.to_string(),
+            username: session.username().to_string(),
             timestamp: format_timestamp(chrono::Utc::now()),
             action: ACTION_COMMENT.to_string(),
             comment: Some(comment),
@@ -1015,7 +1028,7 @@ impl EventStore {
         &self,
         params: &datastore::StatsAggQueryParams,
     ) -> anyhow::Result<serde_json::Value> {
-        let version = self.client.get_version().await.unwrap();
+        let version = self.client.get_version().await?;
         let date_histogram_interval_field_name = if version.major < 7 {
             "interval"
         } else {
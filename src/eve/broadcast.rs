@@ -0,0 +1,73 @@
+// Copyright (C) 2022 Jason Ish
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Broadcasting of committed events to live subscribers, such as clients
+//! streaming alerts.
+
+use std::sync::Arc;
+
+use tokio::sync::broadcast;
+
+use crate::eve::eve::EveJson;
+
+/// The number of events buffered for each subscriber. A subscriber that
+/// falls further behind than this misses events, so slow consumers can't
+/// hold up importing.
+const CAPACITY: usize = 1024;
+
+#[derive(Clone)]
+pub struct EventBroadcaster {
+    sender: broadcast::Sender<Arc<EveJson>>,
+}
+
+impl Default for EventBroadcaster {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+}
+
+impl EventBroadcaster {
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<EveJson>> {
+        self.sender.subscribe()
+    }
+
+    /// Returns true if there are subscribers, so events only need to be
+    /// kept for broadcasting when someone is listening.
+    pub fn is_active(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    /// Returns true if the event is of a type that is broadcast. Only alerts
+    /// are streamed, so other events aren't kept for broadcasting. Archived
+    /// alerts are broadcast, the stream skips them unless asked for.
+    pub fn is_broadcast(event: &EveJson) -> bool {
+        event["event_type"] == "alert"
+    }
+
+    /// Send committed events to all subscribers.
+    pub fn send(&self, events: Vec<EveJson>) {
+        for event in events {
+            // Only fails if there are no subscribers.
+            let _ = self.sender.send(Arc::new(event));
+        }
+    }
+}
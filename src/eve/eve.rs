@@ -58,8 +58,10 @@ pub fn add_evebox_metadata(event: &mut EveJson, filename: Option<String>) {
         }
     }
 
-    // Add a tags object.
-    event["tags"] = serde_json::json!([]);
+    // Add a tags object, keeping any tags added by filters.
+    if !event["tags"].is_array() {
+        event["tags"] = serde_json::json!([]);
+    }
 }
 
 /// Parser for Eve timestamps.
@@ -20,11 +20,13 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use crate::eve::eve::EveJson;
+use crate::eve::userfilters::EveUserFilter;
 use crate::prelude::*;
 use crate::rules::RuleMap;
 
 use serde_json::json;
 use std::sync::Arc;
+use std::sync::RwLock;
 
 #[derive(Clone)]
 pub enum EveFilter {
@@ -33,11 +35,15 @@ pub enum EveFilter {
     CustomFieldFilter(CustomFieldFilter),
     AddRuleFilter(AddRuleFilter),
     AutoArchiveFilter(AutoArchiveFilter),
+    UserFilters(Arc<Vec<EveUserFilter>>),
     Filters(Arc<Vec<EveFilter>>),
+    Reloadable(ReloadableFilters),
 }
 
 impl EveFilter {
-    pub fn run(&self, event: &mut EveJson) {
+    /// Run the filter on an event, returning false if the event is to be
+    /// dropped.
+    pub fn run(&self, event: &mut EveJson) -> bool {
         match self {
             EveFilter::GeoIP(geoip) => {
                 geoip.add_geoip_to_eve(event);
@@ -52,14 +58,54 @@ impl EveFilter {
                 filter.run(event);
             }
             EveFilter::Filters(filters) => {
-                for filter in filters.iter() {
-                    filter.run(event);
-                }
+                return filters.iter().all(|filter| filter.run(event));
             }
             EveFilter::AutoArchiveFilter(filter) => {
                 filter.run(event);
             }
+            EveFilter::UserFilters(filters) => {
+                return crate::eve::userfilters::run(filters, event);
+            }
+            EveFilter::Reloadable(filters) => {
+                return filters.run(event);
+            }
         }
+        true
+    }
+}
+
+/// A chain of filters that can be replaced while in use, for example on a
+/// configuration reload. Clones share the same chain.
+#[derive(Clone, Default)]
+pub struct ReloadableFilters {
+    filters: Arc<RwLock<Arc<Vec<EveFilter>>>>,
+}
+
+impl ReloadableFilters {
+    pub fn new(filters: Vec<EveFilter>) -> Self {
+        Self {
+            filters: Arc::new(RwLock::new(Arc::new(filters))),
+        }
+    }
+
+    /// Replace the filter chain. Events already being filtered finish with
+    /// the previous chain.
+    pub fn swap(&self, filters: Vec<EveFilter>) {
+        *self.filters.write().unwrap() = Arc::new(filters);
+    }
+
+    pub fn get(&self) -> Arc<Vec<EveFilter>> {
+        self.filters.read().unwrap().clone()
+    }
+
+    pub fn run(&self, event: &mut EveJson) -> bool {
+        self.get().iter().all(|filter| filter.run(event))
+    }
+}
+
+impl From<ReloadableFilters> for EveFilter {
+    fn from(filters: ReloadableFilters) -> Self {
+        EveFilter::Reloadable(filters)
     }
 }
 
@@ -98,19 +144,19 @@ impl From<EveBoxMetadataFilter> for EveFilter {
 #[derive(Clone)]
 pub struct CustomFieldFilter {
     pub field: String,
-    pub value: String,
+    pub value: EveJson,
 }
 
 impl CustomFieldFilter {
-    pub fn new(field: &str, value: &str) -> Self {
+    pub fn new(field: &str, value: EveJson) -> Self {
         Self {
             field: field.to_string(),
-            value: value.to_string(),
+            value,
         }
     }
 
     pub fn run(&self, event: &mut EveJson) {
-        event[&self.field] = self.value.clone().into();
+        event[&self.field] = self.value.clone();
     }
 }
 
@@ -150,23 +196,30 @@ impl AutoArchiveFilter {
             .as_array()
             .and_then(|a| a.iter().next().and_then(|e| e.as_str()));
         if let Some(action) = action {
-            if action == "archive" {
-                match &mut event["tags"] {
-                    serde_json::Value::Array(tags) => {
-                        tags.push("evebox.archived".into());
-                        tags.push("evebox.auto-archived".into());
-                    }
-                    serde_json::Value::Null => {
-                        event["tags"] = serde_json::Value::Array(vec![
-                            "evebox.archived".into(),
-                            "evebox.auto-archived".into(),
-                        ]);
-                    }
-                    _ => {
-                        warn!("Unable to auto-archive event, event has incompatible tags entry");
-                    }
+            if action == "archive" && !add_tags(event, &["evebox.archived", "evebox.auto-archived"])
+            {
+                warn!("Unable to auto-archive event, event has incompatible tags entry");
+            }
+        }
+    }
+}
+
+/// Add tags to an event, creating the tags array if needed. Tags already
+/// present are not added again. Returns false if the event has a tags
+/// entry that is not an array.
+pub fn add_tags(event: &mut EveJson, tags: &[&str]) -> bool {
+    if let serde_json::Value::Null = &event["tags"] {
+        event["tags"] = serde_json::Value::Array(vec![]);
+    }
+    match &mut event["tags"] {
+        serde_json::Value::Array(existing) => {
+            for tag in tags {
+                if !existing.iter().any(|t| t == tag) {
+                    existing.push((*tag).into());
                 }
             }
+            true
         }
+        _ => false,
     }
 }
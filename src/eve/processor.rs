@@ -25,16 +25,44 @@
 //! - bookmarker: to remember the last location reader
 
 use crate::bookmark;
+use crate::eve::broadcast::EventBroadcaster;
+use crate::eve::eve::EveJson;
 use crate::eve::filters::EveFilter;
 use crate::eve::reader::EveReader;
 use crate::importer::Importer;
+use crate::metrics;
 use crate::prelude::*;
+use crate::shutdown::CancellationToken;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 const DEFAULT_BATCH_SIZE: usize = 300;
 
+/// The progress of a processor through its input file, shared with the
+/// server for health reporting.
+#[derive(Debug, Default)]
+pub struct ProcessorStatus {
+    pub filename: String,
+    /// Byte offset into the file up to which all events have been committed.
+    pub offset: AtomicU64,
+    /// Time of the last successful commit in seconds since the epoch, 0 if none.
+    pub last_commit: AtomicI64,
+    /// Set while commits are failing.
+    pub failing: AtomicBool,
+}
+
+impl ProcessorStatus {
+    /// The number of bytes in the file not yet committed. If the file is
+    /// smaller than the offset it has been rotated, and all of it is lag.
+    pub fn lag(&self) -> std::io::Result<u64> {
+        let size = std::fs::metadata(&self.filename)?.len();
+        let offset = self.offset.load(Ordering::Relaxed);
+        Ok(if size < offset { size } else { size - offset })
+    }
+}
+
 pub struct Processor {
     pub reader: EveReader,
     pub importer: Importer,
@@ -50,11 +78,29 @@ pub struct Processor {
     pub oneshot: bool,
 
     pub batch_size: usize,
+
+    pub status: Arc<ProcessorStatus>,
+
+    /// Committed events are sent to the broadcaster, if set.
+    pub broadcaster: Option<EventBroadcaster>,
+
+    /// Events submitted since the last commit, kept while the broadcaster
+    /// has subscribers.
+    unpublished: Vec<EveJson>,
+
+    /// When cancelled the processor stops reading, commits any pending
+    /// events and writes a final bookmark.
+    pub shutdown: CancellationToken,
 }
 
 impl Processor {
     pub fn new(reader: EveReader, importer: Importer) -> Self {
+        let status = ProcessorStatus {
+            filename: reader.filename.clone(),
+            ..Default::default()
+        };
         Self {
+            status: Arc::new(status),
             reader: reader,
             importer: importer,
             filters: Arc::new(Vec::new()),
@@ -63,6 +109,9 @@ impl Processor {
             end: false,
             oneshot: false,
             batch_size: DEFAULT_BATCH_SIZE,
+            broadcaster: None,
+            unpublished: Vec::new(),
+            shutdown: CancellationToken::new(),
         }
     }
 
@@ -108,16 +157,35 @@ impl Processor {
                 }
             }
         }
+        let filename = self.reader.filename.clone();
+        let metric_events = metrics::INPUT_EVENTS.with_label_values(&[&filename]);
+        let metric_dropped = metrics::INPUT_EVENTS_DROPPED.with_label_values(&[&filename]);
+        let metric_commits = metrics::INPUT_COMMITS.with_label_values(&[&filename]);
+        let metric_eofs = metrics::INPUT_EOFS.with_label_values(&[&filename]);
         let mut commits = 0;
         let mut count = 0;
         let mut eofs = 0;
+        let mut dropped = 0;
         let mut last_report = std::time::Instant::now();
         loop {
+            if self.shutdown.is_cancelled() {
+                if self.importer.pending() > 0 {
+                    self.commit().await;
+                    commits += 1;
+                    metric_commits.inc();
+                } else {
+                    // Events dropped by filters since the last commit.
+                    self.write_bookmark();
+                }
+                info!(filename = ?self.reader.filename, "Stopped reading");
+                break;
+            }
             if self.report_interval > Duration::from_secs(0)
                 && last_report.elapsed() > self.report_interval
             {
-                debug!(filename = ?self.reader.filename, "count={}, commits={}, eofs={}", count, commits, eofs);
+                debug!(filename = ?self.reader.filename, "count={}, commits={}, eofs={}, dropped={}", count, commits, eofs, dropped);
                 count = 0;
+                dropped = 0;
                 commits = 0;
                 eofs = 0;
                 last_report = std::time::Instant::now();
@@ -129,16 +197,26 @@ impl Processor {
                 }
                 Ok(None) => {
                     eofs += 1;
+                    metric_eofs.inc();
                     if self.importer.pending() > 0 {
                         self.commit().await;
                         commits += 1;
-                    } else if !self.oneshot && self.reader.is_file_changed() {
-                        info!(
-                            "File may have been rotated, will reopen: filename={:?}",
-                            self.reader.filename
-                        );
-                        if let Err(err) = self.reader.reopen() {
-                            error!("Failed to reopen {:?}, error={}", self.reader.filename, err);
+                        metric_commits.inc();
+                    } else {
+                        // Everything read has been committed or dropped.
+                        let offset = self.reader.offset();
+                        self.status.offset.store(offset, Ordering::Relaxed);
+                        if !self.oneshot && self.reader.is_file_changed() {
+                            info!(
+                                "File may have been rotated, will reopen: filename={:?}",
+                                self.reader.filename
+                            );
+                            if let Err(err) = self.reader.reopen() {
+                                error!(
+                                    "Failed to reopen {:?}, error={}",
+                                    self.reader.filename, err
+                                );
+                            }
                         }
                     }
 
@@ -150,34 +228,66 @@ impl Processor {
                     self.sleep_for(1000).await;
                 }
                 Ok(Some(mut event)) => {
-                    for filter in &*self.filters {
-                        filter.run(&mut event);
+                    if !self.filters.iter().all(|filter| filter.run(&mut event)) {
+                        dropped += 1;
+                        metric_dropped.inc();
+                        continue;
                     }
                     count += 1;
+                    metric_events.inc();
+                    if let Some(broadcaster) = &self.broadcaster {
+                        if broadcaster.is_active() && EventBroadcaster::is_broadcast(&event) {
+                            self.unpublished.push(event.clone());
+                        }
+                    }
                     self.importer.submit(event).await.unwrap();
                     if self.importer.pending() >= 100 {
                         self.commit().await;
                         commits += 1;
+                        metric_commits.inc();
                     }
                 }
             }
         }
-        info!(filename = ?self.reader.filename, "count={}, commits={}, eofs={}", count, commits, eofs);
+        info!(filename = ?self.reader.filename, "count={}, commits={}, eofs={}, dropped={}", count, commits, eofs, dropped);
     }
 
     async fn sleep_for(&self, millis: u64) {
         let d = std::time::Duration::from_millis(millis);
-        tokio::time::sleep(d).await;
+        tokio::select! {
+            _ = tokio::time::sleep(d) => {}
+            _ = self.shutdown.cancelled() => {}
+        }
     }
 
     async fn commit(&mut self) {
         loop {
             match self.importer.commit().await {
-                Ok(_n) => {
+                Ok(n) => {
+                    metrics::INPUT_EVENTS_COMMITTED
+                        .with_label_values(&[&self.reader.filename])
+                        .inc_by(n as u64);
                     self.write_bookmark();
+                    self.status
+                        .offset
+                        .store(self.reader.offset(), Ordering::Relaxed);
+                    self.status
+                        .last_commit
+                        .store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+                    self.status.failing.store(false, Ordering::Relaxed);
+                    if let Some(broadcaster) = &self.broadcaster {
+                        broadcaster.send(std::mem::take(&mut self.unpublished));
+                    }
                     break;
                 }
                 Err(err) => {
+                    self.status.failing.store(true, Ordering::Relaxed);
+                    if self.shutdown.is_cancelled() {
+                        // Without a bookmark update the events will be read
+                        // again on the next start.
+                        error!("Failed to commit events on shutdown: {}", err);
+                        break;
+                    }
                     error!("Failed to commit events (will try again): {}", err);
                     self.sleep_for(1000).await;
                 }
@@ -200,3 +310,194 @@ impl Processor {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eve::filters::EveBoxMetadataFilter;
+    use crate::eve::userfilters;
+    use crate::server::testing::TempDir;
+    use std::io::Write;
+    use std::sync::Mutex;
+
+    /// Run the events through a processor with the given filters into an
+    /// SQLite database, returning the archived and escalated flags, and tags
+    /// of each event.
+    async fn process(
+        events: &[serde_json::Value],
+        filters: Vec<EveFilter>,
+    ) -> Vec<(bool, bool, serde_json::Value)> {
+        let directory = TempDir::new("processor");
+        let filename = directory.join("eve.json");
+        let mut file = std::fs::File::create(&filename).unwrap();
+        for event in events {
+            writeln!(file, "{}", event).unwrap();
+        }
+        drop(file);
+
+        let mut conn = crate::sqlite::ConnectionBuilder::filename(None::<PathBuf>)
+            .open()
+            .unwrap();
+        crate::sqlite::init_event_db(&mut conn).unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+        let importer = Importer::SQLite(crate::sqlite::importer::Importer::new(conn.clone()));
+
+        let reader = EveReader::new(&filename.display().to_string());
+        let mut processor = Processor::new(reader, importer);
+        processor.filters = Arc::new(filters);
+        processor.oneshot = true;
+        processor.run().await;
+        // Everything read has been committed, or dropped.
+        assert_eq!(processor.status.lag().unwrap(), 0);
+
+        let conn = conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT archived, escalated, json_extract(source, '$.tags')
+                 FROM events ORDER BY rowid",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map([], |row| {
+                let tags: String = row.get(2)?;
+                Ok((
+                    row.get::<_, i64>(0)? > 0,
+                    row.get::<_, i64>(1)? > 0,
+                    serde_json::from_str(&tags).unwrap(),
+                ))
+            })
+            .unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_user_filters_archive() {
+        let filters = userfilters::from_str(
+            r#"
+- action: archive
+  match:
+    alert.signature:
+      starts_with: ETN AGGRESSIVE
+"#,
+        )
+        .unwrap();
+        let events = vec![
+            serde_json::json!({
+                "timestamp": "2022-03-01T12:00:00.000000-0600",
+                "event_type": "alert",
+                "alert": {"signature": "ETN AGGRESSIVE test event"},
+            }),
+            serde_json::json!({
+                "timestamp": "2022-03-01T12:00:01.000000-0600",
+                "event_type": "alert",
+                "alert": {"signature": "ET SCAN test event"},
+            }),
+        ];
+        let filters = vec![
+            EveFilter::UserFilters(Arc::new(filters)),
+            EveBoxMetadataFilter::default().into(),
+        ];
+        let rows = process(&events, filters).await;
+        assert_eq!(
+            rows,
+            vec![
+                (true, false, serde_json::json!([])),
+                (false, false, serde_json::json!([]))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_user_filters_actions() {
+        let filters = userfilters::from_str(
+            r#"
+- action: drop
+  match:
+    event_type: flow
+- actions:
+    - escalate
+    - tag: malware
+  match:
+    event_type: alert
+    alert.signature:
+      starts_with: ET MALWARE
+"#,
+        )
+        .unwrap();
+        let events = vec![
+            serde_json::json!({
+                "timestamp": "2022-03-01T12:00:00.000000-0600",
+                "event_type": "flow",
+            }),
+            serde_json::json!({
+                "timestamp": "2022-03-01T12:00:01.000000-0600",
+                "event_type": "alert",
+                "alert": {"signature": "ET MALWARE test event"},
+            }),
+            serde_json::json!({
+                "timestamp": "2022-03-01T12:00:02.000000-0600",
+                "event_type": "dns",
+            }),
+        ];
+        let filters = vec![
+            EveFilter::UserFilters(Arc::new(filters)),
+            EveBoxMetadataFilter::default().into(),
+        ];
+        let rows = process(&events, filters).await;
+        assert_eq!(
+            rows,
+            vec![
+                (false, true, serde_json::json!(["malware"])),
+                (false, false, serde_json::json!([]))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let directory = TempDir::new("processor-shutdown");
+        let filename = directory.join("eve.json");
+        let bookmark_filename = filename.with_extension("bookmark");
+        let mut file = std::fs::File::create(&filename).unwrap();
+        for _ in 0..3 {
+            let event = json!({
+                "timestamp": "2022-03-01T12:00:00.000000-0600",
+                "event_type": "alert",
+            });
+            writeln!(file, "{}", event).unwrap();
+        }
+        drop(file);
+
+        let mut conn = crate::sqlite::ConnectionBuilder::filename(None::<PathBuf>)
+            .open()
+            .unwrap();
+        crate::sqlite::init_event_db(&mut conn).unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+        let importer = Importer::SQLite(crate::sqlite::importer::Importer::new(conn));
+
+        let reader = EveReader::new(&filename.display().to_string());
+        let mut processor = Processor::new(reader, importer);
+        processor.bookmark_filename = Some(bookmark_filename.clone());
+        let shutdown = processor.shutdown.clone();
+        let status = processor.status.clone();
+        let task = tokio::spawn(async move { processor.run().await });
+
+        // Wait for the events to be committed, then the processor will be
+        // sleeping at the end of the file waiting for more.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while status.last_commit.load(Ordering::Relaxed) == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let bookmark = bookmark::Bookmark::from_file(&bookmark_filename).unwrap();
+        assert_eq!(bookmark.offset, 3);
+    }
+}
@@ -7,21 +7,40 @@ use serde_json::Value as JsonValue;
 use serde_yaml;
 use serde_yaml::Value as YamlValue;
 use std::collections::HashMap;
-use tracing::error;
+use std::net::IpAddr;
+use tracing::{error, warn};
+
+use crate::cidr::Cidr;
+use crate::eve::filters::add_tags;
 
 #[derive(Debug, Clone)]
 enum UserFilterMatcher {
     Regex(RegularExpression),
     StartsWith(StartsWithMatcher),
     Exact(ExactMatcher),
+    Cidr(CidrMatcher),
+    Numeric(NumericMatcher),
+    In(InMatcher),
+    Exists(bool),
+    /// Matches unless all the matchers match.
+    Not(Vec<UserFilterMatcher>),
 }
 
 impl UserFilterMatcher {
-    pub fn is_match(&self, value: &JsonValue) -> bool {
-        match self {
-            Self::Regex(m) => m.is_match(value),
-            Self::StartsWith(m) => m.is_match(value),
-            Self::Exact(m) => m.is_match(value),
+    /// Match the value of a field, None if the event does not have the
+    /// field. A missing field only matches `exists: false`, and a `not`
+    /// of a matcher that requires the field.
+    pub fn is_match(&self, value: Option<&JsonValue>) -> bool {
+        match (self, value) {
+            (Self::Exists(exists), value) => *exists == value.is_some(),
+            (Self::Not(matchers), value) => !matchers.iter().all(|m| m.is_match(value)),
+            (_, None) => false,
+            (Self::Regex(m), Some(value)) => m.is_match(value),
+            (Self::StartsWith(m), Some(value)) => m.is_match(value),
+            (Self::Exact(m), Some(value)) => m.is_match(value),
+            (Self::Cidr(m), Some(value)) => m.is_match(value),
+            (Self::Numeric(m), Some(value)) => m.is_match(value),
+            (Self::In(m), Some(value)) => m.is_match(value),
         }
     }
 }
@@ -100,35 +119,148 @@ impl RegularExpression {
     }
 }
 
+/// Matches IP addresses in any of a list of networks.
+#[derive(Debug, Clone)]
+pub struct CidrMatcher {
+    networks: Vec<Cidr>,
+}
+
+impl CidrMatcher {
+    pub fn new(networks: Vec<Cidr>) -> Self {
+        Self { networks }
+    }
+
+    pub fn is_match(&self, value: &JsonValue) -> bool {
+        if let Some(addr) = value.as_str().and_then(|s| s.parse::<IpAddr>().ok()) {
+            return self.networks.iter().any(|network| network.contains(&addr));
+        }
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+/// Compares numeric values.
+#[derive(Debug, Clone)]
+pub struct NumericMatcher {
+    op: Comparison,
+    value: f64,
+}
+
+impl NumericMatcher {
+    pub fn new(op: Comparison, value: f64) -> Self {
+        Self { op, value }
+    }
+
+    pub fn is_match(&self, value: &JsonValue) -> bool {
+        if let Some(value) = value.as_f64() {
+            return match self.op {
+                Comparison::Gt => value > self.value,
+                Comparison::Gte => value >= self.value,
+                Comparison::Lt => value < self.value,
+                Comparison::Lte => value <= self.value,
+            };
+        }
+        false
+    }
+}
+
+/// Matches values equal to any of a list of values.
+#[derive(Debug, Clone)]
+pub struct InMatcher {
+    values: Vec<JsonValue>,
+}
+
+impl InMatcher {
+    pub fn new(values: Vec<JsonValue>) -> Self {
+        Self { values }
+    }
+
+    pub fn is_match(&self, value: &JsonValue) -> bool {
+        self.values.iter().any(|v| v == value)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum UserFilterAction {
-    #[serde(rename = "archive")]
+    /// Store the event as archived.
     Archive,
+    /// Discard the event so it is not imported.
+    Drop,
+    /// Store the event as escalated.
+    Escalate,
+    /// Add a tag to the event.
+    Tag(String),
+    /// Set fields on the event, nested fields are separated by dots.
+    Set(HashMap<String, JsonValue>),
 }
 
 #[derive(Debug)]
 pub struct EveUserFilter {
-    action: UserFilterAction,
+    actions: Vec<UserFilterAction>,
     fields: Vec<(String, UserFilterMatcher)>,
+    /// Continue on to the following filters after a match.
+    pub continue_matching: bool,
 }
 
 impl EveUserFilter {
-    pub fn new(action: UserFilterAction) -> Self {
+    pub fn new(actions: Vec<UserFilterAction>) -> Self {
         Self {
-            action,
+            actions,
             fields: Vec::new(),
+            continue_matching: false,
         }
     }
 
-    pub fn is_match(&self, eve: &serde_json::Value) -> Option<UserFilterAction> {
-        for (field, matcher) in &self.fields {
-            if let Some(value) = self.get_value_for_field(field, eve) {
-                if !matcher.is_match(value) {
-                    return None;
+    pub fn actions(&self) -> &[UserFilterAction] {
+        &self.actions
+    }
+
+    /// Check if all the fields of the filter match the event.
+    pub fn is_match(&self, eve: &serde_json::Value) -> bool {
+        self.fields
+            .iter()
+            .all(|(field, matcher)| matcher.is_match(self.get_value_for_field(field, eve)))
+    }
+
+    /// Apply the actions of the filter to the event, returning false if the
+    /// event is to be dropped.
+    pub fn apply(&self, eve: &mut serde_json::Value) -> bool {
+        for action in &self.actions {
+            match action {
+                UserFilterAction::Archive => {
+                    if !add_tags(eve, &["evebox.archived"]) {
+                        warn!("Unable to archive event, event has incompatible tags entry");
+                    }
+                }
+                UserFilterAction::Drop => {
+                    return false;
+                }
+                UserFilterAction::Escalate => {
+                    if !add_tags(eve, &["evebox.escalated"]) {
+                        warn!("Unable to escalate event, event has incompatible tags entry");
+                    }
+                }
+                UserFilterAction::Tag(tag) => {
+                    if !add_tags(eve, &[tag.as_str()]) {
+                        warn!("Unable to tag event, event has incompatible tags entry");
+                    }
+                }
+                UserFilterAction::Set(fields) => {
+                    for (field, value) in fields {
+                        set_field(eve, field, value.clone());
+                    }
                 }
             }
         }
-        Some(self.action.clone())
+        true
     }
 
     fn get_value_for_field<'a>(
@@ -151,56 +283,132 @@ impl EveUserFilter {
     }
 }
 
+/// Set a possibly nested field, replacing any non-object values on the way.
+fn set_field(mut eve: &mut serde_json::Value, field: &str, value: JsonValue) {
+    let mut parts = field.split('.').peekable();
+    while let Some(part) = parts.next() {
+        if !eve.is_object() {
+            *eve = JsonValue::Object(serde_json::Map::new());
+        }
+        if parts.peek().is_none() {
+            eve[part] = value;
+            return;
+        }
+        eve = &mut eve[part];
+    }
+}
+
+/// Run the filters over an event. Only the first matching filter is applied
+/// unless it is set to continue. Returns false if the event is to be dropped.
+pub fn run(filters: &[EveUserFilter], eve: &mut serde_json::Value) -> bool {
+    for filter in filters {
+        if filter.is_match(eve) {
+            if !filter.apply(eve) {
+                return false;
+            }
+            if !filter.continue_matching {
+                break;
+            }
+        }
+    }
+    true
+}
+
 fn yaml_val_to_json(v: &YamlValue) -> anyhow::Result<JsonValue> {
     let s = serde_yaml::to_string(v)?;
     let js: JsonValue = serde_yaml::from_str(&s)?;
     Ok(js)
 }
 
+fn build_matchers(
+    field: &str,
+    config: UserFilterConfigMatchValue,
+) -> anyhow::Result<Vec<UserFilterMatcher>> {
+    let mut matchers = Vec::new();
+    let matcher = match config {
+        UserFilterConfigMatchValue::Scalar(YamlValue::Mapping(_)) => {
+            anyhow::bail!("unknown matcher for {}", field);
+        }
+        UserFilterConfigMatchValue::Scalar(v) => match yaml_val_to_json(&v) {
+            Err(err) => {
+                error!(
+                    "Failed to use value as match for {}: {:?}: error={:?}",
+                    field, &v, err
+                );
+                return Ok(matchers);
+            }
+            Ok(v) => {
+                matchers.push(UserFilterMatcher::Exact(ExactMatcher::new(v)));
+                return Ok(matchers);
+            }
+        },
+        UserFilterConfigMatchValue::Object(matcher) => matcher,
+    };
+    if let Some(re) = matcher.re {
+        let mut re_matcher = RegularExpression::new();
+        for pattern in re.into_vec() {
+            let pattern = regex::Regex::new(&pattern)?;
+            re_matcher.add_pattern(pattern);
+        }
+        matchers.push(UserFilterMatcher::Regex(re_matcher));
+    }
+    if let Some(starts_with) = matcher.starts_with {
+        let starts_with = StartsWithMatcher::new(starts_with);
+        matchers.push(UserFilterMatcher::StartsWith(starts_with));
+    }
+    if let Some(cidr) = matcher.cidr {
+        let networks = cidr
+            .into_vec()
+            .iter()
+            .map(|network| network.parse())
+            .collect::<anyhow::Result<Vec<Cidr>>>()?;
+        matchers.push(UserFilterMatcher::Cidr(CidrMatcher::new(networks)));
+    }
+    for (op, value) in [
+        (Comparison::Gt, matcher.gt),
+        (Comparison::Gte, matcher.gte),
+        (Comparison::Lt, matcher.lt),
+        (Comparison::Lte, matcher.lte),
+    ] {
+        if let Some(value) = value {
+            matchers.push(UserFilterMatcher::Numeric(NumericMatcher::new(op, value)));
+        }
+    }
+    if let Some(values) = matcher.r#in {
+        let values = values
+            .iter()
+            .map(yaml_val_to_json)
+            .collect::<anyhow::Result<Vec<JsonValue>>>()?;
+        matchers.push(UserFilterMatcher::In(InMatcher::new(values)));
+    }
+    if let Some(exists) = matcher.exists {
+        matchers.push(UserFilterMatcher::Exists(exists));
+    }
+    if let Some(not) = matcher.not {
+        matchers.push(UserFilterMatcher::Not(build_matchers(field, *not)?));
+    }
+    if matchers.is_empty() {
+        anyhow::bail!("no matcher for {}", field);
+    }
+    Ok(matchers)
+}
+
 fn build_filters(filter_configs: Vec<UserFilterConfig>) -> anyhow::Result<Vec<EveUserFilter>> {
     let mut filters = Vec::new();
     for config in filter_configs {
-        let mut filter = EveUserFilter::new(config.action);
+        let mut actions = Vec::new();
+        if let Some(action) = config.action {
+            actions.push(action);
+        }
+        actions.extend(config.actions);
+        if actions.is_empty() {
+            anyhow::bail!("filter has no action");
+        }
+        let mut filter = EveUserFilter::new(actions);
+        filter.continue_matching = config.continue_matching;
         for (field, matcher) in config.matchers {
-            match matcher {
-                UserFilterConfigMatchValue::Scalar(v) => match yaml_val_to_json(&v) {
-                    Err(err) => {
-                        error!(
-                            "Failed to use value as match for {}: {:?}: error={:?}",
-                            &field, &v, err
-                        );
-                        continue;
-                    }
-                    Ok(v) => {
-                        let matcher = ExactMatcher::new(v);
-                        filter.add_field(field.to_string(), UserFilterMatcher::Exact(matcher));
-                    }
-                },
-                UserFilterConfigMatchValue::Object(matcher) => {
-                    if let Some(re) = matcher.re {
-                        let mut re_matcher = RegularExpression::new();
-                        match re {
-                            UserFilterConfigRegexValue::Single(pattern) => {
-                                let pattern = regex::Regex::new(&pattern)?;
-                                re_matcher.add_pattern(pattern);
-                            }
-                            UserFilterConfigRegexValue::List(patterns) => {
-                                for pattern in &patterns {
-                                    let pattern = regex::Regex::new(pattern)?;
-                                    re_matcher.add_pattern(pattern);
-                                }
-                            }
-                        }
-                        filter.add_field(field.to_string(), UserFilterMatcher::Regex(re_matcher));
-                    }
-                    if let Some(starts_with) = matcher.starts_with {
-                        let starts_with = StartsWithMatcher::new(starts_with);
-                        filter.add_field(
-                            field.to_string(),
-                            UserFilterMatcher::StartsWith(starts_with),
-                        );
-                    }
-                }
+            for matcher in build_matchers(&field, matcher)? {
+                filter.add_field(field.to_string(), matcher);
             }
         }
         filters.push(filter);
@@ -218,6 +426,14 @@ pub fn from_value(v: YamlValue) -> anyhow::Result<Vec<EveUserFilter>> {
     build_filters(filter_configs)
 }
 
+/// Load the filters from the `filters` section of a configuration file.
+pub fn from_config(config: &crate::config::Config) -> anyhow::Result<Vec<EveUserFilter>> {
+    match config.get_value::<YamlValue>("filters")? {
+        Some(value) => from_value(value),
+        None => Ok(Vec::new()),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -266,30 +482,22 @@ mod test {
         "#;
         let user_filter = from_str(yaml_string).unwrap();
         assert_eq!(user_filter.len(), 3);
+        assert_eq!(user_filter[0].actions(), &[UserFilterAction::Archive]);
 
         let event_aggressive = json!({
             "alert": {
                 "signature": "ETN AGGRESSIVE test event",
             }
         });
-        assert_eq!(
-            user_filter[0].is_match(&event_aggressive),
-            Some(UserFilterAction::Archive)
-        );
+        assert!(user_filter[0].is_match(&event_aggressive));
 
         let event_tor = json!({
             "alert": {
                 "signature": "ETN TOR test event",
             }
         });
-        assert_eq!(
-            user_filter[1].is_match(&event_aggressive),
-            Some(UserFilterAction::Archive)
-        );
-        assert_eq!(
-            user_filter[1].is_match(&event_tor),
-            Some(UserFilterAction::Archive)
-        );
+        assert!(user_filter[1].is_match(&event_aggressive));
+        assert!(user_filter[1].is_match(&event_tor));
 
         let event_other = json!({
             "alert": {
@@ -297,18 +505,196 @@ mod test {
             },
             "agent": "firewall",
         });
-        assert_eq!(user_filter[0].is_match(&event_other), None);
-        assert_eq!(user_filter[1].is_match(&event_other), None);
+        assert!(!user_filter[0].is_match(&event_other));
+        assert!(!user_filter[1].is_match(&event_other));
+        assert!(user_filter[2].is_match(&event_other));
+    }
+
+    #[test]
+    fn test_matchers() {
+        let filters = from_str(
+            r#"
+  - action: archive
+    match:
+      alert.signature:
+        starts_with: ET INFO
+      src_ip:
+        cidr: 10.0.0.0/8
+      alert.severity:
+        gte: 3
+  - action: archive
+    match:
+      dest_ip:
+        cidr:
+          - 192.168.0.0/16
+          - fe80::/10
+      dest_port:
+        in: [53, 123]
+      alert.signature:
+        not:
+          re: ^ET MALWARE
+  - action: archive
+    match:
+      alert.signature:
+        exists: false
+      flow_id:
+        gt: 10
+        lt: 20
+"#,
+        )
+        .unwrap();
+
+        let event = json!({
+            "src_ip": "10.1.2.3",
+            "alert": {"signature": "ET INFO test", "severity": 3},
+        });
+        assert!(filters[0].is_match(&event));
+        let event = json!({
+            "src_ip": "11.1.2.3",
+            "alert": {"signature": "ET INFO test", "severity": 3},
+        });
+        assert!(!filters[0].is_match(&event));
+        let event = json!({
+            "src_ip": "10.1.2.3",
+            "alert": {"signature": "ET INFO test", "severity": 2},
+        });
+        assert!(!filters[0].is_match(&event));
+        // A missing field does not match.
+        let event = json!({
+            "src_ip": "10.1.2.3",
+            "alert": {"signature": "ET INFO test"},
+        });
+        assert!(!filters[0].is_match(&event));
+
+        let event = json!({"dest_ip": "fe80::1", "dest_port": 123});
+        assert!(filters[1].is_match(&event));
+        let event = json!({
+            "dest_ip": "192.168.1.1",
+            "dest_port": 53,
+            "alert": {"signature": "ET INFO test"},
+        });
+        assert!(filters[1].is_match(&event));
+        let event = json!({
+            "dest_ip": "192.168.1.1",
+            "dest_port": 53,
+            "alert": {"signature": "ET MALWARE test"},
+        });
+        assert!(!filters[1].is_match(&event));
+        let event = json!({"dest_ip": "192.168.1.1", "dest_port": 80});
+        assert!(!filters[1].is_match(&event));
+        let event = json!({"dest_ip": "not an address", "dest_port": 53});
+        assert!(!filters[1].is_match(&event));
+
+        assert!(filters[2].is_match(&json!({"flow_id": 15})));
+        assert!(!filters[2].is_match(&json!({"flow_id": 20})));
+        assert!(!filters[2].is_match(&json!({"flow_id": "15"})));
+        assert!(!filters[2].is_match(&json!({"flow_id": 15, "alert": {"signature": "x"}})));
+
+        // Unknown matchers and bad networks are errors.
+        assert!(
+            from_str("- action: archive\n  match:\n    src_ip:\n      cidrs: 10.0.0.0/8").is_err()
+        );
+        assert!(
+            from_str("- action: archive\n  match:\n    src_ip:\n      cidr: 10.0.0.0/33").is_err()
+        );
+    }
+
+    #[test]
+    fn test_actions() {
+        let filters = from_str(
+            r#"
+  - actions:
+      - tag: noisy
+      - set:
+          evebox.note: known scanner
+          severity: 3
+      - archive
+    continue: true
+    match:
+      src_ip: 10.0.0.1
+  - action: escalate
+    match:
+      alert.signature:
+        starts_with: ET MALWARE
+  - action: drop
+    match:
+      event_type: flow
+  - action: tag
+    match:
+      event_type: alert
+"#,
+        );
+        // A tag action requires the tag name.
+        assert!(filters.is_err());
+
+        let filters = from_str(
+            r#"
+  - actions:
+      - tag: noisy
+      - set:
+          evebox.note: known scanner
+          severity: 3
+      - archive
+    continue: true
+    match:
+      src_ip: 10.0.0.1
+  - action: escalate
+    match:
+      event_type: alert
+      alert.signature:
+        starts_with: ET MALWARE
+  - action: drop
+    match:
+      event_type: flow
+  - action: drop
+    match:
+      src_ip: 10.0.0.1
+"#,
+        )
+        .unwrap();
+        assert_eq!(filters.len(), 4);
+
+        // Matches the first filter which continues, then the second which
+        // does not, so the event is not dropped by the last filter.
+        let mut event = json!({
+            "event_type": "alert",
+            "src_ip": "10.0.0.1",
+            "alert": {"signature": "ET MALWARE test"},
+            "evebox": {},
+        });
+        assert!(run(&filters, &mut event));
         assert_eq!(
-            user_filter[2].is_match(&event_other),
-            Some(UserFilterAction::Archive)
+            event["tags"],
+            json!(["noisy", "evebox.archived", "evebox.escalated"])
         );
+        assert_eq!(event["evebox"]["note"], "known scanner");
+        assert_eq!(event["severity"], 3);
+
+        // First match only.
+        let mut event = json!({
+            "event_type": "alert",
+            "src_ip": "10.0.0.2",
+            "alert": {"signature": "ET MALWARE test"},
+        });
+        assert!(run(&filters, &mut event));
+        assert_eq!(event["tags"], json!(["evebox.escalated"]));
+
+        let mut event = json!({"event_type": "flow", "src_ip": "10.0.0.2"});
+        assert!(!run(&filters, &mut event));
+
+        let mut event = json!({"event_type": "dns", "src_ip": "10.0.0.2"});
+        assert!(run(&filters, &mut event));
+        assert_eq!(event, json!({"event_type": "dns", "src_ip": "10.0.0.2"}));
     }
 }
 
 #[derive(Debug, Deserialize)]
 struct UserFilterConfig {
-    pub action: UserFilterAction,
+    pub action: Option<UserFilterAction>,
+    #[serde(default)]
+    pub actions: Vec<UserFilterAction>,
+    #[serde(default, rename = "continue")]
+    pub continue_matching: bool,
     #[serde(rename = "match")]
     pub matchers: HashMap<String, UserFilterConfigMatchValue>,
 }
@@ -321,14 +707,33 @@ enum UserFilterConfigMatchValue {
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct FilterMatch {
-    re: Option<UserFilterConfigRegexValue>,
+    re: Option<UserFilterConfigStringList>,
     starts_with: Option<String>,
+    cidr: Option<UserFilterConfigStringList>,
+    gt: Option<f64>,
+    gte: Option<f64>,
+    lt: Option<f64>,
+    lte: Option<f64>,
+    #[serde(rename = "in")]
+    r#in: Option<Vec<YamlValue>>,
+    exists: Option<bool>,
+    not: Option<Box<UserFilterConfigMatchValue>>,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
-enum UserFilterConfigRegexValue {
+enum UserFilterConfigStringList {
     Single(String),
     List(Vec<String>),
 }
+
+impl UserFilterConfigStringList {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::Single(s) => vec![s],
+            Self::List(list) => list,
+        }
+    }
+}
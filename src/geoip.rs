@@ -22,45 +22,34 @@
 use crate::eve::eve::EveJson;
 use crate::prelude::*;
 use maxminddb::{geoip2, Reader};
+use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, UNIX_EPOCH};
 
 const DAYS_28: i64 = 86400 * 28;
 const UPDATE_CHECK_TIMEOUT: u64 = 60;
 
-struct Inner {
+/// A database file, reloaded when modified on disk.
+struct Database {
+    filename: String,
     reader: Reader<Vec<u8>>,
     last_modified: u64,
     last_update_check: std::time::Instant,
 }
 
-#[derive(Clone)]
-pub struct GeoIP {
-    filename: String,
-    inner: Arc<Mutex<Inner>>,
-}
-
-impl GeoIP {
-    pub fn open(filename: Option<String>) -> Result<GeoIP, Box<dyn std::error::Error>> {
-        let (filename, reader) = if let Some(filename) = &filename {
-            let reader = Reader::open_readfile(filename)?;
-            (filename.clone(), reader)
-        } else if let Some(filename) = find_database() {
-            let reader = Reader::open_readfile(&filename)?;
-            (filename, reader)
-        } else {
-            return Err("No database file found".into());
-        };
+impl Database {
+    fn open(filename: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let reader = Reader::open_readfile(filename)?;
 
         // Warn if database older than 4 weeks.
         let now = chrono::offset::Utc::now();
         let dt = chrono::NaiveDateTime::from_timestamp(reader.metadata.build_epoch as i64, 0);
         if (reader.metadata.build_epoch as i64) < now.timestamp() - DAYS_28 {
-            warn!("GeoIP database older than 4 weeks: {}", dt);
+            warn!("GeoIP database older than 4 weeks: {}: {}", filename, dt);
         }
         info!("Loaded GeoIP database: {}: {}", filename, dt);
 
-        let last_modified = match Self::get_last_modified(&filename) {
+        let last_modified = match GeoIP::get_last_modified(filename) {
             Ok(last_modified) => last_modified,
             Err(err) => {
                 error!(
@@ -71,32 +60,19 @@ impl GeoIP {
             }
         };
 
-        let inner = Inner {
-            reader: reader,
-            last_modified: last_modified,
+        Ok(Self {
+            filename: filename.to_string(),
+            reader,
+            last_modified,
             last_update_check: std::time::Instant::now(),
-        };
-
-        let geoip = GeoIP {
-            filename: filename,
-            inner: Arc::new(Mutex::new(inner)),
-        };
-        return Ok(geoip);
+        })
     }
 
-    pub fn get_last_modified(filename: &str) -> Result<u64, Box<dyn std::error::Error>> {
-        let last_modified = std::fs::metadata(filename)?
-            .modified()?
-            .duration_since(UNIX_EPOCH)?
-            .as_secs();
-        Ok(last_modified)
-    }
-
-    fn check_for_update(&self, inner: &mut Inner) -> bool {
-        if inner.last_update_check.elapsed() < Duration::from_secs(UPDATE_CHECK_TIMEOUT) {
+    fn check_for_update(&mut self) -> bool {
+        if self.last_update_check.elapsed() < Duration::from_secs(UPDATE_CHECK_TIMEOUT) {
             return false;
         }
-        let last_modified = match Self::get_last_modified(&self.filename) {
+        let last_modified = match GeoIP::get_last_modified(&self.filename) {
             Ok(last_modified) => last_modified,
             Err(err) => {
                 warn!(
@@ -107,7 +83,7 @@ impl GeoIP {
             }
         };
         let mut updated = false;
-        if last_modified <= inner.last_modified {
+        if last_modified <= self.last_modified {
             debug!("GeoIP database file has not been updated");
         } else {
             debug!("GeoIP database file on disk has been updated");
@@ -116,42 +92,150 @@ impl GeoIP {
                     error!("Failed to open new GeoIP database file: {}", err);
                 }
                 Ok(new_reader) => {
-                    inner.reader = new_reader;
-                    inner.last_modified = last_modified;
+                    self.reader = new_reader;
+                    self.last_modified = last_modified;
                     updated = true;
                 }
             }
         }
-        inner.last_update_check = std::time::Instant::now();
+        self.last_update_check = std::time::Instant::now();
+        if updated {
+            let build_time =
+                chrono::NaiveDateTime::from_timestamp(self.reader.metadata.build_epoch as i64, 0);
+            info!(
+                "GeoIP database {} has been updated to {}",
+                self.filename, build_time
+            );
+        }
         updated
     }
+}
+
+struct Inner {
+    city: Database,
+    /// A separate ASN database. Without one, ASN information is looked up
+    /// in the city database, which is found in combined databases.
+    asn: Option<Database>,
+}
+
+#[derive(Clone)]
+pub struct GeoIP {
+    filename: String,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl GeoIP {
+    /// Open a city database, along with an ASN database if one is found in
+    /// the default locations.
+    pub fn open(filename: Option<String>) -> Result<GeoIP, Box<dyn std::error::Error>> {
+        let city = if let Some(filename) = &filename {
+            Database::open(filename)?
+        } else if let Some(filename) = find_database(&PATHS) {
+            Database::open(&filename)?
+        } else {
+            return Err("No database file found".into());
+        };
+
+        let asn = match find_database(&ASN_PATHS) {
+            Some(filename) => match Database::open(&filename) {
+                Ok(asn) => Some(asn),
+                Err(err) => {
+                    warn!("Failed to open GeoIP ASN database {}: {}", filename, err);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let geoip = GeoIP {
+            filename: city.filename.clone(),
+            inner: Arc::new(Mutex::new(Inner { city, asn })),
+        };
+        return Ok(geoip);
+    }
+
+    /// Use the ASN database in the given file.
+    pub fn open_asn(&self, filename: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let asn = Database::open(filename)?;
+        self.inner.lock().unwrap().asn = Some(asn);
+        Ok(())
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// The build time of the loaded database in seconds since the epoch.
+    pub fn build_epoch(&self) -> u64 {
+        self.inner.lock().unwrap().city.reader.metadata.build_epoch
+    }
+
+    pub fn get_last_modified(filename: &str) -> Result<u64, Box<dyn std::error::Error>> {
+        let last_modified = std::fs::metadata(filename)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)?
+            .as_secs();
+        Ok(last_modified)
+    }
+
+    fn check_for_update(&self, inner: &mut Inner) {
+        inner.city.check_for_update();
+        if let Some(asn) = &mut inner.asn {
+            asn.check_for_update();
+        }
+    }
 
     pub fn lookup_city_from_str(
         &self,
         addr: &str,
     ) -> Result<geoip2::City, Box<dyn std::error::Error>> {
         let mut inner = self.inner.lock().unwrap();
-        if self.check_for_update(&mut inner) {
-            let build_time =
-                chrono::NaiveDateTime::from_timestamp(inner.reader.metadata.build_epoch as i64, 0);
-            info!("GeoIP database has been updated to {}", build_time);
-        }
-        let ip: std::net::IpAddr = std::str::FromStr::from_str(addr)?;
-        let city = inner.reader.lookup(ip)?;
+        self.check_for_update(&mut inner);
+        let ip: IpAddr = std::str::FromStr::from_str(addr)?;
+        let city = inner.city.reader.lookup(ip)?;
         Ok(city)
     }
 
     pub fn add_geoip_to_eve(&self, eve: &mut EveJson) {
         if let EveJson::String(addr) = &eve["dest_ip"] {
-            if let Ok(city) = self.lookup_city_from_str(addr) {
-                eve["geoip_destination"] = self.as_json(city);
+            if let Some(geoip) = self.lookup(addr) {
+                eve["geoip_destination"] = geoip;
             }
         }
         if let EveJson::String(addr) = &eve["src_ip"] {
-            if let Ok(city) = self.lookup_city_from_str(addr) {
-                eve["geoip_source"] = self.as_json(city);
+            if let Some(geoip) = self.lookup(addr) {
+                eve["geoip_source"] = geoip;
+            }
+        }
+    }
+
+    /// Lookup the location and ASN of an address, returning None if nothing
+    /// is known about the address.
+    fn lookup(&self, addr: &str) -> Option<EveJson> {
+        let ip: IpAddr = addr.parse().ok()?;
+        let mut inner = self.inner.lock().unwrap();
+        self.check_for_update(&mut inner);
+        let mut obj = match inner.city.reader.lookup::<geoip2::City>(ip) {
+            Ok(city) => self.as_json(city),
+            Err(_) => serde_json::json!({}),
+        };
+        let asn_reader = inner.asn.as_ref().unwrap_or(&inner.city);
+        if let Ok(asn) = asn_reader.reader.lookup::<geoip2::Asn>(ip) {
+            let mut asnobj = serde_json::json!({});
+            if let Some(number) = asn.autonomous_system_number {
+                asnobj["number"] = number.into();
             }
+            if let Some(organization) = asn.autonomous_system_organization {
+                asnobj["organization"] = organization.into();
+            }
+            if asnobj.as_object().map(|o| !o.is_empty()).unwrap_or(false) {
+                obj["asn"] = asnobj;
+            }
+        }
+        if obj.as_object().map(|o| o.is_empty()).unwrap_or(true) {
+            return None;
         }
+        Some(obj)
     }
 
     fn as_json(&self, city: geoip2::City) -> serde_json::Value {
@@ -185,19 +269,10 @@ impl GeoIP {
                 }
             }
         }
+        // A location is only useful with both the latitude and longitude.
         if let Some(location) = city.location {
-            let mut locobj = serde_json::json!({});
-            let mut include = false;
-            if let Some(lat) = location.latitude {
-                locobj["lat"] = lat.into();
-                include = true;
-            }
-            if let Some(lon) = location.longitude {
-                locobj["lon"] = lon.into();
-                include = true;
-            }
-            if include {
-                obj["location"] = locobj;
+            if let (Some(lat), Some(lon)) = (location.latitude, location.longitude) {
+                obj["location"] = serde_json::json!({"lat": lat, "lon": lon});
             }
         }
         if let Some(continent) = city.continent {
@@ -206,6 +281,9 @@ impl GeoIP {
                     obj["continent_name"] = name.to_string().into();
                 }
             }
+            if let Some(code) = continent.code {
+                obj["continent_code"] = code.into();
+            }
         }
         return obj;
     }
@@ -219,10 +297,17 @@ lazy_static! {
             "/usr/share/GeoIP/GeoLite2-City.mmdb",
         ]
     };
+    static ref ASN_PATHS: Vec<&'static str> = {
+        vec![
+            "/etc/evebox/GeoLite2-ASN.mmdb",
+            "/usr/local/share/GeoIP/GeoLite2-ASN.mmdb",
+            "/usr/share/GeoIP/GeoLite2-ASN.mmdb",
+        ]
+    };
 }
 
-fn find_database() -> Option<String> {
-    for filename in PATHS.iter() {
+fn find_database(paths: &[&str]) -> Option<String> {
+    for filename in paths {
         if maxminddb::Reader::open_readfile(filename).is_ok() {
             debug!("Found geoip database file {}", filename);
             return Some(filename.to_string());
@@ -233,6 +318,8 @@ fn find_database() -> Option<String> {
 
 #[cfg(test)]
 mod test {
+    use super::testing::{names, write_database, Value};
+    use crate::server::testing::TempDir;
 
     #[test]
     fn lookup_example() {
@@ -240,4 +327,283 @@ mod test {
             let _city = db.lookup_city_from_str("128.101.101.101").unwrap();
         }
     }
+
+    fn london() -> Vec<(&'static str, Value)> {
+        vec![
+            ("city", Value::Map(vec![("names", names("London"))])),
+            (
+                "continent",
+                Value::Map(vec![("code", "EU".into()), ("names", names("Europe"))]),
+            ),
+            (
+                "country",
+                Value::Map(vec![
+                    ("iso_code", "GB".into()),
+                    ("names", names("United Kingdom")),
+                ]),
+            ),
+            (
+                "location",
+                Value::Map(vec![
+                    ("latitude", Value::Double(51.5142)),
+                    ("longitude", Value::Double(-0.0931)),
+                ]),
+            ),
+            (
+                "subdivisions",
+                Value::Array(vec![Value::Map(vec![
+                    ("iso_code", "ENG".into()),
+                    ("names", names("England")),
+                ])]),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_add_geoip_to_eve() {
+        let directory = TempDir::new("geoip");
+        let city = directory.join("city.mmdb");
+        let asn = directory.join("asn.mmdb");
+        write_database(
+            &city,
+            "GeoLite2-City",
+            vec![
+                ("81.2.69.0/24", Value::Map(london())),
+                // A location without a longitude.
+                (
+                    "2.125.160.0/24",
+                    Value::Map(vec![(
+                        "location",
+                        Value::Map(vec![("latitude", Value::Double(51.75))]),
+                    )]),
+                ),
+            ],
+        );
+        write_database(
+            &asn,
+            "GeoLite2-ASN",
+            vec![
+                (
+                    "81.2.69.0/24",
+                    Value::Map(vec![
+                        ("autonomous_system_number", Value::Uint32(20712)),
+                        (
+                            "autonomous_system_organization",
+                            "Andrews & Arnold Ltd".into(),
+                        ),
+                    ]),
+                ),
+                (
+                    "1.128.0.0/11",
+                    Value::Map(vec![("autonomous_system_number", Value::Uint32(1221))]),
+                ),
+            ],
+        );
+        let geoip = super::GeoIP::open(Some(city.display().to_string())).unwrap();
+        geoip.open_asn(&asn.display().to_string()).unwrap();
+
+        let mut eve = serde_json::json!({"src_ip": "81.2.69.160", "dest_ip": "10.0.0.1"});
+        geoip.add_geoip_to_eve(&mut eve);
+        assert_eq!(
+            eve["geoip_source"],
+            serde_json::json!({
+                "city_name": "London",
+                "continent_code": "EU",
+                "continent_name": "Europe",
+                "country_iso_code": "GB",
+                "country_name": "United Kingdom",
+                "location": {"lat": 51.5142, "lon": -0.0931},
+                "region_iso_code": "ENG",
+                "region_name": "England",
+                "asn": {"number": 20712, "organization": "Andrews & Arnold Ltd"},
+            })
+        );
+        assert!(eve.get("geoip_destination").is_none());
+
+        // Only in the ASN database.
+        let mut eve = serde_json::json!({"src_ip": "10.0.0.1", "dest_ip": "1.128.0.1"});
+        geoip.add_geoip_to_eve(&mut eve);
+        assert_eq!(
+            eve["geoip_destination"],
+            serde_json::json!({"asn": {"number": 1221}})
+        );
+
+        // Incomplete locations are not added.
+        let mut eve = serde_json::json!({"src_ip": "2.125.160.216"});
+        geoip.add_geoip_to_eve(&mut eve);
+        assert!(eve.get("geoip_source").is_none());
+    }
+
+    #[test]
+    fn test_combined_database() {
+        let directory = TempDir::new("geoip");
+        let filename = directory.join("combined.mmdb");
+        let mut record = london();
+        record.push(("autonomous_system_number", Value::Uint32(20712)));
+        write_database(
+            &filename,
+            "GeoIP2-City-ISP",
+            vec![("81.2.69.0/24", Value::Map(record))],
+        );
+        let geoip = super::GeoIP::open(Some(filename.display().to_string())).unwrap();
+
+        let mut eve = serde_json::json!({"dest_ip": "81.2.69.1"});
+        geoip.add_geoip_to_eve(&mut eve);
+        assert_eq!(eve["geoip_destination"]["city_name"], "London");
+        assert_eq!(eve["geoip_destination"]["asn"]["number"], 20712);
+    }
+}
+
+/// Writing of small MaxMind DB files for tests.
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::path::Path;
+
+    pub(crate) enum Value {
+        Map(Vec<(&'static str, Value)>),
+        String(String),
+        Double(f64),
+        Uint16(u16),
+        Uint32(u32),
+        Uint64(u64),
+        Array(Vec<Value>),
+    }
+
+    impl From<&str> for Value {
+        fn from(s: &str) -> Self {
+            Value::String(s.to_string())
+        }
+    }
+
+    /// A map of English names, as used for the names of places.
+    pub(crate) fn names(name: &str) -> Value {
+        Value::Map(vec![("en", name.into())])
+    }
+
+    fn control(out: &mut Vec<u8>, data_type: u8, size: usize) {
+        let (size_bits, extra): (u8, Vec<u8>) = if size < 29 {
+            (size as u8, vec![])
+        } else if size < 285 {
+            (29, vec![(size - 29) as u8])
+        } else {
+            (30, ((size - 285) as u16).to_be_bytes().to_vec())
+        };
+        if data_type <= 7 {
+            out.push(data_type << 5 | size_bits);
+        } else {
+            out.push(size_bits);
+            out.push(data_type - 7);
+        }
+        out.extend(extra);
+    }
+
+    fn encode_uint(out: &mut Vec<u8>, data_type: u8, value: u64) {
+        let bytes = value.to_be_bytes();
+        let bytes: Vec<u8> = bytes.iter().copied().skip_while(|b| *b == 0).collect();
+        control(out, data_type, bytes.len());
+        out.extend(bytes);
+    }
+
+    fn encode(out: &mut Vec<u8>, value: &Value) {
+        match value {
+            Value::Map(entries) => {
+                control(out, 7, entries.len());
+                for (key, value) in entries {
+                    encode(out, &(*key).into());
+                    encode(out, value);
+                }
+            }
+            Value::String(s) => {
+                control(out, 2, s.len());
+                out.extend(s.as_bytes());
+            }
+            Value::Double(v) => {
+                control(out, 3, 8);
+                out.extend(v.to_be_bytes());
+            }
+            Value::Uint16(v) => encode_uint(out, 5, *v as u64),
+            Value::Uint32(v) => encode_uint(out, 6, *v as u64),
+            Value::Uint64(v) => encode_uint(out, 9, *v),
+            Value::Array(values) => {
+                control(out, 11, values.len());
+                for value in values {
+                    encode(out, value);
+                }
+            }
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    /// Write an IPv4 database of the given type, with a record for each
+    /// network such as "81.2.69.0/24".
+    pub(crate) fn write_database(
+        filename: &Path,
+        database_type: &str,
+        networks: Vec<(&str, Value)>,
+    ) {
+        let mut nodes: Vec<[Record; 2]> = vec![[Record::Empty; 2]];
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+        for (i, (network, value)) in networks.iter().enumerate() {
+            let (addr, len) = network.split_once('/').unwrap();
+            let addr = u32::from(addr.parse::<std::net::Ipv4Addr>().unwrap());
+            let len: usize = len.parse().unwrap();
+            let mut node = 0;
+            for bit in 0..len {
+                let side = ((addr >> (31 - bit)) & 1) as usize;
+                if bit == len - 1 {
+                    nodes[node][side] = Record::Data(i);
+                } else {
+                    node = match nodes[node][side] {
+                        Record::Node(next) => next,
+                        _ => {
+                            nodes.push([Record::Empty; 2]);
+                            nodes[node][side] = Record::Node(nodes.len() - 1);
+                            nodes.len() - 1
+                        }
+                    };
+                }
+            }
+            offsets.push(data.len());
+            encode(&mut data, value);
+        }
+
+        let node_count = nodes.len();
+        let mut buf = Vec::new();
+        for node in &nodes {
+            for record in node {
+                let value = match record {
+                    Record::Empty => node_count,
+                    Record::Node(next) => *next,
+                    Record::Data(i) => node_count + 16 + offsets[*i],
+                };
+                buf.extend(&(value as u32).to_be_bytes()[1..]);
+            }
+        }
+        buf.extend([0; 16]);
+        buf.extend(data);
+        buf.extend(b"\xab\xcd\xefMaxMind.com");
+        let metadata = Value::Map(vec![
+            ("binary_format_major_version", Value::Uint16(2)),
+            ("binary_format_minor_version", Value::Uint16(0)),
+            (
+                "build_epoch",
+                Value::Uint64(chrono::Utc::now().timestamp() as u64),
+            ),
+            ("database_type", database_type.into()),
+            ("description", names("EveBox test database")),
+            ("ip_version", Value::Uint16(4)),
+            ("languages", Value::Array(vec!["en".into()])),
+            ("node_count", Value::Uint32(node_count as u32)),
+            ("record_size", Value::Uint16(24)),
+        ]);
+        encode(&mut buf, &metadata);
+        std::fs::write(filename, buf).unwrap();
+    }
 }
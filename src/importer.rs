@@ -3,6 +3,7 @@
 // Copyright (C) 2020-2022 Jason Ish
 
 use crate::agent::importer::EveboxImporter;
+use crate::metrics;
 
 /// The importer interface, an enum wrapper around various implementations of an importer for Eve events.
 #[derive(Clone)]
@@ -30,11 +31,31 @@ impl Importer {
     }
 
     pub async fn commit(&mut self) -> anyhow::Result<usize> {
-        match self {
+        let name = self.name();
+        let timer = metrics::IMPORTER_COMMIT_DURATION
+            .with_label_values(&[name])
+            .start_timer();
+        let result = match self {
             Importer::EveBox(importer) => importer.commit().await,
             Importer::Elastic(importer) => importer.commit().await,
             Importer::SQLite(importer) => importer.commit().await,
             _ => unimplemented!(),
+        };
+        timer.observe_duration();
+        if result.is_err() {
+            metrics::IMPORTER_COMMIT_FAILURES
+                .with_label_values(&[name])
+                .inc();
+        }
+        result
+    }
+
+    /// A short name for the importer type, used to label metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Importer::EveBox(_) => "evebox",
+            Importer::Elastic(_) => "elastic",
+            Importer::SQLite(_) => "sqlite",
         }
     }
 
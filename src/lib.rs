@@ -28,6 +28,7 @@ pub mod logger;
 
 pub mod agent;
 pub mod bookmark;
+pub mod cidr;
 pub mod commands;
 pub mod config;
 mod datastore;
@@ -35,15 +36,19 @@ mod elastic;
 pub mod eve;
 pub mod geoip;
 pub mod importer;
+pub mod metrics;
 pub mod packet;
 mod path;
 pub mod pcap;
 pub mod prelude;
+pub mod reload;
 pub mod resource;
 mod rules;
 pub mod searchquery;
 pub mod server;
+pub mod shutdown;
 pub mod sqlite;
+pub mod totp;
 pub mod types;
 pub mod version;
 
@@ -0,0 +1,164 @@
+// Copyright (C) 2022 Jason Ish
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Prometheus metrics.
+//!
+//! Metrics are registered in a registry private to EveBox and exported in
+//! the Prometheus text format by the server on `/metrics`.
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new_custom(Some("evebox".to_string()), None).unwrap();
+    pub static ref INPUT_EVENTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("input_events_total", "Events read from an input file"),
+        &["filename"]
+    ));
+    pub static ref INPUT_EVENTS_DROPPED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "input_events_dropped_total",
+            "Events read from an input file and dropped by a filter"
+        ),
+        &["filename"]
+    ));
+    pub static ref INPUT_EVENTS_COMMITTED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "input_events_committed_total",
+            "Events from an input file committed to the datastore"
+        ),
+        &["filename"]
+    ));
+    pub static ref INPUT_COMMITS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("input_commits_total", "Commits made for an input file"),
+        &["filename"]
+    ));
+    pub static ref INPUT_EOFS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "input_eofs_total",
+            "Times the end of an input file was reached"
+        ),
+        &["filename"]
+    ));
+    pub static ref IMPORTER_COMMIT_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "importer_commit_duration_seconds",
+            "Time taken to commit a batch of events"
+        ),
+        &["importer"]
+    ));
+    pub static ref IMPORTER_COMMIT_FAILURES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("importer_commit_failures_total", "Failed commits"),
+        &["importer"]
+    ));
+    pub static ref SUBMIT_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "submit_requests_total",
+            "Submit requests received from agents"
+        ),
+        &["agent"]
+    ));
+    pub static ref SUBMIT_EVENTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("submit_events_total", "Events received from agents"),
+        &["agent"]
+    ));
+    pub static ref RETENTION_DELETED: IntCounter = register(IntCounter::new(
+        "retention_deleted_events_total",
+        "Events deleted by the SQLite retention job"
+    ));
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "http_request_duration_seconds",
+            "HTTP request duration by route"
+        ),
+        &["method", "route", "status"]
+    ));
+    pub static ref SESSIONS_ACTIVE: IntGauge = register(IntGauge::new(
+        "sessions_active",
+        "Logged in sessions that have not expired"
+    ));
+    pub static ref DATASTORE_QUERY_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "datastore_query_duration_seconds",
+            "Datastore query duration"
+        ),
+        &["datastore", "method"]
+    ));
+}
+
+fn register<T>(metric: prometheus::Result<T>) -> T
+where
+    T: prometheus::core::Collector + Clone + 'static,
+{
+    let metric = metric.unwrap();
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+/// Start a timer for a datastore query, the duration is recorded when the
+/// returned timer is dropped.
+pub fn datastore_timer(datastore: &str, method: &str) -> HistogramTimer {
+    DATASTORE_QUERY_DURATION
+        .with_label_values(&[datastore, method])
+        .start_timer()
+}
+
+/// Encode all metrics in the Prometheus text format.
+pub fn encode() -> anyhow::Result<String> {
+    // Metrics are registered on first use, make sure they all show up even
+    // if nothing has been recorded yet.
+    lazy_static::initialize(&INPUT_EVENTS);
+    lazy_static::initialize(&INPUT_EVENTS_DROPPED);
+    lazy_static::initialize(&INPUT_EVENTS_COMMITTED);
+    lazy_static::initialize(&INPUT_COMMITS);
+    lazy_static::initialize(&INPUT_EOFS);
+    lazy_static::initialize(&IMPORTER_COMMIT_DURATION);
+    lazy_static::initialize(&IMPORTER_COMMIT_FAILURES);
+    lazy_static::initialize(&SUBMIT_REQUESTS);
+    lazy_static::initialize(&SUBMIT_EVENTS);
+    lazy_static::initialize(&RETENTION_DELETED);
+    lazy_static::initialize(&HTTP_REQUEST_DURATION);
+    lazy_static::initialize(&SESSIONS_ACTIVE);
+    lazy_static::initialize(&DATASTORE_QUERY_DURATION);
+    let mut buf = vec![];
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        INPUT_EVENTS.with_label_values(&["/tmp/eve.json"]).inc_by(3);
+        RETENTION_DELETED.inc();
+        drop(datastore_timer("sqlite", "alert_query"));
+        let text = encode().unwrap();
+        assert!(text.contains("evebox_input_events_total{filename=\"/tmp/eve.json\"}"));
+        assert!(text.contains("evebox_retention_deleted_events_total"));
+        assert!(text.contains(
+            "evebox_datastore_query_duration_seconds_count{datastore=\"sqlite\",method=\"alert_query\"} 1"
+        ));
+    }
+}
@@ -23,13 +23,34 @@ pub fn expand(path: &str) -> anyhow::Result<Vec<std::path::PathBuf>> {
     Ok(glob::glob(path)?.flatten().collect())
 }
 
+/// Check if a path matches a pattern as given to `expand`, whether or not the
+/// file currently exists.
+pub fn matches(pattern: &str, path: &str) -> bool {
+    glob::Pattern::new(pattern)
+        .map(|pattern| pattern.matches(path))
+        .unwrap_or(false)
+}
+
 #[cfg(test)]
 mod test {
-    use super::expand;
+    use super::{expand, matches};
 
     #[test]
     fn test_expand() {
         let paths = expand("src/*.rs").unwrap();
         assert!(!paths.is_empty());
     }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches(
+            "/var/log/suricata/eve.json",
+            "/var/log/suricata/eve.json"
+        ));
+        assert!(matches(
+            "/var/log/suricata/*.json",
+            "/var/log/suricata/alerts.json"
+        ));
+        assert!(!matches("/var/log/suricata/*.json", "/var/log/eve.json"));
+    }
 }
@@ -20,6 +20,7 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use crate::prelude::*;
+use crate::shutdown::CancellationToken;
 use std::collections::HashMap;
 use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
@@ -60,7 +61,7 @@ impl RuleMap {
         }
     }
 
-    fn count(&self) -> usize {
+    pub fn count(&self) -> usize {
         let inner = self.inner.read().unwrap();
         inner.map.len()
     }
@@ -165,11 +166,24 @@ pub fn load_rules(filenames: &[String]) -> RuleMap {
 
 /// Watch the known rule files for changes.  This is a polling loop as the
 /// notify crate, at least as of the pre-5.0 releases could use some work.
-pub fn watch_rules(rulemap: Arc<RuleMap>) {
-    tokio::task::spawn_blocking(move || {
+///
+/// The watch stops on shutdown, or once the rule map is dropped, such as
+/// after it has been replaced by a configuration reload.
+pub fn watch_rules(rulemap: Arc<RuleMap>, shutdown: CancellationToken) {
+    let rulemap = Arc::downgrade(&rulemap);
+    tokio::spawn(async move {
         let mut last_modified = std::time::SystemTime::now();
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(6));
+        interval.tick().await;
         loop {
-            std::thread::sleep(std::time::Duration::from_secs(6));
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.cancelled() => break,
+            }
+            let rulemap = match rulemap.upgrade() {
+                Some(rulemap) => rulemap,
+                None => break,
+            };
             let mut reload = false;
             let filenames = rulemap.filenames();
             for filename in &filenames {
@@ -184,7 +198,9 @@ pub fn watch_rules(rulemap: Arc<RuleMap>) {
             }
             if reload {
                 info!("Rule modification detected, reloading");
-                rulemap.rescan();
+                if let Err(err) = tokio::task::spawn_blocking(move || rulemap.rescan()).await {
+                    error!("Failed to reload rules: {}", err);
+                }
             }
         }
     });
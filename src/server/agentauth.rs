@@ -0,0 +1,316 @@
+// Copyright (C) 2022 Jason Ish
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Authentication of agents submitting events to the server.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::async_trait;
+use axum::body::Body;
+use axum::extract::{Extension, FromRequest, RequestParts};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+
+use crate::prelude::*;
+use crate::server::ServerContext;
+use crate::sqlite::configrepo::ROLE_AGENT;
+
+/// How long verified credentials are remembered. Agents submit frequently and
+/// a bcrypt verification on every request would be too expensive.
+const CACHE_PERIOD: Duration = Duration::from_secs(300);
+
+struct VerifiedCredential {
+    /// Digest of the password that was verified.
+    digest: [u8; 32],
+    /// The password hash of the user when verified, so a changed password,
+    /// or a removed user, invalidates the entry.
+    password_hash: String,
+    at: Instant,
+}
+
+/// Credentials that have recently been verified, keyed by username. Only a
+/// digest of the password is kept.
+#[derive(Default)]
+pub struct AgentCredentialCache {
+    verified: Mutex<HashMap<String, VerifiedCredential>>,
+}
+
+impl AgentCredentialCache {
+    /// Check if the password was recently verified against the current
+    /// password hash of the user.
+    fn is_verified(&self, username: &str, password: &str, password_hash: &str) -> bool {
+        let verified = self.verified.lock().unwrap();
+        match verified.get(username) {
+            Some(entry) => {
+                constant_time_eq(&entry.digest, &digest(password))
+                    && entry.password_hash == password_hash
+                    && entry.at.elapsed() < CACHE_PERIOD
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&self, username: &str, password: &str, password_hash: String) {
+        let mut verified = self.verified.lock().unwrap();
+        verified.retain(|_, entry| entry.at.elapsed() < CACHE_PERIOD);
+        verified.insert(
+            username.to_string(),
+            VerifiedCredential {
+                digest: digest(password),
+                password_hash,
+                at: Instant::now(),
+            },
+        );
+    }
+}
+
+fn digest(password: &str) -> [u8; 32] {
+    use sha2::Digest;
+    sha2::Sha256::digest(password.as_bytes()).into()
+}
+
+/// Compare two digests without returning early on the first difference.
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Parse the username and password out of a basic authorization header.
+fn parse_basic_auth(headers: &HeaderMap) -> Option<(String, String)> {
+    let header = headers.get("authorization")?.to_str().ok()?;
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Get the API token from a bearer authorization header.
+pub(crate) fn parse_bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let header = headers.get("authorization")?.to_str().ok()?;
+    Some(header.strip_prefix("Bearer ")?.trim())
+}
+
+/// Extractor for the agent submitting a request, authenticated from the
+/// headers alone so it can run before the body is read. Agents are
+/// "anonymous" when authentication is not required.
+pub(crate) struct AgentExtractor(pub(crate) String);
+
+#[async_trait]
+impl FromRequest for AgentExtractor {
+    type Rejection = (StatusCode, Json<serde_json::Value>);
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<Self, Self::Rejection> {
+        let Extension(context) = Extension::<Arc<ServerContext>>::from_request(req)
+            .await
+            .unwrap();
+        if !context.config.agent_authentication_required {
+            return Ok(Self("anonymous".to_string()));
+        }
+        let headers = req.headers().expect("other extractor taken headers");
+        match authenticate(&context, headers).await {
+            Some(username) => Ok(Self(username)),
+            None => Err((
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "authentication required"})),
+            )),
+        }
+    }
+}
+
+/// Authenticate an agent request with an API token or basic authentication,
+/// returning the agent username on success.
+pub(crate) async fn authenticate(context: &ServerContext, headers: &HeaderMap) -> Option<String> {
+    if let Some(token) = parse_bearer_token(headers) {
+        return match context.config_repo.get_user_by_token(token) {
+            Ok(Some(user)) if user.role == ROLE_AGENT => Some(user.username),
+            Ok(Some(user)) => {
+                warn!(
+                    "Agent authentication failed for token of username {}: role {} is not {}",
+                    &user.username, &user.role, ROLE_AGENT
+                );
+                None
+            }
+            Ok(None) => {
+                warn!("Agent authentication failed: invalid API token");
+                None
+            }
+            Err(err) => {
+                error!("Failed to lookup API token: {}", err);
+                None
+            }
+        };
+    }
+    let (username, password) = parse_basic_auth(headers)?;
+    // Looked up on each request, without the cost of verifying the password,
+    // so removing the user or changing its password or role takes effect
+    // immediately.
+    let password_hash = match context.config_repo.get_password_hash(&username) {
+        Ok(Some((password_hash, role))) if role == ROLE_AGENT => Some(password_hash),
+        Ok(_) => None,
+        Err(err) => {
+            error!("Failed to lookup agent username {}: {}", &username, err);
+            return None;
+        }
+    };
+    if let Some(password_hash) = &password_hash {
+        if context
+            .agent_credentials
+            .is_verified(&username, &password, password_hash)
+        {
+            return Some(username);
+        }
+    }
+    if context.agent_lockout.is_locked(&username) {
+        warn!("Agent authentication for locked out username {}", &username);
+        return None;
+    }
+    match context
+        .config_repo
+        .get_user_by_username_password(&username, &password)
+        .await
+    {
+        Ok(user) if user.role == ROLE_AGENT => {
+            context.agent_lockout.success(&username);
+            if let Some(password_hash) = password_hash {
+                context
+                    .agent_credentials
+                    .insert(&username, &password, password_hash);
+            }
+            Some(username)
+        }
+        Ok(user) => {
+            warn!(
+                "Agent authentication failed for username {}: role {} is not {}",
+                &username, &user.role, ROLE_AGENT
+            );
+            None
+        }
+        Err(err) => {
+            warn!(
+                "Agent authentication failed for username {}: {}",
+                &username, err
+            );
+            context.agent_lockout.failure(&username);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_parse_authorization() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_basic_auth(&headers), None);
+
+        let value = format!("Basic {}", base64::encode("agent:secret:word"));
+        headers.insert("authorization", HeaderValue::from_str(&value).unwrap());
+        assert_eq!(
+            parse_basic_auth(&headers),
+            Some(("agent".to_string(), "secret:word".to_string()))
+        );
+
+        headers.insert("authorization", HeaderValue::from_static("Bearer token"));
+        assert_eq!(parse_basic_auth(&headers), None);
+        assert_eq!(parse_bearer_token(&headers), Some("token"));
+    }
+
+    #[test]
+    fn test_credential_cache() {
+        let cache = AgentCredentialCache::default();
+        assert!(!cache.is_verified("agent", "secret", "hash"));
+        cache.insert("agent", "secret", "hash".to_string());
+        assert!(cache.is_verified("agent", "secret", "hash"));
+        assert!(!cache.is_verified("agent", "wrong", "hash"));
+        assert!(!cache.is_verified("agent", "secret", "changed"));
+        assert!(!cache.is_verified("other", "secret", "hash"));
+    }
+
+    #[tokio::test]
+    async fn test_cached_credentials_revoked() {
+        use crate::server::testing;
+        use crate::server::ServerConfig;
+        use crate::sqlite::configrepo::{ConfigRepo, ROLE_VIEWER};
+
+        let config_repo = ConfigRepo::new(None).unwrap();
+        config_repo
+            .add_user_with_role("agent", "secret", ROLE_AGENT)
+            .unwrap();
+        let context = testing::build_context(ServerConfig::default(), config_repo);
+        let mut headers = HeaderMap::new();
+        let value = format!("Basic {}", base64::encode("agent:secret"));
+        headers.insert("authorization", HeaderValue::from_str(&value).unwrap());
+
+        // Verified, then from the cache.
+        assert!(authenticate(&context, &headers).await.is_some());
+        assert!(authenticate(&context, &headers).await.is_some());
+
+        let set_role = |role: &str| {
+            context
+                .config_repo
+                .db
+                .lock()
+                .unwrap()
+                .execute("UPDATE users SET role = ?", [role])
+                .unwrap();
+        };
+        set_role(ROLE_VIEWER);
+        assert_eq!(authenticate(&context, &headers).await, None);
+        set_role(ROLE_AGENT);
+        assert!(authenticate(&context, &headers).await.is_some());
+
+        let user = context.config_repo.get_user_by_name("agent").unwrap();
+        context
+            .config_repo
+            .update_password_by_id(&user.uuid, "changed")
+            .unwrap();
+        assert_eq!(authenticate(&context, &headers).await, None);
+
+        context.config_repo.remove_user("agent").unwrap();
+        assert_eq!(authenticate(&context, &headers).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_agent_lockout_is_separate() {
+        use crate::server::testing;
+        use crate::server::ServerConfig;
+        use crate::sqlite::configrepo::ConfigRepo;
+
+        let config_repo = ConfigRepo::new(None).unwrap();
+        config_repo
+            .add_user_with_role("agent", "secret", ROLE_AGENT)
+            .unwrap();
+        let context = testing::build_context(ServerConfig::default(), config_repo);
+
+        let mut headers = HeaderMap::new();
+        let value = format!("Basic {}", base64::encode("agent:wrong"));
+        headers.insert("authorization", HeaderValue::from_str(&value).unwrap());
+        for _ in 0..5 {
+            assert_eq!(authenticate(&context, &headers).await, None);
+        }
+        assert!(context.agent_lockout.is_locked("agent"));
+        assert!(!context.login_lockout.is_locked("agent"));
+    }
+}
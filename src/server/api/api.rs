@@ -54,9 +54,10 @@ pub(crate) async fn config(
     context: Extension<Arc<ServerContext>>,
     _session: SessionExtractor,
 ) -> impl axum::response::IntoResponse {
+    let event_services = context.event_services.read().unwrap().clone();
     let config = json!({
        "ElasticSearchIndex": context.config.elastic_index,
-       "event-services": context.event_services,
+       "event-services": event_services,
        "extra": {
             "elasticSearchKeywordSuffix": ".keyword",
        },
@@ -68,6 +69,7 @@ pub(crate) async fn config(
 pub(crate) async fn get_user(SessionExtractor(session): SessionExtractor) -> impl IntoResponse {
     let user = serde_json::json!({
         "username": session.username(),
+        "role": session.role.to_string(),
     });
     Json(user)
 }
@@ -133,13 +135,13 @@ pub(crate) async fn alert_group_star(
 
 pub(crate) async fn alert_group_unstar(
     Extension(context): Extension<Arc<ServerContext>>,
-    SessionExtractor(_session): SessionExtractor,
+    SessionExtractor(session): SessionExtractor,
     Json(request): Json<AlertGroupSpec>,
 ) -> impl IntoResponse {
     info!("De-escalating alert group: {:?}", request);
     context
         .datastore
-        .deescalate_by_alert_group(request)
+        .deescalate_by_alert_group(request, session)
         .await
         .unwrap();
     StatusCode::OK
@@ -147,10 +149,14 @@ pub(crate) async fn alert_group_unstar(
 
 pub(crate) async fn alert_group_archive(
     Extension(context): Extension<Arc<ServerContext>>,
-    SessionExtractor(_session): SessionExtractor,
+    SessionExtractor(session): SessionExtractor,
     Json(request): Json<AlertGroupSpec>,
 ) -> impl IntoResponse {
-    match context.datastore.archive_by_alert_group(request).await {
+    match context
+        .datastore
+        .archive_by_alert_group(request, session)
+        .await
+    {
         Ok(_) => StatusCode::OK,
         Err(err) => {
             error!("Failed to archive by alert group: {:?}", err);
@@ -289,9 +295,13 @@ pub(crate) async fn get_event_by_id(
 pub(crate) async fn archive_event_by_id(
     Extension(context): Extension<Arc<ServerContext>>,
     Path(event_id): axum::extract::Path<String>,
-    _session: SessionExtractor,
+    SessionExtractor(session): SessionExtractor,
 ) -> impl IntoResponse {
-    match context.datastore.archive_event_by_id(&event_id).await {
+    match context
+        .datastore
+        .archive_event_by_id(&event_id, session)
+        .await
+    {
         Ok(()) => StatusCode::OK,
         Err(err) => {
             error!(
@@ -306,9 +316,13 @@ pub(crate) async fn archive_event_by_id(
 pub(crate) async fn escalate_event_by_id(
     Extension(context): Extension<Arc<ServerContext>>,
     Path(event_id): axum::extract::Path<String>,
-    _session: SessionExtractor,
+    SessionExtractor(session): SessionExtractor,
 ) -> impl IntoResponse {
-    match context.datastore.escalate_event_by_id(&event_id).await {
+    match context
+        .datastore
+        .escalate_event_by_id(&event_id, session)
+        .await
+    {
         Ok(()) => StatusCode::OK,
         Err(err) => {
             error!(
@@ -323,9 +337,13 @@ pub(crate) async fn escalate_event_by_id(
 pub(crate) async fn deescalate_event_by_id(
     Extension(context): Extension<Arc<ServerContext>>,
     Path(event_id): axum::extract::Path<String>,
-    _session: SessionExtractor,
+    SessionExtractor(session): SessionExtractor,
 ) -> impl IntoResponse {
-    match context.datastore.deescalate_event_by_id(&event_id).await {
+    match context
+        .datastore
+        .deescalate_event_by_id(&event_id, session)
+        .await
+    {
         Ok(()) => StatusCode::OK,
         Err(err) => {
             error!(
@@ -345,7 +363,7 @@ pub(crate) async fn comment_by_event_id(
 ) -> impl IntoResponse {
     match context
         .datastore
-        .comment_event_by_id(&event_id, body.comment.to_string(), session.username())
+        .comment_event_by_id(&event_id, body.comment.to_string(), session)
         .await
     {
         Ok(()) => StatusCode::OK,
@@ -385,6 +403,36 @@ pub(crate) async fn query_elastic(
     (StatusCode::OK, "").into_response()
 }
 
+/// REST API handler to reload the configuration, as done on SIGHUP.
+pub(crate) async fn reload(
+    Extension(context): Extension<Arc<ServerContext>>,
+    _session: SessionExtractor,
+) -> impl IntoResponse {
+    let reload = match &context.reload {
+        Some(reload) => reload,
+        None => {
+            return (
+                StatusCode::NOT_IMPLEMENTED,
+                "configuration reload not supported",
+            )
+                .into_response();
+        }
+    };
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    if reload.send(tx).await.is_err() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "server is shutting down").into_response();
+    }
+    match rx.await {
+        Ok(Ok(())) => Json(json!({"status": "ok"})).into_response(),
+        Ok(Err(err)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": format!("{:#}", err)})),
+        )
+            .into_response(),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "server is shutting down").into_response(),
+    }
+}
+
 pub(crate) async fn event_query(
     _session: SessionExtractor,
     Extension(context): Extension<Arc<ServerContext>>,
@@ -471,7 +519,7 @@ pub(crate) async fn alert_group_comment(
 ) -> impl IntoResponse {
     match context
         .datastore
-        .comment_by_alert_group(request.alert_group, request.comment, session.username())
+        .comment_by_alert_group(request.alert_group, request.comment, session)
         .await
     {
         Ok(()) => StatusCode::OK,
@@ -562,6 +610,88 @@ mod test {
         let r = query.mints_from_time_range(&now).unwrap();
         dbg!(r);
     }
+
+    #[tokio::test]
+    async fn test_get_user_with_api_token() {
+        use crate::server::{testing, AuthenticationType, ServerConfig};
+        use crate::sqlite::configrepo::{ConfigRepo, ROLE_AGENT};
+
+        let config = ServerConfig {
+            authentication_required: true,
+            authentication_type: AuthenticationType::UsernamePassword,
+            ..Default::default()
+        };
+        let config_repo = ConfigRepo::new(None).unwrap();
+        config_repo.add_user("admin", "password").unwrap();
+        config_repo
+            .add_user_with_role("agent", "password", ROLE_AGENT)
+            .unwrap();
+        let (_, token) = config_repo.add_token("admin", None).unwrap();
+        let (_, agent_token) = config_repo.add_token("agent", None).unwrap();
+        let (addr, _context) = testing::start_server(config, config_repo).await;
+
+        let get_user = |token: &str| {
+            reqwest::Client::new()
+                .get(format!("http://{}/api/1/user", addr))
+                .bearer_auth(token)
+                .send()
+        };
+
+        let response = get_user(&token).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let user: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(user["username"], "admin");
+
+        let response = get_user("evebox_bad").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        // Agent tokens are only good for submitting events.
+        let response = get_user(&agent_token).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_route_roles() {
+        use crate::server::{testing, AuthenticationType, ServerConfig};
+        use crate::sqlite::configrepo::{ConfigRepo, ROLE_ADMIN, ROLE_ANALYST, ROLE_VIEWER};
+
+        let config = ServerConfig {
+            authentication_required: true,
+            authentication_type: AuthenticationType::UsernamePassword,
+            ..Default::default()
+        };
+        let config_repo = ConfigRepo::new(None).unwrap();
+        let mut tokens = std::collections::HashMap::new();
+        for role in [ROLE_VIEWER, ROLE_ANALYST, ROLE_ADMIN] {
+            config_repo
+                .add_user_with_role(role, "password", role)
+                .unwrap();
+            tokens.insert(role, config_repo.add_token(role, None).unwrap().1);
+        }
+        let (addr, _context) = testing::start_server(config, config_repo).await;
+
+        // The datastore can't be reached, so anything other than a 403 means
+        // the request got past the role check.
+        let status = |role: &str, path: &str| {
+            let request = reqwest::Client::new()
+                .post(format!("http://{}{}", addr, path))
+                .bearer_auth(&tokens[role])
+                .json(&serde_json::json!({}))
+                .send();
+            async move { request.await.unwrap().status() }
+        };
+        let forbidden = reqwest::StatusCode::FORBIDDEN;
+
+        let archive = "/api/1/event/1/archive";
+        assert_eq!(status(ROLE_VIEWER, archive).await, forbidden);
+        assert_ne!(status(ROLE_ANALYST, archive).await, forbidden);
+        assert_ne!(status(ROLE_ADMIN, archive).await, forbidden);
+
+        let query = "/api/1/query";
+        assert_eq!(status(ROLE_VIEWER, query).await, forbidden);
+        assert_eq!(status(ROLE_ANALYST, query).await, forbidden);
+        assert_ne!(status(ROLE_ADMIN, query).await, forbidden);
+    }
 }
 
 fn parse_timestamp(
@@ -0,0 +1,241 @@
+// SPDX-License-Identifier: MIT
+//
+// Copyright (C) 2022 Jason Ish
+
+//! Health and readiness endpoints.
+//!
+//! Neither requires authentication so they can be used by container
+//! orchestrators and load balancers. `/api/1/health` always responds with
+//! 200 as long as the server is running, `/api/1/ready` responds with 503
+//! until the datastore is reachable. The detailed status, which includes
+//! filenames and errors, is only reported to authenticated callers.
+
+use crate::datastore::Datastore;
+use crate::server::main::SessionExtractor;
+use crate::server::ServerContext;
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde_json::Value as JsonValue;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long to wait for the datastore to respond.
+const DATASTORE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// GeoIP databases older than this are reported as out of date.
+const GEOIP_MAX_AGE_DAYS: i64 = 28;
+
+/// A liveness check. Only authenticated callers get the detailed status, so
+/// probes don't wait on the datastore.
+pub(crate) async fn health(
+    Extension(context): Extension<Arc<ServerContext>>,
+    session: Option<SessionExtractor>,
+) -> impl IntoResponse {
+    if session.is_none() {
+        return Json(json!({"status": "ok"}));
+    }
+    let (_ready, status) = check(&context).await;
+    Json(status)
+}
+
+pub(crate) async fn ready(
+    Extension(context): Extension<Arc<ServerContext>>,
+    session: Option<SessionExtractor>,
+) -> impl IntoResponse {
+    let (ready, status) = check(&context).await;
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    if session.is_none() {
+        return (
+            code,
+            Json(json!({"status": status["status"], "ready": ready})),
+        );
+    }
+    (code, Json(status))
+}
+
+/// Check the status of each component, returning if the server is ready to
+/// serve requests along with the status to report.
+async fn check(context: &ServerContext) -> (bool, JsonValue) {
+    let datastore = check_datastore(&context.datastore).await;
+    let inputs: Vec<JsonValue> = context
+        .inputs
+        .statuses()
+        .iter()
+        .map(|input| {
+            let last_commit = input.last_commit.load(Ordering::Relaxed);
+            let failing = input.failing.load(Ordering::Relaxed);
+            let mut status = json!({
+                "filename": &input.filename,
+                "ok": !failing,
+                "offset": input.offset.load(Ordering::Relaxed),
+                "last_commit": if last_commit > 0 { format_timestamp(last_commit) } else { None },
+            });
+            match input.lag() {
+                Ok(lag) => status["lag"] = lag.into(),
+                Err(err) => {
+                    status["ok"] = false.into();
+                    status["error"] = err.to_string().into();
+                }
+            }
+            if failing {
+                status["error"] = "failed to commit events".into();
+            }
+            status
+        })
+        .collect();
+    let rules = context.rules.read().unwrap().as_ref().map(|rules| {
+        let count = rules.count();
+        json!({
+            "ok": count > 0,
+            "rules": count,
+            "files": rules.filenames().len(),
+        })
+    });
+    let geoip = context.geoip.read().unwrap().as_ref().map(|geoip| {
+        let build_epoch = geoip.build_epoch() as i64;
+        let age_days = (chrono::Utc::now().timestamp() - build_epoch) / 86400;
+        json!({
+            "ok": age_days <= GEOIP_MAX_AGE_DAYS,
+            "filename": geoip.filename(),
+            "build_time": format_timestamp(build_epoch),
+            "age_days": age_days,
+        })
+    });
+
+    let ready = datastore["ok"] == true;
+    let ok = ready
+        && inputs.iter().all(|input| input["ok"] == true)
+        && rules.as_ref().map(|s| s["ok"] == true).unwrap_or(true)
+        && geoip.as_ref().map(|s| s["ok"] == true).unwrap_or(true);
+    let status = json!({
+        "status": if ok { "ok" } else { "degraded" },
+        "ready": ready,
+        "version": crate::version::version(),
+        "datastore": datastore,
+        "inputs": inputs,
+        "rules": rules,
+        "geoip": geoip,
+    });
+    (ready, status)
+}
+
+async fn check_datastore(datastore: &Datastore) -> JsonValue {
+    let kind = match datastore {
+        Datastore::Elastic(_) => "elasticsearch",
+        Datastore::SQLite(_) => "sqlite",
+    };
+    match tokio::time::timeout(DATASTORE_TIMEOUT, datastore.ping()).await {
+        Ok(Ok(())) => json!({"type": kind, "ok": true}),
+        Ok(Err(err)) => json!({"type": kind, "ok": false, "error": err.to_string()}),
+        Err(_) => json!({"type": kind, "ok": false, "error": "timed out"}),
+    }
+}
+
+fn format_timestamp(secs: i64) -> Option<String> {
+    time::OffsetDateTime::from_unix_timestamp(secs)
+        .ok()?
+        .format(&time::format_description::well_known::Rfc3339)
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::eve::processor::ProcessorStatus;
+    use crate::server::session::Session;
+    use crate::server::testing;
+    use crate::server::{AuthenticationType, ServerConfig};
+    use crate::sqlite::configrepo::ConfigRepo;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_health() {
+        let directory = testing::TempDir::new("health");
+        let filename = directory.join("eve.json");
+        std::fs::write(&filename, b"0123456789").unwrap();
+        let input = ProcessorStatus {
+            filename: filename.display().to_string(),
+            ..Default::default()
+        };
+        input.offset.store(4, Ordering::Relaxed);
+
+        let config = ServerConfig {
+            authentication_required: true,
+            ..Default::default()
+        };
+        let context = testing::build_context(config, ConfigRepo::new(None).unwrap());
+        context.inputs.insert_status(input);
+        let (addr, _context) = testing::serve(context).await;
+
+        // The test datastore can't be reached.
+        let response = reqwest::get(format!("http://{}/api/1/ready", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+
+        let response = reqwest::get(format!("http://{}/api/1/health", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let status: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(status["status"], "degraded");
+        assert_eq!(status["ready"], false);
+        assert_eq!(status["datastore"]["type"], "elasticsearch");
+        assert_eq!(status["datastore"]["ok"], false);
+        assert_eq!(status["inputs"][0]["ok"], true);
+        assert_eq!(status["inputs"][0]["lag"], 6);
+        assert!(status["inputs"][0]["last_commit"].is_null());
+        assert!(status["rules"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_health_unauthenticated() {
+        let config = ServerConfig {
+            authentication_required: true,
+            authentication_type: AuthenticationType::UsernamePassword,
+            ..Default::default()
+        };
+        let config_repo = ConfigRepo::new(None).unwrap();
+        config_repo.add_user("admin", "password").unwrap();
+        let context = testing::build_context(config, config_repo);
+        let (addr, context) = testing::serve(context).await;
+
+        let response = reqwest::get(format!("http://{}/api/1/health", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let status: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(status, serde_json::json!({"status": "ok"}));
+
+        let response = reqwest::get(format!("http://{}/api/1/ready", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        let status: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            status,
+            serde_json::json!({"status": "degraded", "ready": false})
+        );
+
+        // The details are reported with a session.
+        let mut session = Session::new();
+        session.username = Some("admin".to_string());
+        let session_id = session.session_id.clone().unwrap();
+        context.session_store.put(Arc::new(session)).unwrap();
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/api/1/health", addr))
+            .header("x-evebox-session-id", session_id)
+            .send()
+            .await
+            .unwrap();
+        let status: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(status["datastore"]["ok"], false);
+    }
+}
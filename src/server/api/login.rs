@@ -20,22 +20,33 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use crate::prelude::*;
-use axum::extract::Extension;
-use axum::http::StatusCode;
+use axum::body::{Bytes, Full};
+use axum::extract::{ConnectInfo, Extension, Query};
+use axum::http::header::{CONTENT_TYPE, LOCATION, SET_COOKIE};
+use axum::http::{HeaderMap, HeaderValue, Response, StatusCode};
 use axum::response::IntoResponse;
 use axum::Json;
 use serde::Deserialize;
+use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::Arc;
 
+use crate::server::ldap::LdapLogin;
 use crate::server::main::SessionExtractor;
-use crate::server::session::Session;
+use crate::server::oidc::{self, OidcClient};
+use crate::server::proxyauth;
+use crate::server::session::{Role, Session};
 use crate::server::AuthenticationType;
 use crate::server::ServerContext;
+use crate::sqlite::configrepo::{ConfigRepoError, User};
 
 #[derive(Debug, Deserialize)]
 pub struct LoginForm {
     pub username: Option<String>,
     pub password: Option<String>,
+    /// The token from the first step of a login requiring a TOTP code.
+    pub login_token: Option<String>,
+    pub totp: Option<String>,
 }
 
 pub(crate) async fn options_new(
@@ -52,82 +63,429 @@ pub(crate) async fn options_new(
 
 pub(crate) async fn post(
     context: Extension<Arc<ServerContext>>,
+    Extension(ConnectInfo(remote_addr)): Extension<ConnectInfo<SocketAddr>>,
     _session: Option<SessionExtractor>,
     form: axum::extract::Form<LoginForm>,
+    // Must come after the form which also needs the headers.
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    // No authentication required.
-    if context.config.authentication_type == AuthenticationType::Anonymous {
-        return (StatusCode::OK, Json(serde_json::json!({}))).into_response();
-    }
+    let (username, role) = match context.config.authentication_type {
+        // No authentication required.
+        AuthenticationType::Anonymous => {
+            return (StatusCode::OK, Json(serde_json::json!({}))).into_response();
+        }
+        // We just take the username, and as anyone can login, they have full access.
+        AuthenticationType::Username => match &form.username {
+            Some(username) if !username.is_empty() => (username.to_string(), Role::Admin),
+            _ => return login_failed(),
+        },
+        AuthenticationType::UsernamePassword => {
+            // The second step of a login with two-factor authentication.
+            if let Some(token) = &form.login_token {
+                return totp_login(&context, token, form.totp.as_deref().unwrap_or(""));
+            }
+            let (username, password) = match (&form.username, &form.password) {
+                (Some(username), Some(password)) => (username, password),
+                _ => return login_failed(),
+            };
+            if context.login_lockout.is_locked(username) {
+                warn!("Login attempt for locked out username {}", username);
+                return too_many_failures();
+            }
+            let (username, role) = match authenticate_password(&context, username, password).await {
+                Ok(user) => match Role::from_str(&user.role) {
+                    Ok(Role::Agent) => {
+                        warn!("Login refused for agent username {}", username);
+                        return login_failed();
+                    }
+                    Ok(role) => (user.username, role),
+                    Err(err) => {
+                        error!("Login refused for username {}: {}", username, err);
+                        return login_failed();
+                    }
+                },
+                Err(PasswordError::Rejected(err)) => {
+                    warn!("Login failed for username {}: error={}", username, err);
+                    login_failure(&context, username);
+                    return login_failed();
+                }
+                Err(PasswordError::Server(err)) => {
+                    error!("Login failed for username {}: error={}", username, err);
+                    return login_failed();
+                }
+            };
+            match context.config_repo.has_totp(&username) {
+                Ok(false) => {}
+                Ok(true) => {
+                    // Failures are not cleared until the code is verified, so
+                    // the password can't be used to reset the lockout while
+                    // guessing codes.
+                    let token = context.pending_logins.add(&username, role);
+                    return (
+                        StatusCode::OK,
+                        Json(json!({"totp_required": true, "login_token": token})),
+                    )
+                        .into_response();
+                }
+                Err(err) => {
+                    error!("Login refused for username {}: {}", username, err);
+                    return login_failed();
+                }
+            }
+            context.login_lockout.success(&username);
+            (username, role)
+        }
+        // Logins are started with a redirect to the identity provider.
+        AuthenticationType::Oidc => return login_failed(),
+        // The username is provided by a trusted proxy, creating the user on first login.
+        AuthenticationType::ReverseProxy => {
+            let config = &context.config.reverse_proxy_auth;
+            let username = match proxyauth::get_username(config, &remote_addr, &headers) {
+                Some(username) => username,
+                None => return login_failed(),
+            };
+            let role = context
+                .config_repo
+                .provision_user(&username, &config.role.to_string())
+                .map_err(anyhow::Error::from)
+                .and_then(|user| Role::from_str(&user.role));
+            match role {
+                Ok(Role::Agent) => {
+                    warn!("Login refused for agent username {}", username);
+                    return login_failed();
+                }
+                Ok(role) => (username, role),
+                Err(err) => {
+                    error!("Login refused for username {}: {}", username, err);
+                    return login_failed();
+                }
+            }
+        }
+    };
+
+    let session = match create_session(&context, username, role) {
+        Ok(session) => session,
+        Err(err) => {
+            error!("Failed to add new session to session store: {}", err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": err.to_string()})),
+            )
+                .into_response();
+        }
+    };
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "session_id": session.session_id,
+        })),
+    )
+        .into_response()
+}
 
-    // We just take the username.
-    if context.config.authentication_type == AuthenticationType::Username {}
+/// Why a username and password login did not succeed.
+#[derive(thiserror::Error, Debug)]
+enum PasswordError {
+    /// The credentials were rejected, counting towards a lockout.
+    #[error("{0}")]
+    Rejected(String),
+    /// The credentials could not be checked due to a server side error.
+    #[error("{0}")]
+    Server(anyhow::Error),
+}
 
-    if context.config.authentication_type == AuthenticationType::UsernamePassword {
-        let mut session = Session::new();
-        session.username = Some(form.username.as_ref().unwrap().to_string());
-        let session = Arc::new(session);
-        context.session_store.put(session.clone()).unwrap();
-        return (
+/// Verify a username and password against the LDAP directory if configured,
+/// falling back to the local users for usernames not in the directory.
+async fn authenticate_password(
+    context: &ServerContext,
+    username: &str,
+    password: &str,
+) -> Result<User, PasswordError> {
+    let mut ldap_failed = false;
+    if let Some(ldap) = &context.ldap {
+        match ldap.login(username, password).await {
+            Ok(LdapLogin::UnknownUser) => {}
+            Ok(LdapLogin::BadPassword) => {
+                return Err(PasswordError::Rejected("bad password".to_string()))
+            }
+            Ok(LdapLogin::Authenticated(None)) => {
+                return Err(PasswordError::Rejected(
+                    "not in any group mapped to a role".to_string(),
+                ))
+            }
+            Ok(LdapLogin::Authenticated(Some(role))) => {
+                return context
+                    .config_repo
+                    .provision_ldap_user(username, &role.to_string())
+                    .map_err(|err| PasswordError::Server(err.into()));
+            }
+            Err(err) => {
+                // Local users, such as an admin, can still login while the
+                // directory is unavailable.
+                error!(
+                    "LDAP login failed for username {}, trying local users: {}",
+                    username, err
+                );
+                ldap_failed = true;
+            }
+        }
+    }
+    if ldap_failed && !context.config_repo.has_password(username).unwrap_or(false) {
+        // The user may only exist in the directory, so this is not the
+        // user's fault.
+        return Err(PasswordError::Server(anyhow!(
+            "LDAP unavailable and not a local user"
+        )));
+    }
+    match context
+        .config_repo
+        .get_user_by_username_password(username, password)
+        .await
+    {
+        Ok(user) => Ok(user),
+        Err(err @ ConfigRepoError::UsernameNotFound(_))
+        | Err(err @ ConfigRepoError::BadPassword(_)) => {
+            Err(PasswordError::Rejected(err.to_string()))
+        }
+        Err(err) => Err(PasswordError::Server(err.into())),
+    }
+}
+
+/// Complete a login with a TOTP code from the user's authenticator app.
+fn totp_login(context: &ServerContext, token: &str, code: &str) -> Response<Full<Bytes>> {
+    let (username, role) = match context.pending_logins.get(token) {
+        Some(login) => login,
+        None => return login_failed(),
+    };
+    if context.login_lockout.is_locked(&username) {
+        warn!("Login attempt for locked out username {}", username);
+        context.pending_logins.remove(token);
+        return too_many_failures();
+    }
+    match context.config_repo.verify_totp(&username, code) {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!("Login failed for username {}: bad TOTP code", username);
+            if login_failure(context, &username) {
+                context.pending_logins.remove(token);
+            }
+            return login_failed();
+        }
+        Err(err) => {
+            error!("Login refused for username {}: {}", username, err);
+            return login_failed();
+        }
+    }
+    context.pending_logins.remove(token);
+    context.login_lockout.success(&username);
+    match create_session(context, username, role) {
+        Ok(session) => (
             StatusCode::OK,
-            Json(serde_json::json!({
+            Json(json!({
                 "session_id": session.session_id,
             })),
         )
-            .into_response();
-    }
-
-    // let session = match (
-    //     &context.config.authentication_type,
-    //     &form.username,
-    //     &form.password,
-    // ) {
-    //     (AuthenticationType::Anonymous, _, _) => {
-    //         let session = Session::new();
-    //         Some(session)
-    //     }
-    //     (AuthenticationType::Username, Some(username), _) => {
-    //         let mut session = Session::new();
-    //         session.username = Some(username.to_string());
-    //         Some(session)
-    //     }
-    //     (AuthenticationType::UsernamePassword, Some(username), Some(password)) => match context
-    //         .config_repo
-    //         .get_user_by_username_password(username, password)
-    //         .await
-    //     {
-    //         Ok(user) => {
-    //             let mut session = Session::new();
-    //             session.username = Some(user.username.clone());
-    //             Some(session)
-    //         }
-    //         Err(err) => {
-    //             warn!("Login failed for username {}: error={}", username, err);
-    //             None
-    //         }
-    //     },
-    //     _ => None,
-    // };
-    //
-    // if let Some(session) = session {
-    //     let session = Arc::new(session);
-    //     if let Err(err) = context.session_store.put(session.clone()) {
-    //         error!("Failed to add new session to session store: {}", err);
-    //         return Ok(Response::InternalError(err.to_string()));
-    //     }
-    //     let response = json!({
-    //         "session_id": session.session_id,
-    //     });
-    //     return Ok(Response::Json(response));
-    // }
-    //
-    // return Ok(Response::Unauthorized);
-
-    return (
+            .into_response(),
+        Err(err) => {
+            error!("Failed to add new session to session store: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": err.to_string()})),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Record a failed login, returning true if the username is now locked out.
+fn login_failure(context: &ServerContext, username: &str) -> bool {
+    let locked = context.login_lockout.failure(username);
+    if locked {
+        warn!(
+            "Username {} locked out after repeated login failures",
+            username
+        );
+    }
+    locked
+}
+
+fn too_many_failures() -> Response<Full<Bytes>> {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        Json(json!({"error": "too many failed logins"})),
+    )
+        .into_response()
+}
+
+fn create_session(
+    context: &ServerContext,
+    username: String,
+    role: Role,
+) -> anyhow::Result<Arc<Session>> {
+    let mut session = Session::new();
+    session.username = Some(username);
+    session.role = role;
+    let session = Arc::new(session);
+    if context.config.authentication_type == AuthenticationType::Username {
+        // Not backed by a user, so the role is stored with the session.
+        context.session_store.put_with_role(session.clone())?;
+    } else {
+        context.session_store.put(session.clone())?;
+    }
+    info!(
+        "User logged in: {}, role={}",
+        session.username(),
+        session.role
+    );
+    Ok(session)
+}
+
+/// The path back to the webapp from /api/1/login/oidc, relative so this works
+/// behind a path prefix.
+const OIDC_LOGIN_ROOT: &str = "../../../";
+
+/// The path back to the webapp from /api/1/login/oidc/callback.
+const OIDC_CALLBACK_ROOT: &str = "../../../../";
+
+/// Start an OpenID Connect login by redirecting to the identity provider.
+pub(crate) async fn oidc_login(
+    Extension(context): Extension<Arc<ServerContext>>,
+) -> impl IntoResponse {
+    let oidc = match &context.oidc {
+        Some(oidc) => oidc,
+        None => return (StatusCode::NOT_FOUND, "").into_response(),
+    };
+    match oidc.authorization_url().await {
+        Ok((url, state)) => {
+            let mut response = redirect(&url);
+            set_cookie(&mut response, &oidc.state_cookie(&state));
+            response
+        }
+        Err(err) => {
+            error!("Failed to start OpenID Connect login: {}", err);
+            oidc_login_failed(OIDC_LOGIN_ROOT)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct OidcCallback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// The identity provider redirects back here after the user has logged in.
+pub(crate) async fn oidc_callback(
+    Extension(context): Extension<Arc<ServerContext>>,
+    Query(query): Query<OidcCallback>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let oidc = match &context.oidc {
+        Some(oidc) => oidc,
+        None => return (StatusCode::NOT_FOUND, "").into_response(),
+    };
+    let cookie_state = oidc::get_state_cookie(&headers);
+    let mut response = oidc_complete_login(&context, oidc, &query, cookie_state).await;
+    // The state is single use, successful or not.
+    set_cookie(&mut response, &oidc.state_cookie(""));
+    response
+}
+
+async fn oidc_complete_login(
+    context: &ServerContext,
+    oidc: &OidcClient,
+    query: &OidcCallback,
+    cookie_state: Option<&str>,
+) -> Response<Full<Bytes>> {
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => {
+            warn!("OpenID Connect login failed: error={:?}", &query.error);
+            return oidc_login_failed(OIDC_CALLBACK_ROOT);
+        }
+    };
+    let identity = match oidc.callback(code, state, cookie_state).await {
+        Ok(identity) => identity,
+        Err(err) => {
+            warn!("OpenID Connect login failed: {}", err);
+            return oidc_login_failed(OIDC_CALLBACK_ROOT);
+        }
+    };
+    let role = context
+        .config_repo
+        .provision_oidc_user(
+            &identity.issuer,
+            &identity.subject,
+            &identity.username,
+            &oidc.config.role.to_string(),
+        )
+        .map_err(anyhow::Error::from)
+        .and_then(|user| Role::from_str(&user.role).map(|role| (user.username, role)));
+    let (username, role) = match role {
+        Ok((_, Role::Agent)) => {
+            warn!("Login refused for agent username {}", &identity.username);
+            return oidc_login_failed(OIDC_CALLBACK_ROOT);
+        }
+        Ok(user) => user,
+        Err(err) => {
+            warn!(
+                "Login refused for OpenID Connect user {}: {}",
+                &identity.username, err
+            );
+            return oidc_login_failed(OIDC_CALLBACK_ROOT);
+        }
+    };
+    match create_session(context, username, role) {
+        Ok(session) => {
+            // Hand the session ID to the webapp the same way it stores it after a
+            // login, then load it.
+            let session_id = serde_json::to_string(&session.session_id).unwrap();
+            let body = format!(
+                "<!DOCTYPE html><html><head><script>\n\
+                 localStorage._sessionId = {};\n\
+                 window.location.replace(\"{}\");\n\
+                 </script></head></html>",
+                session_id, OIDC_CALLBACK_ROOT
+            );
+            Response::builder()
+                .header(CONTENT_TYPE, "text/html")
+                .body(Full::from(body))
+                .unwrap()
+        }
+        Err(err) => {
+            error!("Failed to add new session to session store: {}", err);
+            oidc_login_failed(OIDC_CALLBACK_ROOT)
+        }
+    }
+}
+
+fn redirect(location: &str) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, location)
+        .body(Full::from(""))
+        .unwrap()
+}
+
+fn set_cookie(response: &mut Response<Full<Bytes>>, cookie: &str) {
+    response
+        .headers_mut()
+        .insert(SET_COOKIE, HeaderValue::from_str(cookie).unwrap());
+}
+
+/// Send the user back to the login page with an error, `root` being the
+/// relative path from the handler to the webapp.
+fn oidc_login_failed(root: &str) -> Response<Full<Bytes>> {
+    redirect(&format!("{}#/login;error=Login%20failed", root))
+}
+
+fn login_failed() -> Response<Full<Bytes>> {
+    (
         StatusCode::UNAUTHORIZED,
         Json(json!({"error": "login failed"})),
     )
-        .into_response();
+        .into_response()
 }
 
 pub(crate) async fn logout_new(
@@ -143,3 +501,234 @@ pub(crate) async fn logout_new(
     }
     StatusCode::OK
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::testing;
+    use crate::server::ServerConfig;
+    use crate::sqlite::configrepo::{ConfigRepo, ROLE_AGENT};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_oidc_root() {
+        let base = reqwest::Url::parse("https://example.com/evebox/api/1/login/oidc").unwrap();
+        assert_eq!(base.join(OIDC_LOGIN_ROOT).unwrap().path(), "/evebox/");
+        let base = base.join("oidc/callback").unwrap();
+        assert_eq!(base.path(), "/evebox/api/1/login/oidc/callback");
+        assert_eq!(base.join(OIDC_CALLBACK_ROOT).unwrap().path(), "/evebox/");
+    }
+
+    async fn start_server(
+        authentication_type: AuthenticationType,
+    ) -> (SocketAddr, Arc<ServerContext>) {
+        let config = ServerConfig {
+            authentication_required: true,
+            authentication_type,
+            ..Default::default()
+        };
+        let config_repo = ConfigRepo::new(None).unwrap();
+        config_repo.add_user("admin", "password").unwrap();
+        testing::start_server(config, config_repo).await
+    }
+
+    async fn login(addr: &SocketAddr, username: &str, password: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("http://{}/api/1/login", addr))
+            .form(&[("username", username), ("password", password)])
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_login_username_password() {
+        let (addr, context) = start_server(AuthenticationType::UsernamePassword).await;
+
+        let response = login(&addr, "admin", "bad").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = login(&addr, "nobody", "password").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = login(&addr, "admin", "password").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let session_id = body["session_id"].as_str().unwrap();
+        let session = context.session_store.get(session_id).unwrap();
+        assert_eq!(session.username(), "admin");
+    }
+
+    #[tokio::test]
+    async fn test_login_lockout() {
+        let (addr, context) = start_server(AuthenticationType::UsernamePassword).await;
+
+        // Lock the user out, without paying for a bcrypt verification on each attempt.
+        while !context.login_lockout.failure("admin") {}
+
+        // Even the correct password is rejected now.
+        let response = login(&addr, "admin", "password").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        context.login_lockout.success("admin");
+        let response = login(&addr, "admin", "password").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_login_ldap_unavailable() {
+        let mut ldap = crate::server::ldap::LdapConfig::new("ldap://127.0.0.1:1", "dc=example");
+        ldap.timeout = std::time::Duration::from_secs(1);
+        let config = ServerConfig {
+            authentication_required: true,
+            authentication_type: AuthenticationType::UsernamePassword,
+            ldap: Some(ldap),
+            ..Default::default()
+        };
+        let config_repo = ConfigRepo::new(None).unwrap();
+        config_repo.add_user("admin", "password").unwrap();
+        let (addr, context) = testing::start_server(config, config_repo).await;
+
+        // Falls back to local users.
+        let response = login(&addr, "admin", "password").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // A user that may only exist in the directory is refused, but without
+        // counting towards a lockout.
+        for _ in 0..5 {
+            let response = login(&addr, "nobody", "password").await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        assert!(!context.login_lockout.is_locked("nobody"));
+
+        // A bad password for a local user still counts, so 3 more failures
+        // reach the lockout of 5.
+        let response = login(&addr, "admin", "bad").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(!context.login_lockout.failure("admin"));
+        assert!(!context.login_lockout.failure("admin"));
+        assert!(!context.login_lockout.failure("admin"));
+        assert!(context.login_lockout.failure("admin"));
+    }
+
+    #[tokio::test]
+    async fn test_login_agent_refused() {
+        let (addr, context) = start_server(AuthenticationType::UsernamePassword).await;
+        context
+            .config_repo
+            .add_user_with_role("agent", "password", ROLE_AGENT)
+            .unwrap();
+        let response = login(&addr, "agent", "password").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_login_totp() {
+        let (addr, context) = start_server(AuthenticationType::UsernamePassword).await;
+        let secret = crate::totp::generate_secret();
+        context
+            .config_repo
+            .set_totp_secret("admin", Some(&secret))
+            .unwrap();
+        let totp_login = |token: String, code: String| async move {
+            reqwest::Client::new()
+                .post(format!("http://{}/api/1/login", addr))
+                .form(&[("login_token", token), ("totp", code)])
+                .send()
+                .await
+                .unwrap()
+        };
+
+        // The password alone only gives a token for the second step.
+        let response = login(&addr, "admin", "password").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(body["session_id"].is_null());
+        assert_eq!(body["totp_required"], true);
+        let token = body["login_token"].as_str().unwrap().to_string();
+
+        let response = totp_login(token.clone(), "000000".to_string()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = totp_login("bad".to_string(), "000000".to_string()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let now = chrono::Utc::now().timestamp() as u64;
+        let code = crate::totp::generate(&secret, now).unwrap();
+        let response = totp_login(token.clone(), code.clone()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let session = context
+            .session_store
+            .get(body["session_id"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(session.username(), "admin");
+
+        // The token is gone, and the code can't be used again.
+        let response = totp_login(token, code.clone()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = login(&addr, "admin", "password").await;
+        let body: serde_json::Value = response.json().await.unwrap();
+        let token = body["login_token"].as_str().unwrap().to_string();
+        let response = totp_login(token, code).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    async fn proxy_login(addr: &SocketAddr, username: Option<&str>) -> reqwest::Response {
+        let request = reqwest::Client::new().post(format!("http://{}/api/1/login", addr));
+        let request = match username {
+            Some(username) => request.header("X-Remote-User", username),
+            None => request,
+        };
+        request.form(&[("username", "")]).send().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_login_reverse_proxy() {
+        let (addr, context) = start_server(AuthenticationType::ReverseProxy).await;
+
+        let response = proxy_login(&addr, None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = proxy_login(&addr, Some("alice")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let session_id = body["session_id"].as_str().unwrap();
+        let session = context.session_store.get(session_id).unwrap();
+        assert_eq!(session.username(), "alice");
+        assert_eq!(session.role, Role::Viewer);
+        assert!(context.config_repo.get_user_by_name("alice").is_ok());
+
+        // Existing users keep their role.
+        let response = proxy_login(&addr, Some("admin")).await;
+        let body: serde_json::Value = response.json().await.unwrap();
+        let session = context
+            .session_store
+            .get(body["session_id"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(session.role, Role::Admin);
+
+        // The session is not valid for another user of the proxy.
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/api/1/user", addr))
+            .header("x-evebox-session-id", session_id)
+            .header("X-Remote-User", "bob")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(context.session_store.get(session_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_login_reverse_proxy_untrusted() {
+        let mut config = ServerConfig {
+            authentication_required: true,
+            authentication_type: AuthenticationType::ReverseProxy,
+            ..Default::default()
+        };
+        config.reverse_proxy_auth.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()];
+        let (addr, _context) = testing::start_server(config, ConfigRepo::new(None).unwrap()).await;
+        let response = proxy_login(&addr, Some("alice")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
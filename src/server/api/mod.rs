@@ -6,9 +6,11 @@
 mod api;
 pub mod eve2pcap;
 pub mod flow_histogram;
+pub mod health;
 pub mod helpers;
 pub mod login;
 pub mod stats;
+pub mod stream;
 pub mod submit;
 
 pub use api::*;
@@ -0,0 +1,254 @@
+// SPDX-License-Identifier: MIT
+//
+// Copyright (C) 2022 Jason Ish
+
+//! Streaming of newly imported alerts with server-sent events.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::extract::{Extension, Form};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::IntoResponse;
+use futures::Stream;
+use serde_json::Value as JsonValue;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::prelude::*;
+use crate::searchquery::{self, Element};
+use crate::server::filters::GenericQuery;
+use crate::server::main::SessionExtractor;
+use crate::server::ServerContext;
+use crate::shutdown::CancellationToken;
+
+/// Stream alerts as they are committed, optionally filtered by a query
+/// string, sensor name and tags. Archived alerts, such as those archived by
+/// a filter, are only streamed when asked for with the tags. A client that
+/// falls behind is sent a "lagged" event with the number of alerts it
+/// missed.
+pub(crate) async fn alerts(
+    Extension(context): Extension<Arc<ServerContext>>,
+    _session: SessionExtractor,
+    Form(query): Form<GenericQuery>,
+) -> impl IntoResponse {
+    let elements = match query.query_string.as_deref().map(searchquery::parse) {
+        Some(Ok((_, elements))) => elements,
+        Some(Err(err)) => {
+            warn!("Failed to parse query string for alert stream: {}", err);
+            return Err((StatusCode::BAD_REQUEST, "failed to parse query string"));
+        }
+        None => Vec::new(),
+    };
+    let tags = match &query.tags {
+        Some(tags) => tags
+            .split(',')
+            .filter(|tag| !tag.is_empty())
+            .map(String::from)
+            .collect(),
+        None => vec!["-evebox.archived".to_string()],
+    };
+    let filter = StreamFilter {
+        elements,
+        sensor_name: query.sensor_name,
+        tags,
+    };
+    let receiver = context.broadcaster.subscribe();
+    Ok(Sse::new(stream(receiver, filter, context.shutdown.clone()))
+        .keep_alive(KeepAlive::default()))
+}
+
+/// The stream of matching alerts, ending when the server shuts down.
+fn stream(
+    receiver: tokio::sync::broadcast::Receiver<Arc<JsonValue>>,
+    filter: StreamFilter,
+    shutdown: CancellationToken,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let state = (receiver, filter, shutdown);
+    futures::stream::unfold(state, |(mut receiver, filter, shutdown)| async move {
+        loop {
+            let next = tokio::select! {
+                next = receiver.recv() => next,
+                _ = shutdown.cancelled() => return None,
+            };
+            let event = match next {
+                Ok(event) => {
+                    if !filter.is_match(&event) {
+                        continue;
+                    }
+                    match Event::default().event("alert").json_data(&*event) {
+                        Ok(event) => event,
+                        Err(err) => {
+                            error!("Failed to encode alert for stream: {}", err);
+                            continue;
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(n)) => Event::default().event("lagged").data(n.to_string()),
+                Err(RecvError::Closed) => return None,
+            };
+            return Some((Ok(event), (receiver, filter, shutdown)));
+        }
+    })
+}
+
+struct StreamFilter {
+    elements: Vec<Element>,
+    sensor_name: Option<String>,
+    /// Tags the alert must have, or must not have if prefixed with "-".
+    tags: Vec<String>,
+}
+
+impl StreamFilter {
+    /// Match an event like the SQLite query string handling: a key/value
+    /// is a numeric equality or a case-insensitive substring match on the
+    /// field, and a bare string a substring match on the whole event.
+    fn is_match(&self, event: &JsonValue) -> bool {
+        if event["event_type"] != "alert" {
+            return false;
+        }
+        if let Some(sensor_name) = &self.sensor_name {
+            if event["host"].as_str() != Some(sensor_name) {
+                return false;
+            }
+        }
+        for tag in &self.tags {
+            let (tag, must_have) = match tag.strip_prefix('-') {
+                Some(tag) => (tag, false),
+                None => (tag.as_str(), true),
+            };
+            // The short names used by the alert queries.
+            let tag = match tag {
+                "archived" => "evebox.archived",
+                "escalated" => "evebox.escalated",
+                _ => tag,
+            };
+            let has_tag = event["tags"]
+                .as_array()
+                .map(|tags| tags.iter().any(|t| t == tag))
+                .unwrap_or(false);
+            if has_tag != must_have {
+                return false;
+            }
+        }
+        self.elements.iter().all(|element| match element {
+            Element::KeyVal(key, val) => {
+                let field = key.split('.').fold(event, |value, key| &value[key]);
+                if let Ok(val) = val.parse::<i64>() {
+                    field.as_i64() == Some(val)
+                } else {
+                    match field {
+                        JsonValue::Null => false,
+                        JsonValue::String(s) => contains(s, val),
+                        _ => contains(&field.to_string(), val),
+                    }
+                }
+            }
+            Element::String(val) => contains(&event.to_string(), val),
+        })
+    }
+}
+
+fn contains(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::testing;
+    use crate::server::ServerConfig;
+    use crate::sqlite::configrepo::ConfigRepo;
+
+    fn filter(query_string: &str) -> StreamFilter {
+        StreamFilter {
+            elements: searchquery::parse(query_string).unwrap().1,
+            sensor_name: None,
+            tags: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let alert = json!({
+            "event_type": "alert",
+            "host": "sensor1",
+            "src_ip": "10.16.1.10",
+            "alert": {"signature_id": 2013028, "signature": "ET POLICY curl User-Agent"},
+        });
+        assert!(filter("").is_match(&alert));
+        assert!(filter("src_ip:10.16.1.10").is_match(&alert));
+        assert!(filter("alert.signature_id:2013028").is_match(&alert));
+        assert!(!filter("alert.signature_id:201302").is_match(&alert));
+        assert!(filter("alert.signature:curl").is_match(&alert));
+        assert!(filter("CURL src_ip:10.16").is_match(&alert));
+        assert!(!filter("wget").is_match(&alert));
+        assert!(!filter("dest_ip:10.16.1.10").is_match(&alert));
+        assert!(!filter("").is_match(&json!({"event_type": "flow"})));
+
+        let mut sensor = filter("");
+        sensor.sensor_name = Some("sensor2".to_string());
+        assert!(!sensor.is_match(&alert));
+
+        let archived = json!({"event_type": "alert", "tags": ["evebox.archived"]});
+        let mut tags = filter("");
+        tags.tags = vec!["-evebox.archived".to_string()];
+        assert!(tags.is_match(&alert));
+        assert!(!tags.is_match(&archived));
+        tags.tags = vec!["archived".to_string()];
+        assert!(!tags.is_match(&alert));
+        assert!(tags.is_match(&archived));
+    }
+
+    #[tokio::test]
+    async fn test_stream_alerts() {
+        let config = ServerConfig {
+            authentication_required: true,
+            ..Default::default()
+        };
+        let context = testing::build_context(config, ConfigRepo::new(None).unwrap());
+        let (addr, context) = testing::serve(context).await;
+
+        // Anonymous authentication, so just the session is required.
+        let mut response = reqwest::get(format!(
+            "http://{}/api/1/stream/alerts?query_string=src_ip:10.0.0.1",
+            addr
+        ))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        // Wait for the request to subscribe.
+        while !context.broadcaster.is_active() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        context.broadcaster.send(vec![
+            json!({"event_type": "flow", "src_ip": "10.0.0.1"}),
+            json!({"event_type": "alert", "src_ip": "10.0.0.2"}),
+            json!({"event_type": "alert", "src_ip": "10.0.0.1", "tags": ["evebox.archived"]}),
+            json!({"event_type": "alert", "src_ip": "10.0.0.1"}),
+        ]);
+        let chunk = response.chunk().await.unwrap().unwrap();
+        let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+        assert!(chunk.starts_with("event:alert\n"));
+        assert!(chunk.contains(r#""src_ip":"10.0.0.1""#));
+        assert!(!chunk.contains("evebox.archived"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_bad_query_string() {
+        let config = ServerConfig {
+            authentication_required: true,
+            ..Default::default()
+        };
+        let context = testing::build_context(config, ConfigRepo::new(None).unwrap());
+        let (addr, _context) = testing::serve(context).await;
+        let response = reqwest::get(format!(
+            "http://{}/api/1/stream/alerts?query_string=:src_ip",
+            addr
+        ))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+}
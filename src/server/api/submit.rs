@@ -29,13 +29,21 @@ use serde_json::json;
 use std::io::BufRead;
 use std::sync::Arc;
 
+use crate::eve::broadcast::EventBroadcaster;
 use crate::eve::eve::EveJson;
+use crate::metrics;
+use crate::server::agentauth::AgentExtractor;
 use crate::server::ServerContext;
 
 pub(crate) async fn handler_new(
     Extension(context): Extension<Arc<ServerContext>>,
+    // The agent is authenticated before the body is read, and its username
+    // labels the submit metrics.
+    AgentExtractor(agent): AgentExtractor,
     ContentLengthLimit(body): ContentLengthLimit<Bytes, { 1024 * 1024 * 256 }>,
 ) -> impl IntoResponse {
+    metrics::SUBMIT_REQUESTS.with_label_values(&[&agent]).inc();
+
     let mut importer = match context.datastore.get_importer() {
         Some(importer) => importer,
         None => {
@@ -43,9 +51,12 @@ pub(crate) async fn handler_new(
         }
     };
     let mut errors = Vec::new();
+    let mut unpublished = Vec::new();
+    let broadcast = context.broadcaster.is_active();
 
     let mut buf = &body[..];
     let mut count = 0;
+    let mut dropped = 0;
     let mut line = String::new();
     loop {
         match buf.read_line(&mut line) {
@@ -66,8 +77,16 @@ pub(crate) async fn handler_new(
                             err, line
                         ));
                     }
-                    Ok(event) => {
+                    Ok(mut event) => {
+                        if !context.submit_filters.run(&mut event) {
+                            dropped += 1;
+                            line.truncate(0);
+                            continue;
+                        }
                         count += 1;
+                        if broadcast && EventBroadcaster::is_broadcast(&event) {
+                            unpublished.push(event.clone());
+                        }
                         if let Err(err) = importer.submit(event).await {
                             error!("Failed to submit event to importer: {}", err);
                         }
@@ -78,6 +97,10 @@ pub(crate) async fn handler_new(
         line.truncate(0);
     }
 
+    metrics::SUBMIT_EVENTS
+        .with_label_values(&[&agent])
+        .inc_by(count + dropped);
+
     // I've seen an issue in the Go agent where it sent 0 events, return early if we have
     // nothing to commit.
     if count == 0 {
@@ -90,7 +113,13 @@ pub(crate) async fn handler_new(
 
     match importer.commit().await {
         Ok(n) => {
-            debug!("Committed {} events (received {})", n, count);
+            context.broadcaster.send(unpublished);
+            debug!(
+                "Committed {} events (received {}, dropped {})",
+                n,
+                count + dropped,
+                dropped
+            );
             let response = json!({
                 // Kept capitolized for compatibility with the Go agent.
                 "Count": n,
@@ -103,3 +132,169 @@ pub(crate) async fn handler_new(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::datastore::{Datastore, EventQueryParams};
+    use crate::eve::filters::{EveFilter, ReloadableFilters};
+    use crate::server::testing;
+    use crate::server::ServerConfig;
+    use crate::sqlite::configrepo::{ConfigRepo, ROLE_AGENT};
+
+    async fn submit(
+        addr: &std::net::SocketAddr,
+        credentials: Option<(&str, &str)>,
+    ) -> reqwest::StatusCode {
+        let request = reqwest::Client::new().post(format!("http://{}/api/1/submit", addr));
+        let request = match credentials {
+            Some((username, password)) => request.basic_auth(username, Some(password)),
+            None => request,
+        };
+        // A body without events is accepted without touching the datastore.
+        request.body("\n").send().await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_submit_authentication() {
+        let config = ServerConfig {
+            agent_authentication_required: true,
+            ..Default::default()
+        };
+        let config_repo = ConfigRepo::new(None).unwrap();
+        config_repo
+            .add_user_with_role("agent", "secret", ROLE_AGENT)
+            .unwrap();
+        config_repo.add_user("admin", "secret").unwrap();
+        let (addr, context) = testing::start_server(config, config_repo).await;
+
+        assert_eq!(submit(&addr, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            submit(&addr, Some(("agent", "bad"))).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            submit(&addr, Some(("admin", "secret"))).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            submit(&addr, Some(("agent", "secret"))).await,
+            StatusCode::OK
+        );
+        // Now from the cache.
+        assert_eq!(
+            submit(&addr, Some(("agent", "secret"))).await,
+            StatusCode::OK
+        );
+
+        let (_, token) = context.config_repo.add_token("agent", None).unwrap();
+        let status = reqwest::Client::new()
+            .post(format!("http://{}/api/1/submit", addr))
+            .bearer_auth(token)
+            .body("\n")
+            .send()
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_submit_unauthenticated_body_not_read() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = ServerConfig {
+            agent_authentication_required: true,
+            ..Default::default()
+        };
+        let (addr, _context) = testing::start_server(config, ConfigRepo::new(None).unwrap()).await;
+
+        // Announce a large body but never send it, the request must be
+        // rejected without waiting for the body.
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"POST /api/1/submit HTTP/1.1\r\n\
+                  Host: localhost\r\n\
+                  Content-Length: 268435456\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let n = tokio::time::timeout(std::time::Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("response before the body was sent")
+            .unwrap();
+        let response = String::from_utf8_lossy(&buf[..n]);
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_submit_filters() {
+        let filters = crate::eve::userfilters::from_str(
+            r#"
+- action: drop
+  match:
+    event_type: flow
+"#,
+        )
+        .unwrap();
+        let mut context = ServerContext::new(
+            ServerConfig::default(),
+            Arc::new(ConfigRepo::new(None).unwrap()),
+            Datastore::SQLite(testing::sqlite_eventstore().await),
+        );
+        context.submit_filters =
+            ReloadableFilters::new(vec![EveFilter::UserFilters(Arc::new(filters))]);
+        let (addr, context) = testing::serve(context).await;
+        let submit = |body: &'static str| async move {
+            reqwest::Client::new()
+                .post(format!("http://{}/api/1/submit", addr))
+                .body(body)
+                .send()
+                .await
+                .unwrap()
+        };
+
+        // Dropped events never reach the datastore.
+        let response = submit(
+            r#"{"timestamp": "2022-03-01T12:00:00.000000-0600", "event_type": "flow"}
+"#,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["Count"], 0);
+
+        let response = submit(
+            r#"{"timestamp": "2022-03-01T12:00:00.000000-0600", "event_type": "flow"}
+{"timestamp": "2022-03-01T12:00:00.000000-0600", "event_type": "dns"}
+"#,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["Count"], 1);
+
+        // Only the dns event was stored.
+        let events = context
+            .datastore
+            .event_query(EventQueryParams::default())
+            .await
+            .unwrap();
+        let event_types: Vec<&serde_json::Value> = events["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| &event["_source"]["event_type"])
+            .collect();
+        assert_eq!(event_types, vec!["dns"]);
+    }
+
+    #[tokio::test]
+    async fn test_submit_authentication_not_required() {
+        let config_repo = ConfigRepo::new(None).unwrap();
+        let (addr, _context) = testing::start_server(ServerConfig::default(), config_repo).await;
+        assert_eq!(submit(&addr, None).await, StatusCode::OK);
+    }
+}
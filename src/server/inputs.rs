@@ -0,0 +1,397 @@
+// SPDX-License-Identifier: MIT
+//
+// Copyright (C) 2022 Jason Ish
+
+//! The file inputs, which are started as files matching their configured
+//! patterns are found, and stopped as the configuration is reloaded.
+
+use crate::config::Config;
+use crate::eve::filters::{
+    AddRuleFilter, CustomFieldFilter, EveBoxMetadataFilter, EveFilter, ReloadableFilters,
+};
+use crate::eve::processor::{Processor, ProcessorStatus};
+use crate::prelude::*;
+use crate::shutdown::CancellationToken;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+/// An input as configured, a filename or glob pattern along with the
+/// settings of the files it matches.
+#[derive(Clone)]
+pub struct InputConfig {
+    pub pattern: String,
+    /// Start at the end of new files that don't have a bookmark.
+    pub end: bool,
+    pub bookmark_directory: Option<String>,
+    /// Filters run before the filters shared by all inputs.
+    pub filters: Vec<EveFilter>,
+}
+
+/// An entry of the `inputs` list in the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct InputSettings {
+    filename: String,
+    end: Option<bool>,
+    bookmark_directory: Option<String>,
+    #[serde(default)]
+    custom_fields: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    rules: Vec<String>,
+    filters: Option<serde_yaml::Value>,
+}
+
+/// Load the inputs from the `inputs` list, along with the single input of
+/// the `input` section.
+pub fn from_config(
+    config: &Config,
+    shutdown: &CancellationToken,
+) -> anyhow::Result<Vec<InputConfig>> {
+    let end = config.get_bool("end")?;
+    let bookmark_directory: Option<String> = config.get("input.bookmark-directory")?;
+    let mut inputs = Vec::new();
+
+    let input_enabled =
+        config.args.occurrences_of("input.filename") > 0 || config.get_bool("input.enabled")?;
+    if input_enabled {
+        if let Some(pattern) = config.get::<String>("input.filename")? {
+            let custom_fields = config
+                .get_value::<HashMap<String, serde_json::Value>>("input.custom-fields")
+                .map_err(|err| anyhow!("Bad input.custom-fields: {}", err))?
+                .unwrap_or_default();
+            inputs.push(InputConfig {
+                pattern,
+                end,
+                bookmark_directory: bookmark_directory.clone(),
+                filters: custom_field_filters(custom_fields)?,
+            });
+        }
+    }
+
+    let settings = config
+        .get_value::<Vec<InputSettings>>("inputs")
+        .map_err(|err| anyhow!("Bad inputs configuration: {}", err))?
+        .unwrap_or_default();
+    for settings in settings {
+        let mut filters = Vec::new();
+        if !settings.rules.is_empty() {
+            let rulemap = Arc::new(crate::rules::load_rules(&settings.rules));
+            crate::rules::watch_rules(rulemap.clone(), shutdown.clone());
+            filters.push(EveFilter::AddRuleFilter(AddRuleFilter { map: rulemap }));
+        }
+        filters.extend(custom_field_filters(settings.custom_fields)?);
+        if let Some(value) = settings.filters {
+            let user_filters = crate::eve::userfilters::from_value(value)
+                .map_err(|err| anyhow!("Bad filters for input {}: {}", settings.filename, err))?;
+            filters.push(EveFilter::UserFilters(Arc::new(user_filters)));
+        }
+        inputs.push(InputConfig {
+            pattern: settings.filename,
+            end: settings.end.unwrap_or(end),
+            bookmark_directory: settings
+                .bookmark_directory
+                .or_else(|| bookmark_directory.clone()),
+            filters,
+        });
+    }
+
+    Ok(inputs)
+}
+
+/// Filters to set custom fields, which must be top level fields with simple
+/// values.
+fn custom_field_filters(
+    fields: HashMap<String, serde_json::Value>,
+) -> anyhow::Result<Vec<EveFilter>> {
+    let mut fields: Vec<(String, serde_json::Value)> = fields.into_iter().collect();
+    fields.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut filters = Vec::new();
+    for (field, value) in fields {
+        match &value {
+            serde_json::Value::String(_)
+            | serde_json::Value::Number(_)
+            | serde_json::Value::Bool(_) => {}
+            _ => bail!("Bad value for custom field {}: {}", field, value),
+        }
+        info!("Adding custom field: {} -> {}", field, value);
+        filters.push(CustomFieldFilter::new(&field, value).into());
+    }
+    Ok(filters)
+}
+
+struct Input {
+    status: Arc<ProcessorStatus>,
+    shutdown: CancellationToken,
+    filters: ReloadableFilters,
+    task: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+pub struct Inputs {
+    /// The filters shared by all inputs.
+    pub filters: ReloadableFilters,
+    configs: Mutex<Vec<InputConfig>>,
+    running: Mutex<HashMap<String, Input>>,
+    /// Inputs that have been stopped, but may still be committing their
+    /// last events, by filename.
+    stopping: Mutex<HashMap<String, JoinHandle<()>>>,
+}
+
+impl Inputs {
+    pub fn filenames(&self) -> Vec<String> {
+        self.running.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Check if a stopped input is still finishing up, so must not be
+    /// restarted yet as both would read from the same bookmark.
+    fn is_stopping(&self, filename: &str) -> bool {
+        let mut stopping = self.stopping.lock().unwrap();
+        stopping.retain(|_, task| !task.is_finished());
+        stopping.contains_key(filename)
+    }
+
+    /// Replace the configured inputs. Running inputs take the filters of the
+    /// first input matching their filename, and are stopped if none match.
+    /// Inputs are not stopped when their file is missing, such as during a
+    /// rotation.
+    pub fn configure(&self, configs: Vec<InputConfig>) {
+        for filename in self.filenames() {
+            let config = configs
+                .iter()
+                .find(|config| crate::path::matches(&config.pattern, &filename));
+            match config {
+                Some(config) => {
+                    if let Some(input) = self.running.lock().unwrap().get(&filename) {
+                        input.filters.swap(config.filters.clone());
+                    }
+                }
+                None => self.stop(&filename),
+            }
+        }
+        *self.configs.lock().unwrap() = configs;
+    }
+
+    /// Find files matching the configured inputs that are not yet running,
+    /// along with the configuration of the first input matching each.
+    pub fn scan(&self) -> anyhow::Result<Vec<(String, InputConfig)>> {
+        let configs = self.configs.lock().unwrap().clone();
+        let running = self.filenames();
+        let mut found: Vec<(String, InputConfig)> = Vec::new();
+        for config in configs {
+            for path in crate::path::expand(&config.pattern)? {
+                let filename = path.display().to_string();
+                if !running.contains(&filename)
+                    && !found.iter().any(|(f, _)| f == &filename)
+                    && !self.is_stopping(&filename)
+                {
+                    found.push((filename, config.clone()));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Start a processor as an input, with the filters for its input ahead
+    /// of the shared filters. The processor is stopped through its shutdown
+    /// token, so each input should have its own.
+    pub fn start(&self, mut processor: Processor, filters: Vec<EveFilter>) {
+        let filename = processor.status.filename.clone();
+        if self.is_stopping(&filename) {
+            warn!("Reader for {} is still stopping", &filename);
+            return;
+        }
+        let mut running = self.running.lock().unwrap();
+        if running.contains_key(&filename) {
+            warn!("Reader for {} already running", &filename);
+            return;
+        }
+        info!("Starting reader for {}", &filename);
+        let filters = ReloadableFilters::new(filters);
+        processor.filters = Arc::new(vec![
+            filters.clone().into(),
+            self.filters.clone().into(),
+            EveBoxMetadataFilter {
+                filename: Some(filename.clone()),
+            }
+            .into(),
+        ]);
+        let status = processor.status.clone();
+        let shutdown = processor.shutdown.clone();
+        let task = tokio::spawn(async move {
+            processor.run().await;
+        });
+        running.insert(
+            filename,
+            Input {
+                status,
+                shutdown,
+                filters,
+                task: Some(task),
+            },
+        );
+    }
+
+    /// Stop an input. Pending events are committed and the bookmark written
+    /// before the input finishes.
+    pub fn stop(&self, filename: &str) {
+        let input = self.running.lock().unwrap().remove(filename);
+        if let Some(input) = input {
+            info!("Stopping reader for {}", filename);
+            input.shutdown.cancel();
+            if let Some(task) = input.task {
+                self.stopping
+                    .lock()
+                    .unwrap()
+                    .insert(filename.to_string(), task);
+            }
+        }
+    }
+
+    /// The status of the running inputs, ordered by filename.
+    pub fn statuses(&self) -> Vec<Arc<ProcessorStatus>> {
+        let running = self.running.lock().unwrap();
+        let mut statuses: Vec<Arc<ProcessorStatus>> =
+            running.values().map(|input| input.status.clone()).collect();
+        statuses.sort_by(|a, b| a.filename.cmp(&b.filename));
+        statuses
+    }
+
+    /// Wait for all inputs, including those already stopped, to finish.
+    pub async fn join(&self) {
+        let mut tasks: Vec<JoinHandle<()>> = self
+            .stopping
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, task)| task)
+            .collect();
+        for input in self.running.lock().unwrap().values_mut() {
+            if let Some(task) = input.task.take() {
+                tasks.push(task);
+            }
+        }
+        futures::future::join_all(tasks).await;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn insert_status(&self, status: ProcessorStatus) {
+        let input = Input {
+            status: Arc::new(status),
+            shutdown: CancellationToken::new(),
+            filters: ReloadableFilters::default(),
+            task: None,
+        };
+        self.running
+            .lock()
+            .unwrap()
+            .insert(input.status.filename.clone(), input);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::testing::{self, TempDir};
+
+    #[test]
+    fn test_from_config() {
+        let directory = TempDir::new("inputs");
+        let filename = directory.join("evebox.yaml");
+        std::fs::write(
+            &filename,
+            r#"
+input:
+  enabled: true
+  filename: /var/log/suricata/eve.json
+  bookmark-directory: /var/lib/evebox
+  custom-fields:
+    host: evebox-server
+    rack: 4
+inputs:
+  - filename: /var/log/sensor-a/*.json
+    end: true
+    custom-fields:
+      host: sensor-a
+    filters:
+      - action: drop
+        match:
+          event_type: stats
+  - filename: /var/log/sensor-b/eve.json
+    bookmark-directory: /var/lib/evebox/sensor-b
+"#,
+        )
+        .unwrap();
+        let args = testing::server_args();
+        let config = Config::new(&args, Some(&filename.display().to_string())).unwrap();
+        let inputs = from_config(&config, &CancellationToken::new()).unwrap();
+
+        assert_eq!(inputs.len(), 3);
+        assert_eq!(inputs[0].pattern, "/var/log/suricata/eve.json");
+        assert!(!inputs[0].end);
+        let mut event = serde_json::json!({"event_type": "alert", "host": "suricata"});
+        assert!(inputs[0].filters.iter().all(|f| f.run(&mut event)));
+        assert_eq!(event["host"], "evebox-server");
+        assert_eq!(event["rack"], 4);
+
+        assert_eq!(inputs[1].pattern, "/var/log/sensor-a/*.json");
+        assert!(inputs[1].end);
+        assert_eq!(
+            inputs[1].bookmark_directory.as_deref(),
+            Some("/var/lib/evebox")
+        );
+        assert_eq!(inputs[1].filters.len(), 2);
+
+        let mut event = serde_json::json!({"event_type": "alert"});
+        assert!(inputs[1].filters.iter().all(|f| f.run(&mut event)));
+        assert_eq!(event["host"], "sensor-a");
+        let mut event = serde_json::json!({"event_type": "stats"});
+        assert!(!inputs[1].filters.iter().all(|f| f.run(&mut event)));
+
+        assert!(!inputs[2].end);
+        assert_eq!(
+            inputs[2].bookmark_directory.as_deref(),
+            Some("/var/lib/evebox/sensor-b")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_waits_for_stopping() {
+        let directory = TempDir::new("inputs-stopping");
+        let filename = directory.join("eve.json");
+        std::fs::write(&filename, "").unwrap();
+        let filename = filename.display().to_string();
+
+        let inputs = Inputs::default();
+        inputs.configure(vec![InputConfig {
+            pattern: filename.clone(),
+            end: false,
+            bookmark_directory: None,
+            filters: Vec::new(),
+        }]);
+        assert_eq!(inputs.scan().unwrap().len(), 1);
+
+        // A stopped reader still committing its last events.
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            let _ = rx.await;
+        });
+        inputs
+            .stopping
+            .lock()
+            .unwrap()
+            .insert(filename.clone(), task);
+        assert!(inputs.scan().unwrap().is_empty());
+
+        // Once it has finished the file can be started again.
+        tx.send(()).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while inputs.scan().unwrap().is_empty() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright (C) 2022 Jason Ish
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! LDAP and Active Directory login with search and bind.
+
+use std::time::Duration;
+
+use ldap3::{LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+use crate::prelude::*;
+use crate::server::session::Role;
+
+/// The LDAP result code for invalid credentials.
+const RC_INVALID_CREDENTIALS: u32 = 49;
+
+#[derive(Debug, Clone)]
+pub struct LdapConfig {
+    /// The server URL, ldaps://host:636 or ldap://host:389.
+    pub url: String,
+    /// Upgrade an ldap:// connection to TLS with StartTLS.
+    pub starttls: bool,
+    pub no_check_certificate: bool,
+    /// The account to search for users as, the search is anonymous
+    /// if not set.
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    /// Where to search for users.
+    pub base_dn: String,
+    /// The search filter for users, with {username} replaced by the escaped
+    /// username.
+    pub user_filter: String,
+    /// The attribute of the user entry holding the DNs of their groups.
+    pub group_attribute: String,
+    /// Group DNs and the role they grant, the highest role wins.
+    pub group_roles: Vec<(String, Role)>,
+    /// The role of users not in any of the mapped groups, such users are
+    /// refused if not set.
+    pub default_role: Option<Role>,
+    pub timeout: Duration,
+}
+
+impl LdapConfig {
+    pub fn new(url: &str, base_dn: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            starttls: false,
+            no_check_certificate: false,
+            bind_dn: None,
+            bind_password: None,
+            base_dn: base_dn.to_string(),
+            user_filter: "(uid={username})".to_string(),
+            group_attribute: "memberOf".to_string(),
+            group_roles: Vec::new(),
+            default_role: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    fn filter(&self, username: &str) -> String {
+        self.user_filter
+            .replace("{username}", &ldap3::ldap_escape(username))
+    }
+
+    /// Map the groups of a user to a role.
+    fn role(&self, groups: &[String]) -> Option<Role> {
+        self.group_roles
+            .iter()
+            .filter(|(group, _)| groups.iter().any(|g| g.eq_ignore_ascii_case(group)))
+            .map(|(_, role)| *role)
+            .max()
+            .or(self.default_role)
+    }
+}
+
+/// The outcome of a login against the directory.
+#[derive(Debug, PartialEq)]
+pub enum LdapLogin {
+    /// The username was not found in the directory.
+    UnknownUser,
+    /// The directory rejected the password.
+    BadPassword,
+    /// The user authenticated, with the role mapped from their groups, if any.
+    Authenticated(Option<Role>),
+}
+
+pub struct LdapAuthenticator {
+    pub config: LdapConfig,
+}
+
+impl LdapAuthenticator {
+    pub fn new(config: LdapConfig) -> Self {
+        Self { config }
+    }
+
+    /// Find the user in the directory, then bind as them to verify the
+    /// password.
+    pub async fn login(&self, username: &str, password: &str) -> Result<LdapLogin> {
+        // A simple bind with an empty password is an unauthenticated bind
+        // that most servers allow, so it must never be taken as a login.
+        if username.is_empty() || password.is_empty() {
+            return Ok(LdapLogin::BadPassword);
+        }
+        let config = &self.config;
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(config.timeout)
+            .set_starttls(config.starttls)
+            .set_no_tls_verify(config.no_check_certificate);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &config.url).await?;
+        ldap3::drive!(conn);
+
+        if let Some(bind_dn) = &config.bind_dn {
+            let password = config.bind_password.as_deref().unwrap_or("");
+            ldap.with_timeout(config.timeout)
+                .simple_bind(bind_dn, password)
+                .await?
+                .success()
+                .map_err(|err| anyhow!("bind as {} failed: {}", bind_dn, err))?;
+        }
+
+        let (entries, _) = ldap
+            .with_timeout(config.timeout)
+            .search(
+                &config.base_dn,
+                Scope::Subtree,
+                &config.filter(username),
+                vec![config.group_attribute.as_str()],
+            )
+            .await?
+            .success()?;
+        let mut entries: Vec<SearchEntry> = entries
+            .into_iter()
+            .filter(|entry| !entry.is_ref())
+            .map(SearchEntry::construct)
+            .collect();
+        let entry = match entries.len() {
+            0 => {
+                let _ = ldap.unbind().await;
+                return Ok(LdapLogin::UnknownUser);
+            }
+            1 => entries.remove(0),
+            n => {
+                let _ = ldap.unbind().await;
+                bail!("user filter matched {} entries for {}", n, username);
+            }
+        };
+
+        let result = ldap
+            .with_timeout(config.timeout)
+            .simple_bind(&entry.dn, password)
+            .await?;
+        let _ = ldap.unbind().await;
+        match result.rc {
+            0 => {}
+            RC_INVALID_CREDENTIALS => return Ok(LdapLogin::BadPassword),
+            _ => {
+                result.success()?;
+            }
+        }
+
+        let groups = entry
+            .attrs
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(&config.group_attribute))
+            .flat_map(|(_, values)| values.iter().cloned())
+            .collect::<Vec<String>>();
+        debug!(
+            "LDAP user {} has DN {} and groups {:?}",
+            username, entry.dn, groups
+        );
+        Ok(LdapLogin::Authenticated(config.role(&groups)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let config = LdapConfig::new("ldap://localhost", "dc=example,dc=com");
+        assert_eq!(config.filter("bob"), "(uid=bob)");
+        assert_eq!(config.filter("*)(uid=*"), "(uid=\\2a\\29\\28uid=\\2a)");
+    }
+
+    #[test]
+    fn test_role() {
+        let mut config = LdapConfig::new("ldap://localhost", "dc=example,dc=com");
+        config.group_roles = vec![
+            (
+                "cn=soc,ou=groups,dc=example,dc=com".to_string(),
+                Role::Analyst,
+            ),
+            (
+                "cn=admins,ou=groups,dc=example,dc=com".to_string(),
+                Role::Admin,
+            ),
+        ];
+        assert_eq!(config.role(&[]), None);
+        assert_eq!(
+            config.role(&["CN=SOC,OU=Groups,DC=example,DC=com".to_string()]),
+            Some(Role::Analyst)
+        );
+        assert_eq!(
+            config.role(&[
+                "cn=admins,ou=groups,dc=example,dc=com".to_string(),
+                "cn=soc,ou=groups,dc=example,dc=com".to_string(),
+            ]),
+            Some(Role::Admin)
+        );
+        config.default_role = Some(Role::Viewer);
+        assert_eq!(
+            config.role(&["cn=other,dc=example,dc=com".to_string()]),
+            Some(Role::Viewer)
+        );
+    }
+
+    #[test]
+    fn test_provision_ldap_user() {
+        let repo = crate::sqlite::configrepo::ConfigRepo::new(None).unwrap();
+        let user = repo.provision_ldap_user("bob", "viewer").unwrap();
+        assert_eq!(user.role, "viewer");
+
+        // The role follows the groups, but the uuid stays the same.
+        let updated = repo.provision_ldap_user("bob", "analyst").unwrap();
+        assert_eq!(updated.uuid, user.uuid);
+        assert_eq!(repo.get_user_by_name("bob").unwrap().role, "analyst");
+
+        // Local users with a password are not taken over.
+        repo.add_user("alice", "password").unwrap();
+        assert!(repo.provision_ldap_user("alice", "viewer").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_empty_password() {
+        // Never reaches the server, which doesn't exist.
+        let ldap = LdapAuthenticator::new(LdapConfig::new("ldap://127.0.0.1:1", "dc=example"));
+        assert_eq!(ldap.login("bob", "").await.unwrap(), LdapLogin::BadPassword);
+    }
+}
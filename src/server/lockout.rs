@@ -0,0 +1,179 @@
+// Copyright (C) 2022 Jason Ish
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Tracking of failed logins to lock out users after repeated failures.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of consecutive failed logins before a username is locked out.
+const MAX_FAILURES: u32 = 5;
+
+/// How long a username is locked out for, also how long failures are remembered.
+const LOCKOUT_PERIOD: Duration = Duration::from_secs(300);
+
+/// Maximum number of usernames to track failures for, so failed logins for
+/// many different usernames can't grow the map without bound.
+const MAX_ENTRIES: usize = 10000;
+
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+pub struct LoginLockout {
+    max_failures: u32,
+    period: Duration,
+    max_entries: usize,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl Default for LoginLockout {
+    fn default() -> Self {
+        Self::new(MAX_FAILURES, LOCKOUT_PERIOD)
+    }
+}
+
+impl LoginLockout {
+    pub fn new(max_failures: u32, period: Duration) -> Self {
+        Self {
+            max_failures,
+            period,
+            max_entries: MAX_ENTRIES,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check if the username is currently locked out.
+    pub fn is_locked(&self, username: &str) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        if let Some(entry) = failures.get(username) {
+            if entry.last.elapsed() > self.period {
+                failures.remove(username);
+                return false;
+            }
+            return entry.count >= self.max_failures;
+        }
+        false
+    }
+
+    /// Record a failed login, returning true if the username is now locked out.
+    pub fn failure(&self, username: &str) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        if !failures.contains_key(username) {
+            failures.retain(|_, entry| entry.last.elapsed() <= self.period);
+            if failures.len() >= self.max_entries {
+                // Make room by forgetting the failures seen the longest ago,
+                // but never those of a locked out username, else an attacker
+                // could lift a lockout by failing logins for other usernames.
+                let oldest = failures
+                    .iter()
+                    .filter(|(_, entry)| entry.count < self.max_failures)
+                    .min_by_key(|(_, entry)| entry.last)
+                    .map(|(username, _)| username.clone());
+                match oldest {
+                    Some(oldest) => {
+                        failures.remove(&oldest);
+                    }
+                    None => return false,
+                }
+            }
+        }
+        let entry = failures.entry(username.to_string()).or_insert(Failures {
+            count: 0,
+            last: Instant::now(),
+        });
+        if entry.last.elapsed() > self.period {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = Instant::now();
+        entry.count >= self.max_failures
+    }
+
+    /// Clear the failures for a username after a successful login.
+    pub fn success(&self, username: &str) {
+        self.failures.lock().unwrap().remove(username);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lockout() {
+        let lockout = LoginLockout::new(3, Duration::from_secs(60));
+        assert!(!lockout.is_locked("user"));
+        assert!(!lockout.failure("user"));
+        assert!(!lockout.failure("user"));
+        assert!(!lockout.is_locked("user"));
+        assert!(lockout.failure("user"));
+        assert!(lockout.is_locked("user"));
+        assert!(!lockout.is_locked("other"));
+
+        lockout.success("user");
+        assert!(!lockout.is_locked("user"));
+    }
+
+    #[test]
+    fn test_lockout_expires() {
+        let lockout = LoginLockout::new(1, Duration::from_millis(10));
+        assert!(lockout.failure("user"));
+        assert!(lockout.is_locked("user"));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!lockout.is_locked("user"));
+    }
+
+    #[test]
+    fn test_lockout_evicts() {
+        let mut lockout = LoginLockout::new(1, Duration::from_millis(10));
+        lockout.max_entries = 2;
+        lockout.failure("expired");
+        std::thread::sleep(Duration::from_millis(20));
+        lockout.failure("one");
+        assert_eq!(lockout.failures.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_lockout_evicts_unlocked_only() {
+        let mut lockout = LoginLockout::new(2, Duration::from_secs(60));
+        lockout.max_entries = 2;
+        lockout.failure("locked");
+        assert!(lockout.failure("locked"));
+        lockout.failure("one");
+        lockout.failure("two");
+        {
+            let failures = lockout.failures.lock().unwrap();
+            assert_eq!(failures.len(), 2);
+            assert!(!failures.contains_key("one"));
+        }
+        assert!(lockout.failure("two"));
+
+        // With every entry locked out, new usernames are not tracked.
+        assert!(!lockout.failure("three"));
+        assert!(!lockout.failure("three"));
+        assert!(!lockout.is_locked("three"));
+        assert!(lockout.is_locked("locked"));
+        assert!(lockout.is_locked("two"));
+    }
+}
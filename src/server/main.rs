@@ -3,8 +3,10 @@
 // Copyright (C) 2020-2022 Jason Ish
 
 use crate::prelude::*;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
@@ -24,17 +26,24 @@ use tracing::Level;
 use crate::bookmark;
 use crate::datastore::Datastore;
 use crate::elastic;
-use crate::eve::filters::{AddRuleFilter, EveBoxMetadataFilter};
+use crate::eve::filters::AddRuleFilter;
 use crate::eve::processor::Processor;
 use crate::eve::EveReader;
-use crate::server::session::Session;
-use crate::server::{api, AuthenticationType};
+use crate::server::agentauth;
+use crate::server::proxyauth;
+use crate::server::session::{Role, Session};
+use crate::server::{api, metrics, AuthenticationType};
+use crate::shutdown::CancellationToken;
 use crate::sqlite;
-use crate::sqlite::configrepo::ConfigRepo;
+use crate::sqlite::configrepo::{ConfigRepo, ROLE_AGENT};
 
 use super::{ServerConfig, ServerContext};
 use crate::elastic::Client;
 
+const DEFAULT_SESSION_IDLE_TIMEOUT: &str = "1d";
+const DEFAULT_SESSION_MAX_AGE: &str = "7d";
+const INPUT_SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
 fn load_event_services(filename: &str) -> anyhow::Result<serde_json::Value> {
     let finput = std::fs::File::open(filename)?;
     let yaml_value: serde_yaml::Value = serde_yaml::from_reader(finput)?;
@@ -80,6 +89,8 @@ pub async fn main(args: &clap::ArgMatches) -> Result<()> {
         || config.get_bool("no-check-certificate")?;
     server_config.http_request_logging = config.get_bool("http.request-logging")?;
     server_config.http_reverse_proxy = config.get_bool("http.reverse-proxy")?;
+    server_config.metrics_enabled = !config.get_bool("metrics.disabled")?;
+    server_config.metrics_anonymous = config.get_bool("metrics.anonymous")?;
 
     debug!(
         "Certificate checks disabled: {}",
@@ -92,6 +103,8 @@ pub async fn main(args: &clap::ArgMatches) -> Result<()> {
             server_config.authentication_type = match auth_type.as_ref() {
                 "username" => AuthenticationType::Username,
                 "usernamepassword" => AuthenticationType::UsernamePassword,
+                "reverse-proxy" => AuthenticationType::ReverseProxy,
+                "oidc" => AuthenticationType::Oidc,
                 _ => {
                     return Err(anyhow!("Bad authentication type: {}", auth_type));
                 }
@@ -99,6 +112,33 @@ pub async fn main(args: &clap::ArgMatches) -> Result<()> {
         }
     }
 
+    if server_config.authentication_type == AuthenticationType::ReverseProxy {
+        configure_reverse_proxy_auth(&config, &mut server_config.reverse_proxy_auth)?;
+        if !server_config.http_reverse_proxy {
+            warn!("Reverse proxy authentication enabled without http.reverse-proxy, client addresses will be those of the proxy");
+        }
+    }
+    if server_config.authentication_type == AuthenticationType::Oidc {
+        server_config.oidc = Some(configure_oidc(&config)?);
+    }
+    if server_config.authentication_type == AuthenticationType::UsernamePassword
+        && config.get_bool("authentication.ldap.enabled")?
+    {
+        server_config.ldap = Some(configure_ldap(&config)?);
+    }
+    server_config.agent_authentication_required =
+        config.get_bool("authentication.agent.required")?;
+    server_config.session_idle_timeout = parse_session_timeout(
+        &config,
+        "authentication.session.idle-timeout",
+        DEFAULT_SESSION_IDLE_TIMEOUT,
+    )?;
+    server_config.session_max_age = parse_session_timeout(
+        &config,
+        "authentication.session.max-age",
+        DEFAULT_SESSION_MAX_AGE,
+    )?;
+
     // Do we need a data-directory? If so, make sure its set.
     let data_directory_required = server_config.datastore == "sqlite";
 
@@ -107,139 +147,427 @@ pub async fn main(args: &clap::ArgMatches) -> Result<()> {
         std::process::exit(1);
     }
 
-    tokio::spawn(async move {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("Failed to register CTRL-C handler");
-        std::process::exit(0);
-    });
+    let shutdown = CancellationToken::new();
+    crate::shutdown::cancel_on_signal(
+        shutdown.clone(),
+        crate::shutdown::timeout_from_config(&config)?,
+    );
 
-    let datastore = configure_datastore(&server_config).await?;
+    let datastore = configure_datastore(&server_config, &shutdown).await?;
     let mut context = build_context(server_config.clone(), datastore).await?;
+    context.shutdown = shutdown.clone();
+    let (reload_tx, reload_rx) = tokio::sync::mpsc::channel(1);
+    context.reload = Some(reload_tx);
+    let context = Arc::new(context);
 
-    if let Some(filename) = config_filename {
-        match load_event_services(filename) {
-            Err(err) => {
-                error!("Failed to load event-services: {}", err);
+    configure(&config, config_filename, &context)?;
+    start_reloader(
+        args.clone(),
+        config_filename.map(String::from),
+        context.clone(),
+        reload_rx,
+    )?;
+
+    // Look for new files matching the inputs, as with the agent.
+    let scan_context = context.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(INPUT_SCAN_INTERVAL);
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = scan_context.shutdown.cancelled() => break,
             }
-            Ok(event_services) => {
-                context.event_services = Some(event_services);
+            if let Err(err) = start_inputs(&scan_context) {
+                error!("Failed to start inputs: {:?}", err);
             }
         }
-    }
+    });
 
-    let input_enabled = {
-        if config.args.occurrences_of("input.filename") > 0 {
-            true
-        } else {
-            config.get_bool("input.enabled")?
+    let reaper_context = context.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = reaper_context.shutdown.cancelled() => break,
+            }
+            match reaper_context.session_store.reap() {
+                Ok(n) if n > 0 => debug!("Removed {} expired sessions", n),
+                Ok(_) => {}
+                Err(err) => error!("Failed to remove expired sessions: {}", err),
+            }
         }
-    };
+    });
 
-    // This needs some cleanup. We load the input file names here, but configure
-    // it later down.  Also, the filters (rules) are unlikely required if we
-    // don't have an input enabled.
-    let input_filenames = if input_enabled {
-        let input_filename: Option<String> = config.get("input.filename")?;
-        let mut input_filenames = Vec::new();
-        if let Some(input_filename) = &input_filename {
-            for path in crate::path::expand(input_filename)? {
-                let path = path.display().to_string();
-                input_filenames.push(path);
-            }
+    info!(
+        "Starting server on {}:{}, tls={}",
+        server_config.host, server_config.port, server_config.tls_enabled
+    );
+    if server_config.tls_enabled {
+        debug!("TLS key filename: {:?}", server_config.tls_key_filename);
+        debug!("TLS cert filename: {:?}", server_config.tls_cert_filename);
+        if let Err(err) = run_axum_server_with_tls(&server_config, context.clone()).await {
+            error!("Failed to start TLS HTTP service: {:?}", err);
+            std::process::exit(1);
         }
-        input_filenames
-    } else {
-        Vec::new()
-    };
+    } else if let Err(err) = run_axum_server(&server_config, context.clone()).await {
+        error!("Failed to start HTTP service: {:?}", err);
+        std::process::exit(1);
+    }
 
-    let mut shared_filters = Vec::new();
+    // The server only returns on shutdown, once connections are drained.
+    context.inputs.join().await;
+    info!("Shutdown complete");
 
-    match config.get_config_value::<Vec<String>>("input.rules") {
-        Ok(Some(rules)) => {
-            let rulemap = crate::rules::load_rules(&rules);
-            let rulemap = Arc::new(rulemap);
-            shared_filters.push(crate::eve::filters::EveFilter::AddRuleFilter(
-                AddRuleFilter {
-                    map: rulemap.clone(),
-                },
-            ));
-            crate::rules::watch_rules(rulemap);
+    // Exit with an error if shutdown was due to an unsupported version.
+    if let Datastore::Elastic(eventstore) = &context.datastore {
+        let version = eventstore.client.version.read().unwrap().clone();
+        if let Some(version) = version {
+            check_version(&version)?;
         }
-        Ok(None) => {}
+    }
+    Ok(())
+}
+
+/// Configure the parts of the server that can be changed without a restart:
+/// the inputs, rules, filters and event services. Everything is loaded
+/// before any change is made, so on error the current configuration is
+/// left in place.
+fn configure(
+    config: &crate::config::Config,
+    config_filename: Option<&str>,
+    context: &ServerContext,
+) -> Result<()> {
+    let inputs = super::inputs::from_config(config, &context.shutdown)?;
+
+    let mut shared_filters = Vec::new();
+
+    let rules = match config.get_config_value::<Vec<String>>("input.rules") {
+        Ok(Some(rules)) => Some(Arc::new(crate::rules::load_rules(&rules))),
+        Ok(None) => None,
         Err(err) => {
             error!("Failed to read input.rules configuration: {}", err);
+            None
         }
+    };
+    let rule_filter = rules.as_ref().map(|rulemap| {
+        crate::eve::filters::EveFilter::AddRuleFilter(AddRuleFilter {
+            map: rulemap.clone(),
+        })
+    });
+    if let Some(filter) = &rule_filter {
+        shared_filters.push(filter.clone());
+    }
+
+    let geoip = if !inputs.is_empty() || config.get_bool("submit.geoip")? {
+        configure_geoip(config)?
+    } else {
+        None
+    };
+    if !inputs.is_empty() {
+        if let Some(geoip) = &geoip {
+            shared_filters.push(crate::eve::filters::EveFilter::GeoIP(geoip.clone()));
+        }
+    }
+
+    let user_filters = crate::eve::userfilters::from_config(config)?;
+    let user_filters = if user_filters.is_empty() {
+        None
+    } else {
+        info!("Loaded {} event filters", user_filters.len());
+        Some(crate::eve::filters::EveFilter::UserFilters(Arc::new(
+            user_filters,
+        )))
+    };
+    if let Some(filter) = &user_filters {
+        shared_filters.push(filter.clone());
     }
 
     shared_filters.push(crate::eve::filters::EveFilter::AutoArchiveFilter(
         crate::eve::filters::AutoArchiveFilter::default(),
     ));
 
-    let shared_filters = Arc::new(shared_filters);
+    // The filters for events submitted by agents, each enabled separately
+    // as agents may already be doing the same.
+    let mut submit_filters = Vec::new();
+    if config.get_bool("submit.rules")? {
+        match &rule_filter {
+            Some(filter) => submit_filters.push(filter.clone()),
+            None => warn!("submit.rules enabled, but no rules configured in input.rules"),
+        }
+    }
+    if config.get_bool("submit.geoip")? {
+        if let Some(geoip) = &geoip {
+            submit_filters.push(crate::eve::filters::EveFilter::GeoIP(geoip.clone()));
+        }
+    }
+    if config.get_bool("submit.filters")? {
+        if let Some(filter) = &user_filters {
+            submit_filters.push(filter.clone());
+        }
+    }
+    if config.get_bool("submit.auto-archive")? {
+        submit_filters.push(crate::eve::filters::EveFilter::AutoArchiveFilter(
+            crate::eve::filters::AutoArchiveFilter::default(),
+        ));
+    }
+
+    let event_services = match config_filename {
+        Some(filename) => match load_event_services(filename) {
+            Err(err) => {
+                error!("Failed to load event-services: {}", err);
+                None
+            }
+            Ok(event_services) => Some(event_services),
+        },
+        None => None,
+    };
+
+    if !inputs.is_empty() && context.datastore.get_importer().is_none() {
+        bail!("No importer implementation for this database");
+    }
+
+    // Everything is loaded, now apply the new configuration.
+    if let Some(rulemap) = &rules {
+        crate::rules::watch_rules(rulemap.clone(), context.shutdown.clone());
+    }
+    *context.rules.write().unwrap() = rules;
+    *context.geoip.write().unwrap() = geoip;
+    *context.event_services.write().unwrap() = event_services;
+    context.submit_filters.swap(submit_filters);
+    context.inputs.filters.swap(shared_filters);
+    context.inputs.configure(inputs);
+    start_inputs(context)
+}
 
-    for input_filename in &input_filenames {
-        let end = config.get_bool("end")?;
-        let bookmark_directory: Option<String> = config.get("input.bookmark-directory")?;
+/// Start readers for new files matching the configured inputs.
+fn start_inputs(context: &ServerContext) -> Result<()> {
+    for (input_filename, input) in context.inputs.scan()? {
+        let importer = context
+            .datastore
+            .get_importer()
+            .ok_or_else(|| anyhow!("No importer implementation for this database"))?;
         let bookmark_filename = get_bookmark_filename(
-            input_filename,
-            bookmark_directory.as_deref(),
-            server_config.data_directory.as_deref(),
+            &input_filename,
+            input.bookmark_directory.as_deref(),
+            context.config.data_directory.as_deref(),
         );
         info!(
             "Using bookmark filename {:?} for input {:?}",
             bookmark_filename, input_filename
         );
 
-        let importer = if let Some(importer) = context.datastore.get_importer() {
-            importer
-        } else {
-            error!("No importer implementation for this database.");
-            std::process::exit(1);
-        };
-
-        let filters = vec![
-            crate::eve::filters::EveFilter::Filters(shared_filters.clone()),
-            EveBoxMetadataFilter {
-                filename: Some(input_filename.clone()),
-            }
-            .into(),
-        ];
-
-        let reader = EveReader::new(input_filename);
-        let mut processor = Processor::new(reader, importer.clone());
+        let reader = EveReader::new(&input_filename);
+        let mut processor = Processor::new(reader, importer);
         processor.report_interval = Duration::from_secs(60);
-        processor.filters = Arc::new(filters);
-        processor.end = end;
+        processor.end = input.end;
         processor.bookmark_filename = bookmark_filename;
-        info!("Starting reader for {}", input_filename);
-        tokio::spawn(async move {
-            processor.run().await;
-        });
+        processor.broadcaster = Some(context.broadcaster.clone());
+        processor.shutdown = context.shutdown.child_token();
+        context.inputs.start(processor, input.filters);
     }
+    Ok(())
+}
 
-    let context = Arc::new(context);
+/// Reload the configuration on SIGHUP, or when requested through the API.
+fn start_reloader(
+    args: clap::ArgMatches,
+    config_filename: Option<String>,
+    context: Arc<ServerContext>,
+    mut requests: tokio::sync::mpsc::Receiver<super::ReloadRequest>,
+) -> Result<()> {
+    let mut hangup = crate::reload::Hangup::new()?;
+    tokio::spawn(async move {
+        loop {
+            let reply = tokio::select! {
+                _ = hangup.recv() => {
+                    info!("Received SIGHUP, reloading configuration");
+                    None
+                }
+                request = requests.recv() => match request {
+                    Some(reply) => {
+                        info!("Reloading configuration");
+                        Some(reply)
+                    }
+                    None => break,
+                },
+                _ = context.shutdown.cancelled() => break,
+            };
+            let result = crate::config::Config::new(&args, config_filename.as_deref())
+                .and_then(|config| configure(&config, config_filename.as_deref(), &context));
+            match &result {
+                Ok(()) => info!("Configuration reloaded"),
+                Err(err) => error!(
+                    "Failed to reload configuration, keeping current configuration: {:?}",
+                    err
+                ),
+            }
+            if let Some(reply) = reply {
+                let _ = reply.send(result);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Open the GeoIP database, and ASN database if configured, unless disabled.
+fn configure_geoip(config: &crate::config::Config) -> anyhow::Result<Option<crate::geoip::GeoIP>> {
+    if config.get_bool("geoip.disabled")? {
+        debug!("GeoIP disabled");
+        return Ok(None);
+    }
+    let filename: Option<String> = config.get("geoip.database")?;
+    let geoip = match crate::geoip::GeoIP::open(filename) {
+        Ok(geoip) => geoip,
+        Err(err) => {
+            warn!("Failed to open GeoIP database: {}", err);
+            return Ok(None);
+        }
+    };
+    if let Some(filename) = config.get::<String>("geoip.asn-database")? {
+        if let Err(err) = geoip.open_asn(&filename) {
+            warn!("Failed to open GeoIP ASN database {}: {}", filename, err);
+        }
+    }
+    Ok(Some(geoip))
+}
 
+fn configure_reverse_proxy_auth(
+    config: &crate::config::Config,
+    auth: &mut super::ReverseProxyAuthConfig,
+) -> anyhow::Result<()> {
+    if let Some(header) = config.get::<String>("authentication.reverse-proxy.header")? {
+        auth.header = header.to_lowercase();
+    }
+    if let Some(proxies) =
+        config.get_value::<Vec<String>>("authentication.reverse-proxy.trusted-proxies")?
+    {
+        auth.trusted_proxies = proxies
+            .iter()
+            .map(|proxy| proxy.parse())
+            .collect::<anyhow::Result<_>>()
+            .map_err(|err| anyhow!("Bad authentication.reverse-proxy.trusted-proxies: {}", err))?;
+    }
+    if let Some(role) = config.get::<String>("authentication.reverse-proxy.role")? {
+        auth.role = match Role::from_str(&role)? {
+            Role::Agent => bail!("Agent role not allowed for reverse proxy users"),
+            role => role,
+        };
+    }
     info!(
-        "Starting server on {}:{}, tls={}",
-        server_config.host, server_config.port, server_config.tls_enabled
+        "Reverse proxy authentication with header {} from trusted proxies {:?}",
+        &auth.header,
+        auth.trusted_proxies
+            .iter()
+            .map(|cidr| cidr.to_string())
+            .collect::<Vec<_>>()
     );
-    if server_config.tls_enabled {
-        debug!("TLS key filename: {:?}", server_config.tls_key_filename);
-        debug!("TLS cert filename: {:?}", server_config.tls_cert_filename);
-        if let Err(err) = run_axum_server_with_tls(&server_config, context).await {
-            error!("Failed to start TLS HTTP service: {:?}", err);
+    Ok(())
+}
+
+fn configure_oidc(config: &crate::config::Config) -> anyhow::Result<super::oidc::OidcConfig> {
+    let required = |name: &str| -> anyhow::Result<String> {
+        config
+            .get::<String>(&format!("authentication.oidc.{}", name))?
+            .ok_or_else(|| anyhow!("authentication.oidc.{} is required", name))
+    };
+    let mut oidc = super::oidc::OidcConfig::new(
+        &required("issuer")?,
+        &required("client-id")?,
+        &required("redirect-url")?,
+    );
+    oidc.client_secret = config.get("authentication.oidc.client-secret")?;
+    if let Some(scopes) = config.get("authentication.oidc.scopes")? {
+        oidc.scopes = scopes;
+    }
+    if let Some(claim) = config.get("authentication.oidc.username-claim")? {
+        oidc.username_claim = claim;
+    }
+    if let Some(role) = config.get::<String>("authentication.oidc.role")? {
+        oidc.role = match Role::from_str(&role)? {
+            Role::Agent => bail!("Agent role not allowed for OpenID Connect users"),
+            role => role,
+        };
+    }
+    info!("OpenID Connect login with issuer {}", &oidc.issuer);
+    Ok(oidc)
+}
+
+fn configure_ldap(config: &crate::config::Config) -> anyhow::Result<super::ldap::LdapConfig> {
+    let required = |name: &str| -> anyhow::Result<String> {
+        config
+            .get::<String>(&format!("authentication.ldap.{}", name))?
+            .ok_or_else(|| anyhow!("authentication.ldap.{} is required", name))
+    };
+    let mut ldap = super::ldap::LdapConfig::new(&required("url")?, &required("base-dn")?);
+    ldap.starttls = config.get_bool("authentication.ldap.starttls")?;
+    ldap.no_check_certificate = config.get_bool("authentication.ldap.disable-certificate-check")?;
+    ldap.bind_dn = config.get("authentication.ldap.bind-dn")?;
+    ldap.bind_password = config.get("authentication.ldap.bind-password")?;
+    if let Some(filter) = config.get::<String>("authentication.ldap.user-filter")? {
+        if !filter.contains("{username}") {
+            bail!("authentication.ldap.user-filter must contain {{username}}");
         }
-    } else if let Err(err) = run_axum_server(&server_config, context).await {
-        error!("Failed to start HTTP service: {:?}", err);
-        std::process::exit(1);
+        ldap.user_filter = filter;
     }
-    Ok(())
+    if let Some(attribute) = config.get("authentication.ldap.group-attribute")? {
+        ldap.group_attribute = attribute;
+    }
+    if let Some(roles) =
+        config.get_value::<HashMap<String, Vec<String>>>("authentication.ldap.roles")?
+    {
+        for (role, groups) in roles {
+            let role = match Role::from_str(&role)? {
+                Role::Agent => bail!("Agent role not allowed for LDAP users"),
+                role => role,
+            };
+            for group in groups {
+                ldap.group_roles.push((group, role));
+            }
+        }
+    }
+    if let Some(role) = config.get::<String>("authentication.ldap.default-role")? {
+        ldap.default_role = match Role::from_str(&role)? {
+            Role::Agent => bail!("Agent role not allowed for LDAP users"),
+            role => Some(role),
+        };
+    }
+    if ldap.group_roles.is_empty() && ldap.default_role.is_none() {
+        bail!("authentication.ldap requires roles or a default-role");
+    }
+    if !ldap.starttls && !ldap.url.starts_with("ldaps://") {
+        warn!("LDAP passwords will be sent in the clear, use ldaps:// or starttls");
+    }
+    info!("LDAP authentication with server {}", &ldap.url);
+    Ok(ldap)
+}
+
+/// Parse a session timeout such as "8h" or "7d". A value of "0" disables the timeout.
+fn parse_session_timeout(
+    config: &crate::config::Config,
+    key: &str,
+    default: &str,
+) -> anyhow::Result<Option<Duration>> {
+    let value = match config.get_value::<serde_yaml::Value>(key)? {
+        None => default.to_string(),
+        Some(serde_yaml::Value::String(value)) => value,
+        Some(serde_yaml::Value::Number(value)) => value.to_string(),
+        Some(value) => return Err(anyhow!("Bad value for {}: {:?}", key, value)),
+    };
+    if value == "0" {
+        return Ok(None);
+    }
+    let duration = humantime::parse_duration(&value)
+        .map_err(|err| anyhow!("Bad value for {}: {}: {}", key, value, err))?;
+    Ok(Some(duration))
 }
 
 pub(crate) fn build_axum_service(
     context: Arc<ServerContext>,
 ) -> IntoMakeServiceWithConnectInfo<Router, SocketAddr> {
+    use axum::extract::extractor_middleware;
+    use axum::handler::Handler;
     use axum::routing::{get, post};
     use tower_http::trace::TraceLayer;
 
@@ -254,32 +582,63 @@ pub(crate) fn build_axum_service(
         .on_response(DefaultOnResponse::new().level(Level::INFO))
         .on_request(());
 
+    // Routes that modify events require at least the analyst role. Routes
+    // without a layer are available to any authenticated user.
+    let analyst = extractor_middleware::<RequireAnalyst>;
+    let admin = extractor_middleware::<RequireAdmin>;
+
     let app = axum::Router::new()
         .route(
             "/api/1/login",
             post(crate::server::api::login::post).options(api::login::options_new),
         )
+        .route("/api/1/login/oidc", get(api::login::oidc_login))
+        .route("/api/1/login/oidc/callback", get(api::login::oidc_callback))
         .route("/api/1/logout", post(crate::server::api::login::logout_new))
         .route("/api/1/config", get(api::config))
         .route("/api/1/version", get(api::get_version))
+        .route("/api/1/health", get(api::health::health))
+        .route("/api/1/ready", get(api::health::ready))
         .route("/api/1/user", get(api::get_user))
         .route("/api/1/alerts", get(api::alert_query))
         .route("/api/1/event-query", get(api::event_query))
         .route("/api/1/event/:id", get(api::get_event_by_id))
-        .route("/api/1/alert-group/star", post(api::alert_group_star))
-        .route("/api/1/alert-group/unstar", post(api::alert_group_unstar))
-        .route("/api/1/alert-group/archive", post(api::alert_group_archive))
-        .route("/api/1/alert-group/comment", post(api::alert_group_comment))
-        .route("/api/1/event/:id/archive", post(api::archive_event_by_id))
-        .route("/api/1/event/:id/escalate", post(api::escalate_event_by_id))
-        .route("/api/1/event/:id/comment", post(api::comment_by_event_id))
+        .route(
+            "/api/1/alert-group/star",
+            post(api::alert_group_star.layer(analyst())),
+        )
+        .route(
+            "/api/1/alert-group/unstar",
+            post(api::alert_group_unstar.layer(analyst())),
+        )
+        .route(
+            "/api/1/alert-group/archive",
+            post(api::alert_group_archive.layer(analyst())),
+        )
+        .route(
+            "/api/1/alert-group/comment",
+            post(api::alert_group_comment.layer(analyst())),
+        )
+        .route(
+            "/api/1/event/:id/archive",
+            post(api::archive_event_by_id.layer(analyst())),
+        )
+        .route(
+            "/api/1/event/:id/escalate",
+            post(api::escalate_event_by_id.layer(analyst())),
+        )
+        .route(
+            "/api/1/event/:id/comment",
+            post(api::comment_by_event_id.layer(analyst())),
+        )
         .route(
             "/api/1/event/:id/de-escalate",
-            post(api::deescalate_event_by_id),
+            post(api::deescalate_event_by_id.layer(analyst())),
         )
         .route("/api/1/report/agg", get(api::agg))
         .route("/api/1/report/histogram", get(api::histogram))
-        .route("/api/1/query", post(api::query_elastic))
+        .route("/api/1/query", post(api::query_elastic.layer(admin())))
+        .route("/api/1/reload", post(api::reload.layer(admin())))
         .route("/api/1/flow/histogram", get(api::flow_histogram::handler))
         .route("/api/1/report/dhcp/:what", get(api::report_dhcp))
         .route("/api/1/eve2pcap", post(api::eve2pcap::handler))
@@ -290,6 +649,9 @@ pub(crate) fn build_axum_service(
         )
         .route("/api/1/stats/agg", get(api::stats::stats_agg))
         .route("/api/1/sensors", get(api::stats::get_sensor_names))
+        .route("/api/1/stream/alerts", get(api::stream::alerts))
+        .route("/metrics", get(metrics::handler))
+        .layer(metrics::HttpMetricsLayer)
         .layer(AddExtensionLayer::new(context.clone()))
         .layer(response_header_layer)
         .fallback(axum::routing::get(fallback_handler));
@@ -339,11 +701,25 @@ pub(crate) async fn run_axum_server_with_tls(
         )
     })?;
     axum_server::bind_rustls(addr, tls_config)
+        .handle(shutdown_handle(&context))
         .serve(service)
         .await?;
     Ok(())
 }
 
+/// A handle to gracefully shutdown the server when the context is shutdown.
+fn shutdown_handle(context: &ServerContext) -> axum_server::Handle {
+    let handle = axum_server::Handle::new();
+    let shutdown = context.shutdown.clone();
+    let server = handle.clone();
+    tokio::spawn(async move {
+        shutdown.cancelled().await;
+        info!("Waiting for HTTP connections to close");
+        server.graceful_shutdown(None);
+    });
+    handle
+}
+
 pub(crate) async fn run_axum_server(
     config: &ServerConfig,
     context: Arc<ServerContext>,
@@ -351,7 +727,10 @@ pub(crate) async fn run_axum_server(
     let port: u16 = config.port;
     let addr: SocketAddr = format!("{}:{}", config.host, port).parse()?;
     let service = build_axum_service(context.clone());
-    axum_server::bind(addr).serve(service).await?;
+    axum_server::bind(addr)
+        .handle(shutdown_handle(&context))
+        .serve(service)
+        .await?;
     Ok(())
 }
 
@@ -409,13 +788,15 @@ pub async fn build_context(
 
     let mut context = ServerContext::new(config, Arc::new(config_repo), datastore);
 
-    #[allow(clippy::single_match)]
     match context.datastore {
         Datastore::Elastic(_) => {
             context.features.comments = true;
             context.features.reporting = true;
         }
-        _ => {}
+        Datastore::SQLite(_) => {
+            context.features.comments = true;
+            context.features.reporting = true;
+        }
     }
 
     Ok(context)
@@ -438,7 +819,21 @@ async fn wait_for_version(client: &Client) -> elastic::client::Version {
     }
 }
 
-async fn configure_datastore(config: &ServerConfig) -> anyhow::Result<Datastore> {
+/// Check that the Elasticsearch version is supported.
+fn check_version(version: &elastic::client::Version) -> Result<()> {
+    if version.major < 6 {
+        bail!(
+            "Elasticsearch version {} is not supported, 6 or newer is required",
+            version.version
+        );
+    }
+    Ok(())
+}
+
+async fn configure_datastore(
+    config: &ServerConfig,
+    shutdown: &CancellationToken,
+) -> anyhow::Result<Datastore> {
     match config.datastore.as_ref() {
         "elasticsearch" => {
             let mut client = elastic::ClientBuilder::new(&config.elastic_url);
@@ -452,16 +847,24 @@ async fn configure_datastore(config: &ServerConfig) -> anyhow::Result<Datastore>
 
             let client = client.build();
 
-            let version = wait_for_version(&client).await;
-            if version.major < 6 {
-                return Err(anyhow!(
-                    "Elasticsearch versions less than 6 are not supported"
-                ));
-            }
-            info!(
-                "Found Elasticsearch version {} at {}",
-                version.version, &config.elastic_url
-            );
+            // Check the version in the background so the server can start,
+            // and report through /api/1/ready, while Elasticsearch is down.
+            // The client shares the cached version with the event store. An
+            // unsupported version shuts the server down.
+            let version_client = client.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                let version = wait_for_version(&version_client).await;
+                if let Err(err) = check_version(&version) {
+                    error!("{} at {}", err, &version_client.url);
+                    shutdown.cancel();
+                    return;
+                }
+                info!(
+                    "Found Elasticsearch version {} at {}",
+                    version.version, &version_client.url
+                );
+            });
 
             let index_pattern = if config.elastic_no_index_suffix {
                 config.elastic_index.clone()
@@ -507,10 +910,11 @@ async fn configure_datastore(config: &ServerConfig) -> anyhow::Result<Datastore>
                 if period > 0 {
                     info!("Setting data retention period to {} days", period);
                     let retention_config = sqlite::retention::RetentionConfig { days: period };
-                    let connection = connection;
-                    tokio::task::spawn_blocking(|| {
-                        sqlite::retention::retention_task(retention_config, connection);
-                    });
+                    tokio::spawn(sqlite::retention::retention_task(
+                        retention_config,
+                        connection,
+                        shutdown.clone(),
+                    ));
                 }
             }
 
@@ -563,10 +967,53 @@ impl FromRequest for SessionExtractor {
         if let Some(session_id) = session_id {
             let session = context.session_store.get(session_id);
             if let Some(session) = session {
+                // The proxy may have logged in a different user since the session was created.
+                if context.config.authentication_type == AuthenticationType::ReverseProxy {
+                    if let Some(username) = proxyauth::get_username(
+                        &context.config.reverse_proxy_auth,
+                        &remote_addr,
+                        headers,
+                    ) {
+                        if session.username.as_ref() != Some(&username) {
+                            warn!(
+                                "Session for user {} used by reverse proxy user {}, logging out",
+                                session.username(),
+                                &username
+                            );
+                            context.session_store.delete(session_id);
+                            return Err((StatusCode::UNAUTHORIZED, "authentication required"));
+                        }
+                    }
+                }
                 return Ok(SessionExtractor(session));
             }
         }
 
+        if let Some(token) = agentauth::parse_bearer_token(headers) {
+            match context.config_repo.get_user_by_token(token) {
+                Ok(Some(user)) if user.role != ROLE_AGENT => {
+                    let role = Role::from_str(&user.role).map_err(|err| {
+                        error!("Bad role for user {}: {}", &user.username, err);
+                        (StatusCode::INTERNAL_SERVER_ERROR, "internal server error")
+                    })?;
+                    let session = Session {
+                        session_id: None,
+                        username: Some(user.username),
+                        role,
+                    };
+                    return Ok(SessionExtractor(Arc::new(session)));
+                }
+                Ok(_) => {
+                    warn!("Request with invalid API token");
+                    return Err((StatusCode::UNAUTHORIZED, "invalid api token"));
+                }
+                Err(err) => {
+                    error!("Failed to lookup API token: {}", err);
+                    return Err((StatusCode::INTERNAL_SERVER_ERROR, "internal server error"));
+                }
+            }
+        }
+
         match context.config.authentication_type {
             AuthenticationType::Anonymous => {
                 return Ok(Self(Arc::new(Session::anonymous(remote_user))));
@@ -583,6 +1030,49 @@ impl FromRequest for SessionExtractor {
     }
 }
 
+/// Extractor middleware restricting a route to analysts and admins.
+pub(crate) struct RequireAnalyst;
+
+#[async_trait]
+impl FromRequest for RequireAnalyst {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<Self, Self::Rejection> {
+        require_role(req, Role::Analyst).await?;
+        Ok(Self)
+    }
+}
+
+/// Extractor middleware restricting a route to admins.
+pub(crate) struct RequireAdmin;
+
+#[async_trait]
+impl FromRequest for RequireAdmin {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<Self, Self::Rejection> {
+        require_role(req, Role::Admin).await?;
+        Ok(Self)
+    }
+}
+
+async fn require_role(
+    req: &mut RequestParts<Body>,
+    role: Role,
+) -> Result<(), (StatusCode, &'static str)> {
+    let SessionExtractor(session) = SessionExtractor::from_request(req).await?;
+    if session.role < role {
+        warn!(
+            "Permission denied for user {} with role {} to {}",
+            session.username(),
+            session.role,
+            req.uri()
+        );
+        return Err((StatusCode::FORBIDDEN, "permission denied"));
+    }
+    Ok(())
+}
+
 fn get_bookmark_filename(
     input_filename: &str,
     input_bookmark_dir: Option<&str>,
@@ -642,3 +1132,211 @@ fn test_writable<T: AsRef<Path>>(filename: T) -> anyhow::Result<()> {
         .open(filename)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{check_version, configure, configure_datastore};
+    use crate::datastore::Datastore;
+    use crate::server::testing;
+    use crate::server::ServerConfig;
+    use crate::shutdown::CancellationToken;
+    use crate::sqlite::configrepo::ConfigRepo;
+
+    #[tokio::test]
+    async fn test_configure_reload() {
+        let directory = testing::TempDir::new("reload");
+        let eve_a = directory.join("a.json").display().to_string();
+        let eve_b = directory.join("b.json").display().to_string();
+        std::fs::write(&eve_a, b"").unwrap();
+        std::fs::write(&eve_b, b"").unwrap();
+        let config_filename = directory.join("evebox.yaml").display().to_string();
+        let write_config = |input: &str, filters: &str| {
+            let config = format!(
+                "input:\n  enabled: true\n  filename: {}\n  bookmark-directory: {}\ngeoip:\n  disabled: true\n{}",
+                input,
+                directory.path().display(),
+                filters
+            );
+            std::fs::write(&config_filename, config).unwrap();
+        };
+        let args = testing::server_args();
+        let reload = |context| {
+            let config = crate::config::Config::new(&args, Some(&config_filename)).unwrap();
+            configure(&config, Some(&config_filename), context)
+        };
+
+        let context =
+            testing::build_context(ServerConfig::default(), ConfigRepo::new(None).unwrap());
+        write_config(&eve_a, "");
+        reload(&context).unwrap();
+        assert_eq!(context.inputs.filenames(), vec![eve_a.clone()]);
+        assert_eq!(context.inputs.filters.get().len(), 1);
+
+        // Replace the input and add a filter.
+        write_config(
+            &eve_b,
+            "filters:\n  - action: drop\n    match:\n      event_type: stats\n",
+        );
+        reload(&context).unwrap();
+        assert_eq!(context.inputs.filenames(), vec![eve_b.clone()]);
+        assert_eq!(context.inputs.filters.get().len(), 2);
+
+        // A bad configuration leaves the current configuration in place.
+        write_config(
+            &directory.join("*.json").display().to_string(),
+            "filters:\n  - action: bad\n",
+        );
+        assert!(reload(&context).is_err());
+        assert_eq!(context.inputs.filenames(), vec![eve_b.clone()]);
+        assert_eq!(context.inputs.filters.get().len(), 2);
+
+        // Add a list of inputs, a file matching more than one is only read
+        // once.
+        write_config(
+            &eve_a,
+            &format!(
+                "inputs:\n  - filename: {}\n  - filename: {}\n    end: true\n",
+                eve_b,
+                directory.join("*.json").display()
+            ),
+        );
+        reload(&context).unwrap();
+
+        // The earlier reader for eve_a must finish before it is read again,
+        // the next scan starts it.
+        tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            while context.inputs.filenames().len() < 2 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                super::start_inputs(&context).unwrap();
+            }
+        })
+        .await
+        .unwrap();
+        let mut filenames = context.inputs.filenames();
+        filenames.sort();
+        assert_eq!(filenames, vec![eve_a.clone(), eve_b.clone()]);
+        assert!(context.inputs.scan().unwrap().is_empty());
+
+        // New files matching a pattern are found on the next scan.
+        let eve_c = directory.join("c.json").display().to_string();
+        std::fs::write(&eve_c, b"").unwrap();
+        let found = context.inputs.scan().unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, eve_c);
+        assert!(found[0].1.end);
+
+        context.shutdown.cancel();
+        context.inputs.join().await;
+    }
+
+    #[tokio::test]
+    async fn test_configure_input_geoip() {
+        use crate::geoip::testing::{names, write_database, Value};
+
+        let directory = testing::TempDir::new("input-geoip");
+        let database = directory.join("GeoLite2-City.mmdb");
+        write_database(
+            &database,
+            "GeoLite2-City",
+            vec![(
+                "81.2.69.0/24",
+                Value::Map(vec![("city", Value::Map(vec![("names", names("London"))]))]),
+            )],
+        );
+        let eve = directory.join("eve.json");
+        std::fs::write(&eve, b"").unwrap();
+        let config_filename = directory.join("evebox.yaml").display().to_string();
+        let args = testing::server_args();
+
+        for disabled in [false, true] {
+            std::fs::write(
+                &config_filename,
+                format!(
+                    "input:\n  enabled: true\n  filename: {}\n  bookmark-directory: {}\ngeoip:\n  disabled: {}\n  database: {}\n",
+                    eve.display(),
+                    directory.path().display(),
+                    disabled,
+                    database.display()
+                ),
+            )
+            .unwrap();
+            let config = crate::config::Config::new(&args, Some(&config_filename)).unwrap();
+            let context =
+                testing::build_context(ServerConfig::default(), ConfigRepo::new(None).unwrap());
+            configure(&config, Some(&config_filename), &context).unwrap();
+            assert_eq!(context.geoip.read().unwrap().is_some(), !disabled);
+
+            let mut event = serde_json::json!({"src_ip": "81.2.69.160"});
+            assert!(context.inputs.filters.run(&mut event));
+            if disabled {
+                assert!(event.get("geoip_source").is_none());
+            } else {
+                assert_eq!(event["geoip_source"]["city_name"], "London");
+            }
+
+            context.shutdown.cancel();
+            context.inputs.join().await;
+        }
+    }
+
+    /// Start a mock Elasticsearch that reports the given version.
+    fn start_elastic(version: &'static str) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::get(move || async move {
+                axum::Json(serde_json::json!({"version": {"number": version}}))
+            }),
+        );
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_elastic_version() {
+        let timeout = std::time::Duration::from_secs(10);
+
+        let config = ServerConfig {
+            datastore: "elasticsearch".to_string(),
+            elastic_url: start_elastic("5.6.16"),
+            ..Default::default()
+        };
+        let shutdown = CancellationToken::new();
+        let datastore = configure_datastore(&config, &shutdown).await.unwrap();
+        tokio::time::timeout(timeout, shutdown.cancelled())
+            .await
+            .unwrap();
+        if let Datastore::Elastic(eventstore) = &datastore {
+            let version = eventstore.client.version.read().unwrap().clone();
+            assert!(check_version(&version.unwrap()).is_err());
+        } else {
+            panic!("expected an Elasticsearch datastore");
+        }
+
+        let config = ServerConfig {
+            datastore: "elasticsearch".to_string(),
+            elastic_url: start_elastic("7.17.0"),
+            ..Default::default()
+        };
+        let shutdown = CancellationToken::new();
+        let datastore = configure_datastore(&config, &shutdown).await.unwrap();
+        let version = tokio::time::timeout(timeout, async {
+            loop {
+                if let Datastore::Elastic(eventstore) = &datastore {
+                    if let Some(version) = eventstore.client.version.read().unwrap().clone() {
+                        return version;
+                    }
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(check_version(&version).is_ok());
+        assert!(!shutdown.is_cancelled());
+    }
+}
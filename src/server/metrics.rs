@@ -0,0 +1,179 @@
+// Copyright (C) 2022 Jason Ish
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! The Prometheus metrics endpoint and HTTP request metrics.
+
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use axum::extract::{Extension, MatchedPath};
+use axum::http::{header, Request, Response, StatusCode};
+use axum::response::IntoResponse;
+use futures::future::BoxFuture;
+use tower::{Layer, Service};
+
+use crate::metrics;
+use crate::prelude::*;
+use crate::server::main::SessionExtractor;
+use crate::server::ServerContext;
+
+pub(crate) async fn handler(
+    Extension(context): Extension<Arc<ServerContext>>,
+    session: Result<SessionExtractor, (StatusCode, &'static str)>,
+) -> impl IntoResponse {
+    if !context.config.metrics_enabled {
+        return (StatusCode::NOT_FOUND, "").into_response();
+    }
+    if !context.config.metrics_anonymous {
+        if let Err(err) = session {
+            return err.into_response();
+        }
+    }
+    match context.session_store.count_active() {
+        Ok(n) => metrics::SESSIONS_ACTIVE.set(n as i64),
+        Err(err) => error!("Failed to count active sessions: {}", err),
+    }
+    match metrics::encode() {
+        Ok(text) => Response::builder()
+            .header(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)
+            .body(text.into())
+            .unwrap(),
+        Err(err) => {
+            error!("Failed to encode metrics: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "").into_response()
+        }
+    }
+}
+
+/// Layer recording the duration of requests, labelled with the route
+/// pattern so path parameters such as event IDs don't create new series.
+#[derive(Clone)]
+pub(crate) struct HttpMetricsLayer;
+
+impl<S> Layer<S> for HttpMetricsLayer {
+    type Service = HttpMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HttpMetrics { inner }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct HttpMetrics<S> {
+    inner: S,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for HttpMetrics<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let method = req.method().to_string();
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_default();
+        let start = Instant::now();
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let response = future.await?;
+            metrics::HTTP_REQUEST_DURATION
+                .with_label_values(&[&method, &route, response.status().as_str()])
+                .observe(start.elapsed().as_secs_f64());
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::testing;
+    use crate::server::{AuthenticationType, ServerConfig};
+    use crate::sqlite::configrepo::ConfigRepo;
+    use std::net::SocketAddr;
+
+    async fn start_server(anonymous: bool) -> SocketAddr {
+        let config = ServerConfig {
+            authentication_required: true,
+            authentication_type: AuthenticationType::UsernamePassword,
+            metrics_enabled: true,
+            metrics_anonymous: anonymous,
+            ..Default::default()
+        };
+        let config_repo = ConfigRepo::new(None).unwrap();
+        config_repo.add_user("admin", "password").unwrap();
+        testing::start_server(config, config_repo).await.0
+    }
+
+    async fn get(addr: &SocketAddr, path: &str, session_id: Option<&str>) -> reqwest::Response {
+        let request = reqwest::Client::new().get(format!("http://{}{}", addr, path));
+        let request = match session_id {
+            Some(session_id) => request.header("x-evebox-session-id", session_id),
+            None => request,
+        };
+        request.send().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_metrics_authentication() {
+        let addr = start_server(false).await;
+        let response = get(&addr, "/metrics", None).await;
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/api/1/login", addr))
+            .form(&[("username", "admin"), ("password", "password")])
+            .send()
+            .await
+            .unwrap();
+        let body: serde_json::Value = response.json().await.unwrap();
+        let session_id = body["session_id"].as_str().unwrap();
+        let response = get(&addr, "/metrics", Some(session_id)).await;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_anonymous() {
+        let addr = start_server(true).await;
+        let response = get(&addr, "/api/1/event/1234", None).await;
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let response = get(&addr, "/metrics", None).await;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let text = response.text().await.unwrap();
+        assert!(text.contains("evebox_sessions_active"));
+        // Requests are labelled with the route, not the path.
+        assert!(text.contains(
+            "evebox_http_request_duration_seconds_count{method=\"GET\",route=\"/api/1/event/:id\",status=\"401\"}"
+        ));
+    }
+}
@@ -19,31 +19,50 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
 use serde::Serialize;
 
 pub(crate) use main::build_axum_server;
 pub use main::build_context;
 pub use main::main;
-use session::SessionStore;
+use session::{Role, SessionStore};
 
+use crate::cidr::Cidr;
 use crate::datastore::Datastore;
+use crate::eve::broadcast::EventBroadcaster;
+use crate::eve::filters::ReloadableFilters;
+use crate::geoip::GeoIP;
+use crate::rules::RuleMap;
+use crate::shutdown::CancellationToken;
 use crate::sqlite::configrepo::ConfigRepo;
 
+mod agentauth;
 pub mod api;
 mod asset;
 mod filters;
+mod inputs;
+pub mod ldap;
+mod lockout;
 mod main;
+mod metrics;
+pub mod oidc;
+mod proxyauth;
 mod rejection;
 mod response;
 pub mod session;
+#[cfg(test)]
+pub(crate) mod testing;
+mod twofactor;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum AuthenticationType {
     Anonymous,
     Username,
     UsernamePassword,
+    ReverseProxy,
+    Oidc,
 }
 
 impl ToString for AuthenticationType {
@@ -52,6 +71,8 @@ impl ToString for AuthenticationType {
             AuthenticationType::Anonymous => "anonymous",
             AuthenticationType::Username => "username",
             AuthenticationType::UsernamePassword => "usernamepassword",
+            AuthenticationType::ReverseProxy => "reverse-proxy",
+            AuthenticationType::Oidc => "oidc",
         };
         s.to_string()
     }
@@ -74,19 +95,84 @@ pub struct ServerContext {
     pub datastore: Datastore,
     pub features: Features,
     pub session_store: session::SessionStore,
+    pub login_lockout: lockout::LoginLockout,
+    pub pending_logins: twofactor::PendingLogins,
+    pub agent_credentials: agentauth::AgentCredentialCache,
+    /// Failed agent authentications are tracked separately from user logins
+    /// so a misconfigured agent can't lock a user out, or the other way around.
+    pub agent_lockout: lockout::LoginLockout,
     pub config_repo: Arc<ConfigRepo>,
-    pub event_services: Option<serde_json::Value>,
+    pub oidc: Option<oidc::OidcClient>,
+    pub ldap: Option<ldap::LdapAuthenticator>,
+    pub event_services: RwLock<Option<serde_json::Value>>,
+    /// Filters run on events submitted by agents.
+    pub submit_filters: ReloadableFilters,
+    /// The file inputs.
+    pub inputs: inputs::Inputs,
+    pub rules: RwLock<Option<Arc<RuleMap>>>,
+    pub geoip: RwLock<Option<GeoIP>>,
+    /// Events committed by the file inputs and agent submissions.
+    pub broadcaster: EventBroadcaster,
+    /// Cancelled when the server is shutting down.
+    pub shutdown: CancellationToken,
+    /// Requests a reload of the configuration, if supported.
+    pub reload: Option<tokio::sync::mpsc::Sender<ReloadRequest>>,
 }
 
+/// A request to reload the configuration, answered with the result.
+pub type ReloadRequest = tokio::sync::oneshot::Sender<anyhow::Result<()>>;
+
 impl ServerContext {
     pub fn new(config: ServerConfig, config_repo: Arc<ConfigRepo>, datastore: Datastore) -> Self {
+        let mut session_store = SessionStore::new(config_repo.clone());
+        session_store.idle_timeout = config.session_idle_timeout;
+        session_store.max_age = config.session_max_age;
+        let oidc = config.oidc.clone().map(oidc::OidcClient::new);
+        let ldap = config.ldap.clone().map(ldap::LdapAuthenticator::new);
         Self {
             config: config,
             datastore,
             features: Features::default(),
-            session_store: SessionStore::new(),
+            session_store,
+            login_lockout: lockout::LoginLockout::default(),
+            pending_logins: twofactor::PendingLogins::default(),
+            agent_credentials: agentauth::AgentCredentialCache::default(),
+            agent_lockout: lockout::LoginLockout::default(),
             config_repo: config_repo,
-            event_services: None,
+            oidc,
+            ldap,
+            event_services: RwLock::new(None),
+            submit_filters: ReloadableFilters::default(),
+            inputs: inputs::Inputs::default(),
+            rules: RwLock::new(None),
+            geoip: RwLock::new(None),
+            broadcaster: EventBroadcaster::default(),
+            shutdown: CancellationToken::new(),
+            reload: None,
+        }
+    }
+}
+
+/// Configuration for authentication by a trusted reverse proxy.
+#[derive(Debug, Clone)]
+pub struct ReverseProxyAuthConfig {
+    /// The header the proxy provides the authenticated username in.
+    pub header: String,
+    /// Only requests from these networks may authenticate with the header.
+    pub trusted_proxies: Vec<Cidr>,
+    /// The role of users provisioned on their first login.
+    pub role: Role,
+}
+
+impl Default for ReverseProxyAuthConfig {
+    fn default() -> Self {
+        Self {
+            header: "x-remote-user".to_string(),
+            trusted_proxies: vec![
+                Cidr::from_str("127.0.0.1/32").unwrap(),
+                Cidr::from_str("::1/128").unwrap(),
+            ],
+            role: Role::Viewer,
         }
     }
 }
@@ -110,7 +196,16 @@ pub struct ServerConfig {
     pub data_directory: Option<String>,
     pub authentication_required: bool,
     pub authentication_type: AuthenticationType,
+    pub agent_authentication_required: bool,
+    pub reverse_proxy_auth: ReverseProxyAuthConfig,
+    pub oidc: Option<oidc::OidcConfig>,
+    pub ldap: Option<ldap::LdapConfig>,
+    pub session_idle_timeout: Option<std::time::Duration>,
+    pub session_max_age: Option<std::time::Duration>,
     pub database_retention_period: Option<u64>,
     pub http_reverse_proxy: bool,
     pub http_request_logging: bool,
+    pub metrics_enabled: bool,
+    /// Allow the metrics to be scraped without authentication.
+    pub metrics_anonymous: bool,
 }
@@ -0,0 +1,62 @@
+// Copyright (C) 2022 Jason Ish
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Authentication by a trusted reverse proxy, such as an SSO proxy, that
+//! provides the authenticated username in a request header.
+
+use std::net::SocketAddr;
+
+use axum::http::HeaderMap;
+
+use crate::prelude::*;
+use crate::server::ReverseProxyAuthConfig;
+
+/// Get the username provided by the reverse proxy. The header is only trusted
+/// if the request was made by one of the trusted proxies, as anyone else could
+/// set it.
+pub(crate) fn get_username(
+    config: &ReverseProxyAuthConfig,
+    peer: &SocketAddr,
+    headers: &HeaderMap,
+) -> Option<String> {
+    let username = headers
+        .get(config.header.as_str())?
+        .to_str()
+        .ok()?
+        .trim()
+        .to_string();
+    if username.is_empty() {
+        return None;
+    }
+    let peer = peer.ip();
+    if !config
+        .trusted_proxies
+        .iter()
+        .any(|cidr| cidr.contains(&peer))
+    {
+        warn!(
+            "Ignoring {} header from untrusted address {}",
+            &config.header, peer
+        );
+        return None;
+    }
+    Some(username)
+}
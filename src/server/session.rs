@@ -19,46 +19,178 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use crate::prelude::*;
+use crate::sqlite::configrepo::{self, ConfigRepo, StoredSession};
 use anyhow::Result;
 use rand::RngCore;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub struct SessionStore {
-    cache: Mutex<HashMap<String, Arc<Session>>>,
-}
+/// How often the last seen time of a session is written back to the database.
+const TOUCH_INTERVAL: i64 = 60;
 
-impl Default for SessionStore {
-    fn default() -> Self {
-        Self::new()
-    }
+/// Sessions are persisted in the configuration database so they survive
+/// restarts and can be revoked from the command line.
+pub struct SessionStore {
+    repo: Arc<ConfigRepo>,
+    /// Expire sessions that have not been used for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Expire sessions this long after login, even if still in use.
+    pub max_age: Option<Duration>,
 }
 
 impl SessionStore {
-    pub fn new() -> Self {
+    pub fn new(repo: Arc<ConfigRepo>) -> Self {
         SessionStore {
-            cache: Mutex::new(HashMap::new()),
+            repo,
+            idle_timeout: None,
+            max_age: None,
         }
     }
 
+    /// Add a session for a user, taking its role from the users table.
     pub fn put(&self, session: Arc<Session>) -> Result<()> {
-        let mut cache = self.cache.lock().unwrap();
+        self.add(session, None)
+    }
+
+    /// Add a session that is not backed by a user, storing its role with the
+    /// session.
+    pub fn put_with_role(&self, session: Arc<Session>) -> Result<()> {
+        let role = session.role.to_string();
+        self.add(session, Some(&role))
+    }
+
+    fn add(&self, session: Arc<Session>, role: Option<&str>) -> Result<()> {
         if let Some(session_id) = &session.session_id {
-            if cache.insert(session_id.to_string(), session).is_some() {
+            if self.repo.get_session(session_id)?.is_some() {
                 return Err(anyhow!("duplicate session-id"));
             }
+            self.repo
+                .add_session(session_id, session.username(), role)?;
         }
         Ok(())
     }
 
     pub fn get(&self, session_id: &str) -> Option<Arc<Session>> {
-        let cache = self.cache.lock().unwrap();
-        cache.get(session_id).cloned()
+        let stored = match self.repo.get_session(session_id) {
+            Ok(Some(stored)) => stored,
+            Ok(None) => return None,
+            Err(err) => {
+                error!("Failed to get session from database: {}", err);
+                return None;
+            }
+        };
+        let now = chrono::Utc::now().timestamp();
+        if self.is_expired(&stored, now) {
+            info!("Session for user {} has expired", &stored.username);
+            self.delete(session_id);
+            return None;
+        }
+        if now - stored.updated >= TOUCH_INTERVAL {
+            if let Err(err) = self.repo.touch_session(session_id) {
+                error!("Failed to update session last seen time: {}", err);
+            }
+        }
+        let role = match Role::from_str(&stored.role) {
+            Ok(role) => role,
+            Err(err) => {
+                error!("Bad role for user {}: {}", &stored.username, err);
+                return None;
+            }
+        };
+        Some(Arc::new(Session {
+            session_id: Some(session_id.to_string()),
+            username: Some(stored.username),
+            role,
+        }))
     }
 
     pub fn delete(&self, session_id: &str) -> bool {
-        let mut cache = self.cache.lock().unwrap();
-        cache.remove(session_id).is_some()
+        match self.repo.delete_session(session_id) {
+            Ok(n) => n > 0,
+            Err(err) => {
+                error!("Failed to delete session: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Delete all sessions for a user, returning the number deleted.
+    pub fn delete_by_username(&self, username: &str) -> Result<usize> {
+        Ok(self.repo.delete_sessions_by_username(username)?)
+    }
+
+    /// Remove all expired sessions from the database.
+    pub fn reap(&self) -> Result<usize> {
+        if self.idle_timeout.is_none() && self.max_age.is_none() {
+            return Ok(0);
+        }
+        let now = chrono::Utc::now().timestamp();
+        let updated_before = self.idle_timeout.map(|d| now - d.as_secs() as i64);
+        let created_before = self.max_age.map(|d| now - d.as_secs() as i64);
+        Ok(self
+            .repo
+            .delete_expired_sessions(updated_before, created_before)?)
+    }
+
+    /// Count the sessions that have not expired.
+    pub fn count_active(&self) -> Result<usize> {
+        let now = chrono::Utc::now().timestamp();
+        let updated_after = self.idle_timeout.map(|d| now - d.as_secs() as i64);
+        let created_after = self.max_age.map(|d| now - d.as_secs() as i64);
+        Ok(self.repo.count_sessions(updated_after, created_after)?)
+    }
+
+    fn is_expired(&self, session: &StoredSession, now: i64) -> bool {
+        if let Some(idle_timeout) = self.idle_timeout {
+            if now - session.updated > idle_timeout.as_secs() as i64 {
+                return true;
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            if now - session.created > max_age.as_secs() as i64 {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// User roles, ordered from least to most privileged. Agents may only submit
+/// events so are not allowed any access through a session.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Agent,
+    #[default]
+    Viewer,
+    Analyst,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            configrepo::ROLE_AGENT => Ok(Self::Agent),
+            configrepo::ROLE_VIEWER => Ok(Self::Viewer),
+            configrepo::ROLE_ANALYST => Ok(Self::Analyst),
+            configrepo::ROLE_ADMIN => Ok(Self::Admin),
+            _ => Err(anyhow!("unknown role: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let role = match self {
+            Self::Agent => configrepo::ROLE_AGENT,
+            Self::Viewer => configrepo::ROLE_VIEWER,
+            Self::Analyst => configrepo::ROLE_ANALYST,
+            Self::Admin => configrepo::ROLE_ADMIN,
+        };
+        write!(f, "{}", role)
     }
 }
 
@@ -66,6 +198,7 @@ impl SessionStore {
 pub struct Session {
     pub session_id: Option<String>,
     pub username: Option<String>,
+    pub role: Role,
 }
 
 impl Session {
@@ -74,12 +207,15 @@ impl Session {
         Session {
             session_id: Some(session_id),
             username: None,
+            role: Role::default(),
         }
     }
 
+    /// A session for when authentication is not required, with full access.
     pub fn anonymous(username: Option<String>) -> Session {
         Session {
             username,
+            role: Role::Admin,
             ..Default::default()
         }
     }
@@ -99,3 +235,97 @@ pub fn generate_session_id() -> String {
     rng.fill_bytes(&mut buf);
     base64::encode(&buf)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_session_store() {
+        let repo = Arc::new(ConfigRepo::new(None).unwrap());
+        let store = SessionStore::new(repo.clone());
+
+        let mut session = Session::new();
+        session.username = Some("admin".to_string());
+        let session_id = session.session_id.clone().unwrap();
+        store.put(Arc::new(session)).unwrap();
+
+        // A second store on the same database sees the session, as a
+        // restarted server would.
+        let store = SessionStore::new(repo.clone());
+        // Not in the users table and without a role, so no access.
+        assert!(store.get(&session_id).is_none());
+
+        repo.add_user_with_role("admin", "password", configrepo::ROLE_VIEWER)
+            .unwrap();
+        let session = store.get(&session_id).unwrap();
+        assert_eq!(session.username(), "admin");
+        assert_eq!(session.role, Role::Viewer);
+
+        assert_eq!(store.delete_by_username("admin").unwrap(), 1);
+        assert!(store.get(&session_id).is_none());
+    }
+
+    #[test]
+    fn test_session_with_role() {
+        let repo = Arc::new(ConfigRepo::new(None).unwrap());
+        let store = SessionStore::new(repo.clone());
+
+        let mut session = Session::new();
+        session.username = Some("someone".to_string());
+        session.role = Role::Admin;
+        let session_id = session.session_id.clone().unwrap();
+        store.put_with_role(Arc::new(session)).unwrap();
+        let session = store.get(&session_id).unwrap();
+        assert_eq!(session.role, Role::Admin);
+
+        // The role of a user takes precedence.
+        repo.add_user_with_role("someone", "password", configrepo::ROLE_VIEWER)
+            .unwrap();
+        let session = store.get(&session_id).unwrap();
+        assert_eq!(session.role, Role::Viewer);
+    }
+
+    #[test]
+    fn test_remove_user() {
+        let repo = Arc::new(ConfigRepo::new(None).unwrap());
+        let store = SessionStore::new(repo.clone());
+        repo.add_user_with_role("admin", "password", configrepo::ROLE_ADMIN)
+            .unwrap();
+
+        let mut session = Session::new();
+        session.username = Some("admin".to_string());
+        let session_id = session.session_id.clone().unwrap();
+        store.put(Arc::new(session)).unwrap();
+        assert!(store.get(&session_id).is_some());
+        repo.add_token("admin", None).unwrap();
+
+        // Sessions and API tokens are removed with the user, so they can't be
+        // used if the username is added again.
+        assert_eq!(repo.remove_user("admin").unwrap(), 1);
+        assert_eq!(repo.delete_sessions_by_username("admin").unwrap(), 0);
+        assert!(repo.get_tokens().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let repo = Arc::new(ConfigRepo::new(None).unwrap());
+        let mut store = SessionStore::new(repo);
+        let session = StoredSession {
+            username: "admin".to_string(),
+            role: configrepo::ROLE_ADMIN.to_string(),
+            created: 1000,
+            updated: 2000,
+        };
+        assert!(!store.is_expired(&session, 1_000_000));
+
+        store.idle_timeout = Some(Duration::from_secs(60));
+        assert!(!store.is_expired(&session, 2060));
+        assert!(store.is_expired(&session, 2061));
+
+        store.idle_timeout = None;
+        store.max_age = Some(Duration::from_secs(3600));
+        assert!(!store.is_expired(&session, 4600));
+        assert!(store.is_expired(&session, 4601));
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright (C) 2022 Jason Ish
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Helpers for testing the server through its HTTP interface, and for test
+//! files.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::datastore::Datastore;
+use crate::server::main::build_axum_service;
+use crate::server::{ServerConfig, ServerContext};
+use crate::sqlite::configrepo::ConfigRepo;
+use crate::sqlite::eventstore::SQLiteEventStore;
+use crate::sqlite::ConnectionBuilder;
+
+/// Start a server on a random port with an Elasticsearch datastore that
+/// can't be reached, for testing requests that don't need the datastore.
+pub(crate) async fn start_server(
+    config: ServerConfig,
+    config_repo: ConfigRepo,
+) -> (SocketAddr, Arc<ServerContext>) {
+    serve(build_context(config, config_repo)).await
+}
+
+/// Build the context for a server with an Elasticsearch datastore that can't
+/// be reached, to be modified before starting the server with `serve`.
+pub(crate) fn build_context(config: ServerConfig, config_repo: ConfigRepo) -> ServerContext {
+    let client = crate::elastic::ClientBuilder::new("http://127.0.0.1:1").build();
+    let datastore = Datastore::Elastic(crate::elastic::EventStore {
+        base_index: "logstash".to_string(),
+        index_pattern: "logstash-*".to_string(),
+        client,
+        ecs: false,
+        no_index_suffix: false,
+    });
+    ServerContext::new(config, Arc::new(config_repo), datastore)
+}
+
+/// Start a server for the context on a random port.
+pub(crate) async fn serve(context: ServerContext) -> (SocketAddr, Arc<ServerContext>) {
+    let context = Arc::new(context);
+    let service = build_axum_service(context.clone());
+    let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    (addr, context)
+}
+
+/// Open an SQLite event store on a new in-memory database. The database is
+/// shared by the connections of the store, and lives as long as the store.
+pub(crate) async fn sqlite_eventstore() -> SQLiteEventStore {
+    let filename = format!(
+        "file:{}?mode=memory&cache=shared",
+        unique_name("eventstore")
+    );
+    let connection_builder = Arc::new(ConnectionBuilder::filename(Some(&filename)));
+    let mut conn = connection_builder.open().unwrap();
+    crate::sqlite::init_event_db(&mut conn).unwrap();
+    let pool = crate::sqlite::open_pool(&filename).await.unwrap();
+    SQLiteEventStore::new(connection_builder, pool)
+}
+
+/// A name unique to this test run, for temporary files and databases.
+pub(crate) fn unique_name(name: &str) -> String {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    format!(
+        "evebox-{}-test-{}-{}",
+        name,
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// A temporary directory, removed along with its contents when dropped.
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(unique_name(name));
+        // Left over from an earlier run that was interrupted.
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Command line arguments of a server with no options set, for loading a
+/// configuration file with `crate::config::Config`.
+pub(crate) fn server_args() -> clap::ArgMatches {
+    clap::Command::new("server")
+        .arg(
+            clap::Arg::new("input.filename")
+                .long("input")
+                .takes_value(true),
+        )
+        .arg(clap::Arg::new("end").long("end"))
+        .get_matches_from(vec!["server"])
+}
@@ -0,0 +1,116 @@
+// Copyright (C) 2022 Jason Ish
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Logins waiting on the second factor after the password was verified.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::server::session::{generate_session_id, Role};
+
+/// How long a user has to enter their code after their password.
+const PENDING_LOGIN_PERIOD: Duration = Duration::from_secs(300);
+
+struct PendingLogin {
+    username: String,
+    role: Role,
+    created: Instant,
+}
+
+pub struct PendingLogins {
+    period: Duration,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+impl Default for PendingLogins {
+    fn default() -> Self {
+        Self::new(PENDING_LOGIN_PERIOD)
+    }
+}
+
+impl PendingLogins {
+    pub fn new(period: Duration) -> Self {
+        Self {
+            period,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Add a login waiting on the second factor, returning the token that
+    /// identifies it in the second step.
+    pub fn add(&self, username: &str, role: Role) -> String {
+        let token = generate_session_id();
+        let mut pending = self.pending.lock().unwrap();
+        let period = self.period;
+        pending.retain(|_, login| login.created.elapsed() < period);
+        pending.insert(
+            token.clone(),
+            PendingLogin {
+                username: username.to_string(),
+                role,
+                created: Instant::now(),
+            },
+        );
+        token
+    }
+
+    /// Get the username and role of a pending login.
+    pub fn get(&self, token: &str) -> Option<(String, Role)> {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get(token) {
+            Some(login) if login.created.elapsed() < self.period => {
+                Some((login.username.clone(), login.role))
+            }
+            Some(_) => {
+                pending.remove(token);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn remove(&self, token: &str) {
+        self.pending.lock().unwrap().remove(token);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pending_logins() {
+        let logins = PendingLogins::new(Duration::from_millis(50));
+        let token = logins.add("user", Role::Analyst);
+        assert_eq!(
+            logins.get(&token),
+            Some(("user".to_string(), Role::Analyst))
+        );
+        assert_eq!(logins.get("other"), None);
+        logins.remove(&token);
+        assert_eq!(logins.get(&token), None);
+
+        let token = logins.add("user", Role::Analyst);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(logins.get(&token), None);
+    }
+}
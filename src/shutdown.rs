@@ -0,0 +1,90 @@
+// Copyright (C) 2022 Jason Ish
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Graceful shutdown on SIGINT and SIGTERM.
+//!
+//! On a signal a cancellation token is cancelled so readers can stop and
+//! commit pending events, and the HTTP server can drain its connections.
+//! If that takes longer than the shutdown timeout, or a second signal is
+//! received, the process exits immediately.
+
+use std::time::Duration;
+
+pub use tokio_util::sync::CancellationToken;
+
+use crate::prelude::*;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Cancel the token on SIGINT or SIGTERM, then exit if shutdown has not
+/// completed within the timeout.
+pub fn cancel_on_signal(token: CancellationToken, timeout: Duration) {
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        info!(
+            "Shutting down, waiting up to {:?} for pending events to be committed",
+            timeout
+        );
+        token.cancel();
+        tokio::select! {
+            _ = tokio::time::sleep(timeout) => {
+                warn!("Shutdown did not complete within {:?}, exiting", timeout);
+            }
+            _ = wait_for_signal() => {
+                warn!("Received second signal, exiting");
+            }
+        }
+        std::process::exit(1);
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to register SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to register CTRL-C handler");
+}
+
+/// Get the shutdown timeout from the `shutdown-timeout` configuration key, a
+/// duration such as "30s", or a number of seconds.
+pub fn timeout_from_config(config: &crate::config::Config) -> anyhow::Result<Duration> {
+    let key = "shutdown-timeout";
+    match config.get_value::<serde_yaml::Value>(key)? {
+        None => Ok(DEFAULT_TIMEOUT),
+        Some(serde_yaml::Value::Number(value)) => match value.as_u64() {
+            Some(seconds) => Ok(Duration::from_secs(seconds)),
+            None => Err(anyhow!("Bad value for {}: {:?}", key, value)),
+        },
+        Some(serde_yaml::Value::String(value)) => humantime::parse_duration(&value)
+            .map_err(|err| anyhow!("Bad value for {}: {}: {}", key, value, err)),
+        Some(value) => Err(anyhow!("Bad value for {}: {:?}", key, value)),
+    }
+}
@@ -23,11 +23,28 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
 
+use rand::RngCore;
 use rusqlite::params;
 
 use crate::prelude::*;
 use crate::sqlite::ConnectionBuilder;
 
+/// A user that can view events, but not modify them.
+pub const ROLE_VIEWER: &str = "viewer";
+
+/// A user that can view, archive, escalate and comment on events.
+pub const ROLE_ANALYST: &str = "analyst";
+
+/// A user with full access to EveBox.
+pub const ROLE_ADMIN: &str = "admin";
+
+/// A user that may only submit events, such as the EveBox agent.
+pub const ROLE_AGENT: &str = "agent";
+
+/// How often the last used time of an API token is written back to the
+/// database, so agents submitting events don't write on every request.
+const TOKEN_TOUCH_INTERVAL: i64 = 60;
+
 #[derive(thiserror::Error, Debug)]
 pub enum ConfigRepoError {
     #[error("username not found: {0}")]
@@ -42,12 +59,38 @@ pub enum ConfigRepoError {
     JoinError(#[from] tokio::task::JoinError),
     #[error("user does not exist: {0}")]
     NoUser(String),
+    #[error("user already exists: {0}")]
+    UserExists(String),
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
 pub struct User {
     pub uuid: String,
     pub username: String,
+    pub role: String,
+}
+
+/// A session as stored in the database.
+#[derive(Debug, Clone)]
+pub struct StoredSession {
+    pub username: String,
+    /// The current role of the user, sessions for users not in the
+    /// database, as with username authentication, are admins.
+    pub role: String,
+    /// Creation time in seconds since the epoch.
+    pub created: i64,
+    /// Last seen time in seconds since the epoch.
+    pub updated: i64,
+}
+
+/// An API token, without the secret token itself.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ApiToken {
+    pub uuid: String,
+    pub username: String,
+    pub description: Option<String>,
+    pub created: i64,
+    pub last_used: Option<i64>,
 }
 
 pub struct ConfigRepo {
@@ -73,17 +116,24 @@ impl ConfigRepo {
         let db = self.db.clone();
         tokio::task::spawn_blocking(move || {
             let conn = db.lock().unwrap();
-            let mut stmt =
-                conn.prepare("SELECT uuid, username, password FROM users WHERE username = ?1")?;
+            let mut stmt = conn
+                .prepare("SELECT uuid, username, password, role FROM users WHERE username = ?1")?;
             let mut rows = stmt.query(params![username])?;
             if let Some(row) = rows.next()? {
                 let uuid: String = row.get(0)?;
                 let username: String = row.get(1)?;
-                let password_hash: String = row.get(2)?;
-                if bcrypt::verify(password_in, &password_hash)? {
+                // Users provisioned from an external login have no password.
+                let password_hash: Option<String> = row.get(2)?;
+                let role: String = row.get(3)?;
+                let verified = match password_hash {
+                    Some(password_hash) => bcrypt::verify(password_in, &password_hash)?,
+                    None => false,
+                };
+                if verified {
                     Ok(User {
                         uuid: uuid,
                         username: username,
+                        role: role,
                     })
                 } else {
                     Err(ConfigRepoError::BadPassword(username))
@@ -99,12 +149,13 @@ impl ConfigRepo {
         let conn = self.db.lock().unwrap();
         let user = conn
             .query_row(
-                "SELECT uuid, username FROM users WHERE username = ?",
+                "SELECT uuid, username, role FROM users WHERE username = ?",
                 params![username],
                 |row| {
                     Ok(User {
                         uuid: row.get(0)?,
                         username: row.get(1)?,
+                        role: row.get(2)?,
                     })
                 },
             )
@@ -119,11 +170,12 @@ impl ConfigRepo {
 
     pub fn get_users(&self) -> Result<Vec<User>, ConfigRepoError> {
         let conn = self.db.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT uuid, username FROM users")?;
+        let mut stmt = conn.prepare("SELECT uuid, username, role FROM users")?;
         let rows = stmt.query_map(params![], |row| {
             Ok(User {
                 uuid: row.get(0)?,
                 username: row.get(1)?,
+                role: row.get(2)?,
             })
         })?;
         let mut users = Vec::new();
@@ -134,22 +186,171 @@ impl ConfigRepo {
     }
 
     pub fn add_user(&self, username: &str, password: &str) -> Result<String, ConfigRepoError> {
+        self.add_user_with_role(username, password, ROLE_ADMIN)
+    }
+
+    pub fn add_user_with_role(
+        &self,
+        username: &str,
+        password: &str,
+        role: &str,
+    ) -> Result<String, ConfigRepoError> {
         let password_hash = bcrypt::hash(password, bcrypt::DEFAULT_COST)?;
         let user_id = uuid::Uuid::new_v4().to_string();
         let mut conn = self.db.lock().unwrap();
         let tx = conn.transaction()?;
         tx.execute(
-            "INSERT INTO users (uuid, username, password) VALUES (?, ?, ?)",
-            params![user_id, username, password_hash],
+            "INSERT INTO users (uuid, username, password, role) VALUES (?, ?, ?, ?)",
+            params![user_id, username, password_hash, role],
         )?;
         tx.commit()?;
         Ok(user_id)
     }
 
+    /// Get a user that has been authenticated externally, adding the user
+    /// without a password and with the given role if they don't exist yet.
+    pub fn provision_user(&self, username: &str, role: &str) -> Result<User, ConfigRepoError> {
+        match self.get_user_by_name(username) {
+            Err(ConfigRepoError::NoUser(_)) => {}
+            result => return result,
+        }
+        let user = User {
+            uuid: uuid::Uuid::new_v4().to_string(),
+            username: username.to_string(),
+            role: role.to_string(),
+        };
+        let conn = self.db.lock().unwrap();
+        conn.execute(
+            "INSERT INTO users (uuid, username, role) VALUES (?, ?, ?)",
+            params![user.uuid, user.username, user.role],
+        )?;
+        info!("Provisioned user {} with role {}", username, role);
+        Ok(user)
+    }
+
+    /// Get a user authenticated by an LDAP directory, adding the user on their
+    /// first login and otherwise updating their role, as it follows their
+    /// current groups.
+    ///
+    /// Users with a local password are not taken over by a directory user of
+    /// the same name.
+    pub fn provision_ldap_user(&self, username: &str, role: &str) -> Result<User, ConfigRepoError> {
+        let mut conn = self.db.lock().unwrap();
+        let tx = conn.transaction()?;
+        let user = tx.query_row(
+            "SELECT uuid, password IS NOT NULL FROM users WHERE username = ?",
+            params![username],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?)),
+        );
+        let uuid = match user {
+            Ok((_, true)) => return Err(ConfigRepoError::UserExists(username.to_string())),
+            Ok((uuid, false)) => {
+                tx.execute(
+                    "UPDATE users SET role = ? WHERE uuid = ?",
+                    params![role, uuid],
+                )?;
+                uuid
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                let uuid = uuid::Uuid::new_v4().to_string();
+                tx.execute(
+                    "INSERT INTO users (uuid, username, role) VALUES (?, ?, ?)",
+                    params![uuid, username, role],
+                )?;
+                info!("Provisioned user {} with role {}", username, role);
+                uuid
+            }
+            Err(err) => return Err(err.into()),
+        };
+        tx.commit()?;
+        Ok(User {
+            uuid,
+            username: username.to_string(),
+            role: role.to_string(),
+        })
+    }
+
+    /// Get the user for an OpenID Connect identity, adding the user with the
+    /// given role on their first login.
+    ///
+    /// An existing user with the same username is only linked to the identity
+    /// if they have no password, otherwise anyone able to choose their username
+    /// at the identity provider could take over a local user.
+    pub fn provision_oidc_user(
+        &self,
+        issuer: &str,
+        subject: &str,
+        username: &str,
+        role: &str,
+    ) -> Result<User, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let user = conn.query_row(
+            "SELECT uuid, username, role FROM users WHERE oidc_issuer = ? AND oidc_subject = ?",
+            params![issuer, subject],
+            |row| {
+                Ok(User {
+                    uuid: row.get(0)?,
+                    username: row.get(1)?,
+                    role: row.get(2)?,
+                })
+            },
+        );
+        match user {
+            Ok(user) => return Ok(user),
+            Err(rusqlite::Error::QueryReturnedNoRows) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let n = conn.execute(
+            "UPDATE users SET oidc_issuer = ?, oidc_subject = ?
+             WHERE username = ? AND password IS NULL AND oidc_subject IS NULL",
+            params![issuer, subject, username],
+        )?;
+        if n == 0 {
+            let exists: bool = conn.query_row(
+                "SELECT count(*) > 0 FROM users WHERE username = ?",
+                params![username],
+                |row| row.get(0),
+            )?;
+            if exists {
+                return Err(ConfigRepoError::UserExists(username.to_string()));
+            }
+            conn.execute(
+                "INSERT INTO users (uuid, username, role, oidc_issuer, oidc_subject)
+                 VALUES (?, ?, ?, ?, ?)",
+                params![
+                    uuid::Uuid::new_v4().to_string(),
+                    username,
+                    role,
+                    issuer,
+                    subject
+                ],
+            )?;
+            info!("Provisioned user {} with role {}", username, role);
+        }
+        let user = conn.query_row(
+            "SELECT uuid, username, role FROM users WHERE username = ?",
+            params![username],
+            |row| {
+                Ok(User {
+                    uuid: row.get(0)?,
+                    username: row.get(1)?,
+                    role: row.get(2)?,
+                })
+            },
+        )?;
+        Ok(user)
+    }
+
     pub fn remove_user(&self, username: &str) -> Result<usize, ConfigRepoError> {
         let mut conn = self.db.lock().unwrap();
         let tx = conn.transaction()?;
         let n = tx.execute("DELETE FROM users WHERE username = ?", params![username])?;
+        tx.execute("DELETE FROM sessions WHERE username = ?", params![username])?;
+        tx.execute(
+            "DELETE FROM api_tokens WHERE username = ?",
+            params![username],
+        )?;
         tx.commit()?;
         Ok(n)
     }
@@ -165,6 +366,313 @@ impl ConfigRepo {
         tx.commit()?;
         Ok(n > 0)
     }
+
+    /// Set the TOTP secret for a user, or clear it to disable two-factor
+    /// authentication.
+    pub fn set_totp_secret(
+        &self,
+        username: &str,
+        secret: Option<&str>,
+    ) -> Result<bool, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let n = conn.execute(
+            "UPDATE users SET totp_secret = ?, totp_step = NULL WHERE username = ?",
+            params![secret, username],
+        )?;
+        Ok(n > 0)
+    }
+
+    /// Get the password hash and role of a user with a local password. The
+    /// hash changes with the password, so it can be used to invalidate
+    /// cached credentials.
+    pub fn get_password_hash(
+        &self,
+        username: &str,
+    ) -> Result<Option<(String, String)>, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let result = conn.query_row(
+            "SELECT password, role FROM users WHERE username = ? AND password IS NOT NULL",
+            params![username],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        );
+        match result {
+            Ok(hash) => Ok(Some(hash)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Check if a user has a local password, users provisioned from an
+    /// external login do not.
+    pub fn has_password(&self, username: &str) -> Result<bool, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let result = conn.query_row(
+            "SELECT password IS NOT NULL FROM users WHERE username = ?",
+            params![username],
+            |row| row.get(0),
+        );
+        match result {
+            Ok(has_password) => Ok(has_password),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn has_totp(&self, username: &str) -> Result<bool, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let enabled = conn
+            .query_row(
+                "SELECT totp_secret IS NOT NULL FROM users WHERE username = ?",
+                params![username],
+                |row| row.get(0),
+            )
+            .map_err(|err| match err {
+                rusqlite::Error::QueryReturnedNoRows => {
+                    ConfigRepoError::NoUser(username.to_string())
+                }
+                _ => err.into(),
+            })?;
+        Ok(enabled)
+    }
+
+    /// Verify a TOTP code for a user. Each code is only accepted once, as is
+    /// any code older than the last accepted one.
+    pub fn verify_totp(&self, username: &str, code: &str) -> Result<bool, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let (secret, last_step): (Option<String>, Option<i64>) = conn.query_row(
+            "SELECT totp_secret, totp_step FROM users WHERE username = ?",
+            params![username],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let secret = match secret {
+            Some(secret) => secret,
+            None => return Ok(false),
+        };
+        let now = chrono::Utc::now().timestamp() as u64;
+        let step = match crate::totp::verify(&secret, code, now) {
+            Some(step) => step as i64,
+            None => return Ok(false),
+        };
+        if matches!(last_step, Some(last_step) if step <= last_step) {
+            warn!("Refusing reused TOTP code for user {}", username);
+            return Ok(false);
+        }
+        conn.execute(
+            "UPDATE users SET totp_step = ? WHERE username = ?",
+            params![step, username],
+        )?;
+        Ok(true)
+    }
+
+    /// Add a session. Like API tokens, only a hash of the session ID is
+    /// stored. The role should only be provided for sessions not backed by a
+    /// user, otherwise the role of the user is used.
+    pub fn add_session(
+        &self,
+        session_id: &str,
+        username: &str,
+        role: Option<&str>,
+    ) -> Result<(), ConfigRepoError> {
+        let now = chrono::Utc::now().timestamp();
+        let conn = self.db.lock().unwrap();
+        conn.execute(
+            "INSERT INTO sessions (session_id, username, role, created, updated)
+             VALUES (?, ?, ?, ?, ?)",
+            params![hash_token(session_id), username, role, now, now],
+        )?;
+        Ok(())
+    }
+
+    /// Get a session. Sessions without a role, such as those of a user that
+    /// no longer exists, are not returned.
+    pub fn get_session(&self, session_id: &str) -> Result<Option<StoredSession>, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let result = conn.query_row(
+            "SELECT sessions.username, COALESCE(users.role, sessions.role),
+                created, updated
+             FROM sessions LEFT JOIN users ON sessions.username = users.username
+             WHERE session_id = ? AND COALESCE(users.role, sessions.role) IS NOT NULL",
+            params![hash_token(session_id)],
+            |row| {
+                Ok(StoredSession {
+                    username: row.get(0)?,
+                    role: row.get(1)?,
+                    created: row.get(2)?,
+                    updated: row.get(3)?,
+                })
+            },
+        );
+        match result {
+            Ok(session) => Ok(Some(session)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Update the last seen time of a session.
+    pub fn touch_session(&self, session_id: &str) -> Result<(), ConfigRepoError> {
+        let now = chrono::Utc::now().timestamp();
+        let conn = self.db.lock().unwrap();
+        conn.execute(
+            "UPDATE sessions SET updated = ? WHERE session_id = ?",
+            params![now, hash_token(session_id)],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_session(&self, session_id: &str) -> Result<usize, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let n = conn.execute(
+            "DELETE FROM sessions WHERE session_id = ?",
+            params![hash_token(session_id)],
+        )?;
+        Ok(n)
+    }
+
+    pub fn delete_sessions_by_username(&self, username: &str) -> Result<usize, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let n = conn.execute("DELETE FROM sessions WHERE username = ?", params![username])?;
+        Ok(n)
+    }
+
+    /// Delete sessions last seen before `updated_before` or created before `created_before`,
+    /// both in seconds since the epoch.
+    pub fn delete_expired_sessions(
+        &self,
+        updated_before: Option<i64>,
+        created_before: Option<i64>,
+    ) -> Result<usize, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let n = conn.execute(
+            "DELETE FROM sessions WHERE updated < ? OR created < ?",
+            params![
+                updated_before.unwrap_or(i64::MIN),
+                created_before.unwrap_or(i64::MIN)
+            ],
+        )?;
+        Ok(n)
+    }
+
+    /// Count sessions last seen at or after `updated_after` and created at or after
+    /// `created_after`, both in seconds since the epoch.
+    pub fn count_sessions(
+        &self,
+        updated_after: Option<i64>,
+        created_after: Option<i64>,
+    ) -> Result<usize, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let n: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM sessions WHERE updated >= ? AND created >= ?",
+            params![
+                updated_after.unwrap_or(i64::MIN),
+                created_after.unwrap_or(i64::MIN)
+            ],
+            |row| row.get(0),
+        )?;
+        Ok(n as usize)
+    }
+
+    /// Create a new API token for a user, returning the token ID and the
+    /// token. Only a hash of the token is stored, so it can't be shown again.
+    pub fn add_token(
+        &self,
+        username: &str,
+        description: Option<&str>,
+    ) -> Result<(String, String), ConfigRepoError> {
+        let user = self.get_user_by_name(username)?;
+        let token = generate_token();
+        let token_id = uuid::Uuid::new_v4().to_string();
+        let now = chrono::Utc::now().timestamp();
+        let conn = self.db.lock().unwrap();
+        conn.execute(
+            "INSERT INTO api_tokens (uuid, username, description, token_hash, created)
+             VALUES (?, ?, ?, ?, ?)",
+            params![
+                token_id,
+                user.username,
+                description,
+                hash_token(&token),
+                now
+            ],
+        )?;
+        Ok((token_id, token))
+    }
+
+    /// Get the user an API token belongs to, if the token is valid.
+    pub fn get_user_by_token(&self, token: &str) -> Result<Option<User>, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let token_hash = hash_token(token);
+        let result = conn.query_row(
+            "SELECT users.uuid, users.username, users.role, api_tokens.last_used
+             FROM api_tokens JOIN users ON api_tokens.username = users.username
+             WHERE api_tokens.token_hash = ?",
+            params![token_hash],
+            |row| {
+                let user = User {
+                    uuid: row.get(0)?,
+                    username: row.get(1)?,
+                    role: row.get(2)?,
+                };
+                let last_used: Option<i64> = row.get(3)?;
+                Ok((user, last_used))
+            },
+        );
+        match result {
+            Ok((user, last_used)) => {
+                let now = chrono::Utc::now().timestamp();
+                if !matches!(last_used, Some(last_used) if now - last_used < TOKEN_TOUCH_INTERVAL) {
+                    conn.execute(
+                        "UPDATE api_tokens SET last_used = ? WHERE token_hash = ?",
+                        params![now, token_hash],
+                    )?;
+                }
+                Ok(Some(user))
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn get_tokens(&self) -> Result<Vec<ApiToken>, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT uuid, username, description, created, last_used
+             FROM api_tokens ORDER BY created",
+        )?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok(ApiToken {
+                uuid: row.get(0)?,
+                username: row.get(1)?,
+                description: row.get(2)?,
+                created: row.get(3)?,
+                last_used: row.get(4)?,
+            })
+        })?;
+        let mut tokens = Vec::new();
+        for row in rows {
+            tokens.push(row?);
+        }
+        Ok(tokens)
+    }
+
+    pub fn remove_token(&self, token_id: &str) -> Result<usize, ConfigRepoError> {
+        let conn = self.db.lock().unwrap();
+        let n = conn.execute("DELETE FROM api_tokens WHERE uuid = ?", params![token_id])?;
+        Ok(n)
+    }
+}
+
+fn generate_token() -> String {
+    let mut buf = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut buf);
+    let hex: String = buf.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("evebox_{}", hex)
+}
+
+fn hash_token(token: &str) -> String {
+    use sha2::Digest;
+    let digest = sha2::Sha256::digest(token.as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 pub fn init_db(db: &mut rusqlite::Connection) -> Result<(), rusqlite::Error> {
@@ -206,3 +714,59 @@ mod embedded {
     use refinery::embed_migrations;
     embed_migrations!("./resources/configdb/migrations");
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_session_id_is_hashed() {
+        let repo = ConfigRepo::new(None).unwrap();
+        repo.add_session("session-id", "admin", Some(ROLE_ADMIN))
+            .unwrap();
+        let stored: String = repo
+            .db
+            .lock()
+            .unwrap()
+            .query_row("SELECT session_id FROM sessions", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(stored, hash_token("session-id"));
+
+        assert!(repo.get_session("session-id").unwrap().is_some());
+        assert!(repo.get_session(&stored).unwrap().is_none());
+        repo.touch_session("session-id").unwrap();
+        assert_eq!(repo.delete_session("session-id").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_token_last_used() {
+        let repo = ConfigRepo::new(None).unwrap();
+        repo.add_user("admin", "password").unwrap();
+        let (_, token) = repo.add_token("admin", None).unwrap();
+        assert!(repo.get_tokens().unwrap()[0].last_used.is_none());
+
+        repo.get_user_by_token(&token).unwrap().unwrap();
+        let last_used = repo.get_tokens().unwrap()[0].last_used.unwrap();
+
+        // Not written again until the interval has passed.
+        let earlier = last_used - TOKEN_TOUCH_INTERVAL + 5;
+        repo.db
+            .lock()
+            .unwrap()
+            .execute("UPDATE api_tokens SET last_used = ?", params![earlier])
+            .unwrap();
+        repo.get_user_by_token(&token).unwrap().unwrap();
+        assert_eq!(repo.get_tokens().unwrap()[0].last_used, Some(earlier));
+
+        let earlier = last_used - TOKEN_TOUCH_INTERVAL;
+        repo.db
+            .lock()
+            .unwrap()
+            .execute("UPDATE api_tokens SET last_used = ?", params![earlier])
+            .unwrap();
+        repo.get_user_by_token(&token).unwrap().unwrap();
+        assert!(repo.get_tokens().unwrap()[0].last_used.unwrap() >= last_used);
+    }
+}
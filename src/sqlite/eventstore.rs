@@ -26,9 +26,12 @@ use crate::eve::eve::EveJson;
 use rusqlite::{params, Connection, Error, ToSql};
 use serde_json::json;
 
-use crate::datastore::DatastoreError;
-use crate::elastic::AlertQueryOptions;
+use crate::datastore::{DatastoreError, HistogramInterval};
+use crate::elastic::{
+    AlertQueryOptions, ACTION_ARCHIVED, ACTION_COMMENT, ACTION_DEESCALATED, ACTION_ESCALATED,
+};
 use crate::server::api::AlertGroupSpec;
+use crate::server::session::Session;
 use crate::sqlite::ConnectionBuilder;
 use crate::{datastore, eve};
 
@@ -52,7 +55,7 @@ pub struct SQLiteEventStore {
     pub pool: deadpool_sqlite::Pool,
 }
 
-type QueryParam = dyn ToSql + Send + Sync + 'static;
+pub(crate) type QueryParam = dyn ToSql + Send + Sync + 'static;
 
 impl SQLiteEventStore {
     pub fn new(connection_builder: Arc<ConnectionBuilder>, pool: deadpool_sqlite::Pool) -> Self {
@@ -82,7 +85,8 @@ impl SQLiteEventStore {
                 b.mints as mints,
                 b.escalated_count,
                 a.archived,
-                a.source
+                a.source,
+                %HISTORY% AS history
             FROM events a
                 INNER JOIN
                 (
@@ -131,39 +135,13 @@ impl SQLiteEventStore {
             params.push(Box::new(ts.timestamp_nanos()));
         }
 
-        if let Some(query_string) = options.query_string {
-            let mut query_string = query_string.as_str();
-            let mut counter = 0;
-            loop {
-                counter += 1;
-                if counter > 128 {
-                    error!(
-                        "Aborting query string parsing, too many iterations: {}",
-                        query_string
-                    );
-                    break;
-                }
-                let (key, val, rem) = crate::sqlite::queryparser::parse_query_string(query_string);
-                if let Some(key) = key {
-                    if let Ok(val) = val.parse::<i64>() {
-                        filters.push(format!("json_extract(events.source, '$.{}') = ?", key));
-                        params.push(Box::new(val));
-                    } else {
-                        filters.push(format!("json_extract(events.source, '$.{}') LIKE ?", key));
-                        params.push(Box::new(format!("%{}%", val)));
-                    }
-                } else if !val.is_empty() {
-                    filters.push("events.source LIKE ?".into());
-                    params.push(Box::new(format!("%{}%", val)));
-                } else {
-                    break;
-                }
-                query_string = rem;
-            }
+        if let Some(query_string) = &options.query_string {
+            query_string_to_filters(query_string, &mut filters, &mut params);
         }
 
         let query = query.replace("%WHERE%", &filters.join(" AND "));
         let query = query.replace("%FROM%", &from.join(", "));
+        let query = query.replace("%HISTORY%", &history_subquery("a.rowid"));
 
         let map = |row: &rusqlite::Row| -> Result<serde_json::Value, rusqlite::Error> {
             let count: i64 = row.get(0)?;
@@ -173,6 +151,8 @@ impl SQLiteEventStore {
             let escalated_count: i64 = row.get(3)?;
             let archived: i8 = row.get(4)?;
             let mut parsed: serde_json::Value = row.get(5)?;
+            let history: serde_json::Value = row.get(6)?;
+            add_history(&mut parsed, history);
 
             if let serde_json::Value::Null = &parsed["tags"] {
                 let tags: Vec<String> = Vec::new();
@@ -213,6 +193,20 @@ impl SQLiteEventStore {
         return Ok(response);
     }
 
+    /// Run a query on a new connection, retrying on lock errors.
+    pub(crate) async fn query<F, T>(
+        &self,
+        query: &str,
+        params: &[Box<QueryParam>],
+        f: F,
+    ) -> Result<Vec<T>, DatastoreError>
+    where
+        F: FnMut(&rusqlite::Row<'_>) -> Result<T, rusqlite::Error> + Copy,
+    {
+        let mut conn = self.connection_builder.open()?;
+        Ok(self.retry_query_loop(&mut conn, query, params, f).await?)
+    }
+
     /// Run a database query in a loop as lock errors can occur, and we should retry those.
     async fn retry_query_loop<'a, F, T>(
         &'a self,
@@ -304,41 +298,8 @@ impl SQLiteEventStore {
         }
 
         // Query string.
-        if let Some(query_string) = options.query_string {
-            let mut query_string = query_string.as_str();
-            let mut counter = 0;
-            loop {
-                if query_string.is_empty() {
-                    // Nothing left to parse.
-                    break;
-                }
-
-                // Escape hatch in case of an infinite loop bug in the query parser as it could
-                // use more testing.
-                if counter > 100 {
-                    error!(
-                        "Aborting query string parsing, too many iterations: {}",
-                        query_string
-                    );
-                    break;
-                }
-
-                let (key, val, rem) = crate::sqlite::queryparser::parse_query_string(query_string);
-                if let Some(key) = key {
-                    if let Ok(val) = val.parse::<i64>() {
-                        filters.push(format!("json_extract(events.source, '$.{}') = ?", key));
-                        params.push(Box::new(val));
-                    } else {
-                        filters.push(format!("json_extract(events.source, '$.{}') LIKE ?", key));
-                        params.push(Box::new(format!("%{}%", val)));
-                    }
-                } else if !val.is_empty() {
-                    filters.push("events.source LIKE ?".into());
-                    params.push(Box::new(format!("%{}%", val)));
-                }
-                query_string = rem;
-                counter += 1;
-            }
+        if let Some(query_string) = &options.query_string {
+            query_string_to_filters(query_string, &mut filters, &mut params);
         }
 
         let order = if let Some(order) = options.order {
@@ -406,15 +367,20 @@ impl SQLiteEventStore {
         event_id: String,
     ) -> Result<Option<serde_json::Value>, DatastoreError> {
         let conn = self.connection.lock().unwrap();
-        let query = "SELECT rowid, archived, escalated, source FROM events WHERE rowid = ?";
+        let query = format!(
+            "SELECT rowid, archived, escalated, source, {} FROM events WHERE rowid = ?",
+            history_subquery("events.rowid")
+        );
         let params = params![event_id];
-        let mut stmt = conn.prepare(query)?;
+        let mut stmt = conn.prepare(&query)?;
         let mut rows = stmt.query(params)?;
         if let Some(row) = rows.next()? {
             let rowid: i64 = row.get(0)?;
             let archived: i8 = row.get(1)?;
             let escalated: i8 = row.get(2)?;
             let mut parsed: EveJson = row.get(3)?;
+            let history: serde_json::Value = row.get(4)?;
+            add_history(&mut parsed, history);
 
             if let serde_json::Value::Null = &parsed["tags"] {
                 let tags: Vec<String> = Vec::new();
@@ -446,45 +412,15 @@ impl SQLiteEventStore {
     pub async fn archive_by_alert_group(
         &self,
         alert_group: AlertGroupSpec,
+        session: Arc<Session>,
     ) -> Result<(), DatastoreError> {
         debug!("Archiving alert group: {:?}", alert_group);
-        let sql = "
-            UPDATE events
-            SET archived = 1
-            WHERE %WHERE%
-        ";
-
-        let mut filters: Vec<String> = Vec::new();
+        let mut filters: Vec<String> = vec!["archived = 0".to_string()];
         let mut params: Vec<Box<QueryParam>> = Vec::new();
-
-        filters.push("json_extract(events.source, '$.event_type') = ?".to_string());
-        params.push(Box::new("alert".to_string()));
-
-        filters.push("archived = 0".to_string());
-
-        filters.push("json_extract(events.source, '$.alert.signature_id') = ?".to_string());
-        params.push(Box::new(alert_group.signature_id as i64));
-
-        filters.push("json_extract(events.source, '$.src_ip') = ?".to_string());
-        params.push(Box::new(alert_group.src_ip));
-
-        filters.push("json_extract(events.source, '$.dest_ip') = ?".to_string());
-        params.push(Box::new(alert_group.dest_ip));
-
-        let mints = eve::parse_eve_timestamp(&alert_group.min_timestamp)?;
-        let mints_nanos = mints.timestamp_nanos();
-        filters.push("timestamp >= ?".to_string());
-        params.push(Box::new(mints_nanos));
-
-        let maxts = eve::parse_eve_timestamp(&alert_group.max_timestamp)?;
-        let maxts_nanos = maxts.timestamp_nanos();
-        filters.push("timestamp <= ?".to_string());
-        params.push(Box::new(maxts_nanos));
-
-        let sql = sql.replace("%WHERE%", &filters.join(" AND "));
-
-        let mut conn = self.connection_builder.open()?;
-        let n = self.retry_execute_loop(&mut conn, &sql, &params).await?;
+        alert_group_filters(&alert_group, &mut filters, &mut params)?;
+        let n = self
+            .update_with_history("archived = 1", &filters, &params, ACTION_ARCHIVED, &session)
+            .await?;
         debug!("Archived {} alerts", n);
         Ok(())
     }
@@ -502,7 +438,57 @@ impl SQLiteEventStore {
                     return Ok(n);
                 }
                 Err(err) => {
-                    if start_time.elapsed().as_millis() > 1000 {
+                    if !is_locked(&err) || start_time.elapsed().as_millis() > 1000 {
+                        return Err(err);
+                    }
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Update the events matching the filters, recording the action in the history of
+    /// each updated event. Like `retry_execute_loop`, lock errors are retried for up to
+    /// a second.
+    async fn update_with_history(
+        &self,
+        set: &str,
+        filters: &[String],
+        params: &[Box<QueryParam>],
+        action: &str,
+        session: &Session,
+    ) -> Result<usize, rusqlite::Error> {
+        let history_sql = format!(
+            "INSERT INTO history (event_id, timestamp, username, action)
+             SELECT rowid, ?, ?, ? FROM events WHERE {}",
+            filters.join(" AND ")
+        );
+        let update_sql = format!("UPDATE events SET {} WHERE {}", set, filters.join(" AND "));
+
+        let timestamp = now_nanos();
+        let username = session.username();
+        let history_params: Vec<&(dyn ToSql + Sync)> =
+            [&timestamp as &(dyn ToSql + Sync), &username, &action]
+                .into_iter()
+                .chain(params.iter().map(|p| p.as_ref() as &(dyn ToSql + Sync)))
+                .collect();
+
+        let mut conn = self.connection_builder.open()?;
+        let start_time = std::time::Instant::now();
+        loop {
+            let result = (|| {
+                let tx = conn.transaction()?;
+                tx.execute(&history_sql, rusqlite::params_from_iter(&history_params))?;
+                let n = tx.execute(&update_sql, rusqlite::params_from_iter(params))?;
+                tx.commit()?;
+                Ok(n)
+            })();
+            match result {
+                Ok(n) => {
+                    return Ok(n);
+                }
+                Err(err) => {
+                    if !is_locked(&err) || start_time.elapsed().as_millis() > 1000 {
                         return Err(err);
                     }
                 }
@@ -514,92 +500,284 @@ impl SQLiteEventStore {
     pub async fn escalate_by_alert_group(
         &self,
         alert_group: AlertGroupSpec,
+        session: Arc<Session>,
     ) -> Result<(), DatastoreError> {
-        let sql = "
-            UPDATE events
-            SET escalated = 1
+        let mut filters: Vec<String> = vec!["escalated = 0".to_string()];
+        let mut params: Vec<Box<QueryParam>> = Vec::new();
+        alert_group_filters(&alert_group, &mut filters, &mut params)?;
+        let n = self
+            .update_with_history(
+                "escalated = 1",
+                &filters,
+                &params,
+                ACTION_ESCALATED,
+                &session,
+            )
+            .await?;
+        info!("Escalated {} alerts in alert group", n);
+        Ok(())
+    }
+
+    pub async fn deescalate_by_alert_group(
+        &self,
+        alert_group: AlertGroupSpec,
+        session: Arc<Session>,
+    ) -> Result<(), DatastoreError> {
+        let mut filters: Vec<String> = vec!["escalated = 1".to_string()];
+        let mut params: Vec<Box<QueryParam>> = Vec::new();
+        alert_group_filters(&alert_group, &mut filters, &mut params)?;
+        let n = self
+            .update_with_history(
+                "escalated = 0",
+                &filters,
+                &params,
+                ACTION_DEESCALATED,
+                &session,
+            )
+            .await?;
+        info!("De-escalated {} alerts in alert group", n);
+        Ok(())
+    }
+
+    async fn update_event_by_id(
+        &self,
+        event_id: &str,
+        set: &str,
+        action: &str,
+        session: &Session,
+    ) -> Result<(), DatastoreError> {
+        let filters = vec!["rowid = ?".to_string()];
+        let params: Vec<Box<QueryParam>> = vec![Box::new(event_id.to_string())];
+        let n = self
+            .update_with_history(set, &filters, &params, action, session)
+            .await?;
+        if n == 0 {
+            Err(DatastoreError::EventNotFound)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn archive_event_by_id(
+        &self,
+        event_id: &str,
+        session: Arc<Session>,
+    ) -> Result<(), DatastoreError> {
+        self.update_event_by_id(event_id, "archived = 1", ACTION_ARCHIVED, &session)
+            .await
+    }
+
+    pub async fn escalate_event_by_id(
+        &self,
+        event_id: &str,
+        session: Arc<Session>,
+    ) -> Result<(), DatastoreError> {
+        self.update_event_by_id(event_id, "escalated = 1", ACTION_ESCALATED, &session)
+            .await
+    }
+
+    pub async fn deescalate_event_by_id(
+        &self,
+        event_id: &str,
+        session: Arc<Session>,
+    ) -> Result<(), DatastoreError> {
+        self.update_event_by_id(event_id, "escalated = 0", ACTION_DEESCALATED, &session)
+            .await
+    }
+
+    pub async fn histogram(
+        &self,
+        params: datastore::HistogramParameters,
+    ) -> Result<serde_json::Value, DatastoreError> {
+        let mut conn = self.connection_builder.open()?;
+
+        let query = r#"
+            SELECT
+                (timestamp / ?) * ? AS bucket,
+                count(*)
+            FROM events
             WHERE %WHERE%
-        ";
+            GROUP BY bucket
+            ORDER BY bucket ASC
+        "#;
+
+        let interval = histogram_interval_nanos(params.interval.as_ref());
 
         let mut filters: Vec<String> = Vec::new();
-        let mut params: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+        let mut args: Vec<Box<QueryParam>> = vec![Box::new(interval), Box::new(interval)];
 
-        filters.push("json_extract(events.source, '$.event_type') = ?".to_string());
-        params.push(Box::new("alert".to_string()));
+        filters.push("json_extract(events.source, '$.event_type') != 'stats'".to_string());
 
-        filters.push("escalated = 0".to_string());
+        if let Some(ts) = params.min_timestamp {
+            filters.push("timestamp >= ?".to_string());
+            args.push(Box::new(ts.timestamp_nanos()));
+        }
 
-        filters.push("json_extract(events.source, '$.alert.signature_id') = ?".to_string());
-        params.push(Box::new(alert_group.signature_id as i64));
+        if let Some(ts) = params.max_timestamp {
+            filters.push("timestamp <= ?".to_string());
+            args.push(Box::new(ts.timestamp_nanos()));
+        }
 
-        filters.push("json_extract(events.source, '$.src_ip') = ?".to_string());
-        params.push(Box::new(alert_group.src_ip));
+        if let Some(event_type) = params.event_type {
+            filters.push("json_extract(events.source, '$.event_type') = ?".to_string());
+            args.push(Box::new(event_type));
+        }
 
-        filters.push("json_extract(events.source, '$.dest_ip') = ?".to_string());
-        params.push(Box::new(alert_group.dest_ip));
+        if let Some(dns_type) = params.dns_type {
+            filters.push("json_extract(events.source, '$.dns.type') = ?".to_string());
+            args.push(Box::new(dns_type));
+        }
 
-        let mints = eve::parse_eve_timestamp(&alert_group.min_timestamp)?;
-        filters.push("timestamp >= ?".to_string());
-        params.push(Box::new(mints.timestamp_nanos()));
+        if let Some(sensor_name) = params.sensor_name {
+            if !sensor_name.is_empty() {
+                filters.push("json_extract(events.source, '$.host') = ?".to_string());
+                args.push(Box::new(sensor_name));
+            }
+        }
 
-        let maxts = eve::parse_eve_timestamp(&alert_group.max_timestamp)?;
-        filters.push("timestamp <= ?".to_string());
-        params.push(Box::new(maxts.timestamp_nanos()));
+        if let Some(address_filter) = params.address_filter {
+            filters.push(
+                "(json_extract(events.source, '$.src_ip') = ? OR json_extract(events.source, '$.dest_ip') = ?)"
+                    .to_string(),
+            );
+            args.push(Box::new(address_filter.clone()));
+            args.push(Box::new(address_filter));
+        }
 
-        let sql = sql.replace("%WHERE%", &filters.join(" AND "));
-        let conn = self.connection.lock().unwrap();
-        let n = conn.execute(&sql, rusqlite::params_from_iter(params))?;
-        info!("Escalated {} alerts in alert group", n);
-        Ok(())
+        if let Some(query_string) = params.query_string {
+            query_string_to_filters(&query_string, &mut filters, &mut args);
+        }
+
+        let query = query.replace("%WHERE%", &filters.join(" AND "));
+
+        let mapper = |row: &rusqlite::Row| -> Result<(i64, u64), rusqlite::Error> {
+            Ok((row.get(0)?, row.get(1)?))
+        };
+        let rows = self
+            .retry_query_loop(&mut conn, &query, &args, mapper)
+            .await?;
+
+        // Like Elasticsearch, fill in the empty buckets between the bounds of the request.
+        let bounds = params.min_timestamp.map(|min| {
+            let max = params.max_timestamp.unwrap_or_else(chrono::Utc::now);
+            (min.timestamp_nanos(), max.timestamp_nanos())
+        });
+        let rows = fill_histogram_buckets(rows, interval, bounds);
+
+        let mut data = Vec::new();
+        for (bucket, count) in rows {
+            data.push(json!({
+                "key": bucket / 1000000,
+                "count": count,
+                "key_as_string": nanos_to_rfc3339(bucket as i128)?,
+            }));
+        }
+
+        Ok(json!({
+            "data": data,
+        }))
     }
 
-    pub async fn deescalate_by_alert_group(
+    pub async fn agg(
         &self,
-        alert_group: AlertGroupSpec,
-    ) -> Result<(), DatastoreError> {
-        let sql = "
-            UPDATE events
-            SET escalated = 0
+        params: datastore::AggParameters,
+    ) -> Result<serde_json::Value, DatastoreError> {
+        let mut conn = self.connection_builder.open()?;
+
+        let query = r#"
+            SELECT
+                json_extract(events.source, ?) AS agg,
+                count(*) AS count
+            FROM events
             WHERE %WHERE%
-        ";
+            GROUP BY agg
+            ORDER BY count DESC
+            LIMIT ?
+        "#;
+
+        let field = format!("$.{}", params.agg);
 
         let mut filters: Vec<String> = Vec::new();
-        let mut params: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+        let mut args: Vec<Box<QueryParam>> = vec![Box::new(field.clone())];
 
-        filters.push("json_extract(events.source, '$.event_type') = ?".to_string());
-        params.push(Box::new("alert".to_string()));
+        // Like a terms aggregation, events missing the field are not counted.
+        filters.push("json_extract(events.source, ?) IS NOT NULL".to_string());
+        args.push(Box::new(field));
 
-        filters.push("escalated = 1".to_string());
+        if let Some(event_type) = params.event_type {
+            filters.push("json_extract(events.source, '$.event_type') = ?".to_string());
+            args.push(Box::new(event_type));
+        }
 
-        filters.push("json_extract(events.source, '$.alert.signature_id') = ?".to_string());
-        params.push(Box::new(alert_group.signature_id as i64));
+        if let Some(dns_type) = params.dns_type {
+            filters.push("json_extract(events.source, '$.dns.type') = ?".to_string());
+            args.push(Box::new(dns_type));
+        }
 
-        filters.push("json_extract(events.source, '$.src_ip') = ?".to_string());
-        params.push(Box::new(alert_group.src_ip));
+        if let Some(ts) = params.min_timestamp {
+            filters.push("timestamp >= ?".to_string());
+            args.push(Box::new(ts.timestamp_nanos()));
+        }
 
-        filters.push("json_extract(events.source, '$.dest_ip') = ?".to_string());
-        params.push(Box::new(alert_group.dest_ip));
+        if let Some(address_filter) = params.address_filter {
+            filters.push(
+                "(json_extract(events.source, '$.src_ip') = ? OR json_extract(events.source, '$.dest_ip') = ?)"
+                    .to_string(),
+            );
+            args.push(Box::new(address_filter.clone()));
+            args.push(Box::new(address_filter));
+        }
 
-        let mints = eve::parse_eve_timestamp(&alert_group.min_timestamp)?;
-        filters.push("timestamp >= ?".to_string());
-        params.push(Box::new(mints.timestamp_nanos()));
+        if let Some(query_string) = params.query_string {
+            query_string_to_filters(&query_string, &mut filters, &mut args);
+        }
 
-        let maxts = eve::parse_eve_timestamp(&alert_group.max_timestamp)?;
-        filters.push("timestamp <= ?".to_string());
-        params.push(Box::new(maxts.timestamp_nanos()));
+        args.push(Box::new(params.size as i64));
 
-        let sql = sql.replace("%WHERE%", &filters.join(" AND "));
-        let conn = self.connection.lock().unwrap();
-        let n = conn.execute(&sql, rusqlite::params_from_iter(params))?;
-        info!("De-escalated {} alerts in alert group", n);
-        Ok(())
+        let query = query.replace("%WHERE%", &filters.join(" AND "));
+
+        let mapper = |row: &rusqlite::Row| -> Result<serde_json::Value, rusqlite::Error> {
+            let key: rusqlite::types::Value = row.get(0)?;
+            let count: i64 = row.get(1)?;
+            let key = match key {
+                rusqlite::types::Value::Integer(v) => json!(v),
+                rusqlite::types::Value::Real(v) => json!(v),
+                rusqlite::types::Value::Text(v) => json!(v),
+                _ => serde_json::Value::Null,
+            };
+            Ok(json!({
+                "key": key,
+                "count": count,
+            }))
+        };
+        let data = self
+            .retry_query_loop(&mut conn, &query, &args, mapper)
+            .await?;
+
+        Ok(json!({
+            "data": data,
+        }))
     }
 
-    pub async fn archive_event_by_id(&self, event_id: &str) -> Result<(), DatastoreError> {
-        let conn = self.connection.lock().unwrap();
-        let query = "UPDATE events SET archived = 1 WHERE rowid = ?";
-        let params = params![event_id];
-        let n = conn.execute(query, params)?;
+    pub async fn comment_event_by_id(
+        &self,
+        event_id: &str,
+        comment: String,
+        session: Arc<Session>,
+    ) -> Result<(), DatastoreError> {
+        let sql = "
+            INSERT INTO history (event_id, timestamp, username, action, comment)
+            SELECT rowid, ?, ?, ?, ? FROM events WHERE rowid = ?
+        ";
+        let params: Vec<Box<QueryParam>> = vec![
+            Box::new(now_nanos()),
+            Box::new(session.username().to_string()),
+            Box::new(ACTION_COMMENT),
+            Box::new(comment),
+            Box::new(event_id.to_string()),
+        ];
+        let mut conn = self.connection_builder.open()?;
+        let n = self.retry_execute_loop(&mut conn, sql, &params).await?;
         if n == 0 {
             Err(DatastoreError::EventNotFound)
         } else {
@@ -607,28 +785,107 @@ impl SQLiteEventStore {
         }
     }
 
-    pub async fn escalate_event_by_id(&self, event_id: &str) -> Result<(), DatastoreError> {
-        let conn = self.connection.lock().unwrap();
-        let query = "UPDATE events SET escalated = 1 WHERE rowid = ?";
-        let params = params![event_id];
-        let n = conn.execute(query, params)?;
-        if n == 0 {
-            Err(DatastoreError::EventNotFound)
-        } else {
-            Ok(())
-        }
+    pub async fn comment_by_alert_group(
+        &self,
+        alert_group: AlertGroupSpec,
+        comment: String,
+        session: Arc<Session>,
+    ) -> Result<(), DatastoreError> {
+        let sql = "
+            INSERT INTO history (event_id, timestamp, username, action, comment)
+            SELECT rowid, ?, ?, ?, ? FROM events WHERE %WHERE%
+        ";
+        let mut filters: Vec<String> = Vec::new();
+        let mut params: Vec<Box<QueryParam>> = vec![
+            Box::new(now_nanos()),
+            Box::new(session.username().to_string()),
+            Box::new(ACTION_COMMENT),
+            Box::new(comment),
+        ];
+        alert_group_filters(&alert_group, &mut filters, &mut params)?;
+        let sql = sql.replace("%WHERE%", &filters.join(" AND "));
+        let mut conn = self.connection_builder.open()?;
+        let n = self.retry_execute_loop(&mut conn, &sql, &params).await?;
+        debug!("Commented on {} alerts in alert group", n);
+        Ok(())
     }
 
-    pub async fn deescalate_event_by_id(&self, event_id: &str) -> Result<(), DatastoreError> {
-        let conn = self.connection.lock().unwrap();
-        let query = "UPDATE events SET escalated = 0 WHERE rowid = ?";
-        let params = params![event_id];
-        let n = conn.execute(query, params)?;
-        if n == 0 {
-            Err(DatastoreError::EventNotFound)
-        } else {
-            Ok(())
+    pub async fn flow_histogram(
+        &self,
+        params: datastore::FlowHistogramParameters,
+    ) -> Result<serde_json::Value, DatastoreError> {
+        let interval = match &params.interval {
+            Some(interval) => humantime::parse_duration(interval)
+                .map_err(|err| anyhow!("failed to parse interval: {}: {}", interval, err))?,
+            None => std::time::Duration::from_secs(3600),
+        };
+        let interval = interval.as_nanos() as i64;
+        if interval <= 0 {
+            return Err(anyhow!("invalid interval: {:?}", params.interval).into());
         }
+
+        let mut filters = vec!["json_extract(events.source, '$.event_type') = 'flow'".to_string()];
+        let mut args: Vec<Box<QueryParam>> = vec![Box::new(interval), Box::new(interval)];
+
+        if let Some(mints) = params.mints {
+            filters.push("timestamp >= ?".to_string());
+            args.push(Box::new(mints.timestamp_nanos()));
+        }
+
+        if let Some(query_string) = &params.query_string {
+            query_string_to_filters(query_string, &mut filters, &mut args);
+        }
+
+        let query = format!(
+            "SELECT
+                (timestamp / ?) * ? AS bucket,
+                json_extract(events.source, '$.app_proto') AS app_proto,
+                count(*)
+             FROM events
+             WHERE {}
+             GROUP BY bucket, app_proto
+             ORDER BY bucket ASC",
+            filters.join(" AND ")
+        );
+
+        let mapper = |row: &rusqlite::Row| -> Result<(i64, Option<String>, u64), rusqlite::Error> {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        };
+        let rows = self.query(&query, &args, mapper).await?;
+
+        let mut data: Vec<serde_json::Value> = Vec::new();
+        let mut last_bucket = None;
+        for (bucket, app_proto, count) in rows {
+            if last_bucket != Some(bucket) {
+                data.push(json!({
+                    "key": bucket / 1000000,
+                    "events": 0,
+                    "app_proto": {},
+                }));
+                last_bucket = Some(bucket);
+            }
+            if let Some(entry) = data.last_mut() {
+                entry["events"] = (entry["events"].as_u64().unwrap_or(0) + count).into();
+                if let Some(app_proto) = app_proto {
+                    entry["app_proto"][app_proto] = count.into();
+                }
+            }
+        }
+
+        Ok(json!({
+            "data": data,
+        }))
+    }
+
+    /// Check that a connection can be taken from the pool and used.
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        self.pool
+            .get()
+            .await?
+            .interact(|conn| conn.query_row("SELECT 1", [], |_| Ok(())))
+            .await
+            .map_err(|err| anyhow::anyhow!("sqlite interact error:: {:?}", err))??;
+        Ok(())
     }
 
     pub async fn get_sensors(&self) -> anyhow::Result<Vec<String>> {
@@ -747,6 +1004,146 @@ impl SQLiteEventStore {
     }
 }
 
+/// Build the filters to select the events in an alert group.
+fn alert_group_filters(
+    alert_group: &AlertGroupSpec,
+    filters: &mut Vec<String>,
+    params: &mut Vec<Box<QueryParam>>,
+) -> Result<(), DatastoreError> {
+    filters.push("json_extract(events.source, '$.event_type') = ?".to_string());
+    params.push(Box::new("alert".to_string()));
+
+    filters.push("json_extract(events.source, '$.alert.signature_id') = ?".to_string());
+    params.push(Box::new(alert_group.signature_id as i64));
+
+    filters.push("json_extract(events.source, '$.src_ip') = ?".to_string());
+    params.push(Box::new(alert_group.src_ip.clone()));
+
+    filters.push("json_extract(events.source, '$.dest_ip') = ?".to_string());
+    params.push(Box::new(alert_group.dest_ip.clone()));
+
+    let mints = eve::parse_eve_timestamp(&alert_group.min_timestamp)?;
+    filters.push("timestamp >= ?".to_string());
+    params.push(Box::new(mints.timestamp_nanos()));
+
+    let maxts = eve::parse_eve_timestamp(&alert_group.max_timestamp)?;
+    filters.push("timestamp <= ?".to_string());
+    params.push(Box::new(maxts.timestamp_nanos()));
+
+    Ok(())
+}
+
+/// SQL subquery returning the history of the event with the given rowid as a JSON array
+/// formatted like the Elasticsearch history entries.
+fn history_subquery(rowid: &str) -> String {
+    format!(
+        r#"(SELECT json_group_array(json_object(
+                'username', username,
+                'timestamp', strftime('%Y-%m-%dT%H:%M:%fZ', timestamp / 1000000000.0, 'unixepoch'),
+                'action', action,
+                'comment', comment))
+            FROM (SELECT * FROM history WHERE history.event_id = {} ORDER BY timestamp))"#,
+        rowid
+    )
+}
+
+/// Add the history to the event under evebox.history, like Elasticsearch.
+fn add_history(event: &mut EveJson, history: serde_json::Value) {
+    if let serde_json::Value::Array(entries) = &history {
+        if entries.is_empty() {
+            return;
+        }
+        if !event["evebox"].is_object() {
+            event["evebox"] = json!({});
+        }
+        event["evebox"]["history"] = history;
+    }
+}
+
+/// Returns true if the error is due to the database being locked by another
+/// connection, in which case the statement can be retried.
+fn is_locked(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(err, _)
+            if err.code == rusqlite::ErrorCode::DatabaseBusy
+                || err.code == rusqlite::ErrorCode::DatabaseLocked
+    )
+}
+
+fn now_nanos() -> i64 {
+    chrono::Utc::now().timestamp_nanos()
+}
+
+/// Convert the query string into SQL filters and their parameters.
+pub(crate) fn query_string_to_filters(
+    query_string: &str,
+    filters: &mut Vec<String>,
+    params: &mut Vec<Box<QueryParam>>,
+) {
+    let mut query_string = query_string;
+    let mut counter = 0;
+    while !query_string.is_empty() {
+        // Escape hatch in case of an infinite loop bug in the query parser.
+        if counter > 100 {
+            error!(
+                "Aborting query string parsing, too many iterations: {}",
+                query_string
+            );
+            break;
+        }
+        let (key, val, rem) = crate::sqlite::queryparser::parse_query_string(query_string);
+        if let Some(key) = key {
+            // Bind the JSON path as a parameter, the key comes from the user.
+            params.push(Box::new(format!("$.{}", key)));
+            if let Ok(val) = val.parse::<i64>() {
+                filters.push("json_extract(events.source, ?) = ?".into());
+                params.push(Box::new(val));
+            } else {
+                filters.push("json_extract(events.source, ?) LIKE ?".into());
+                params.push(Box::new(format!("%{}%", val)));
+            }
+        } else if !val.is_empty() {
+            filters.push("events.source LIKE ?".into());
+            params.push(Box::new(format!("%{}%", val)));
+        }
+        query_string = rem;
+        counter += 1;
+    }
+}
+
+/// The histogram bucket size in nanoseconds, defaulting to an hour like Elasticsearch.
+fn histogram_interval_nanos(interval: Option<&HistogramInterval>) -> i64 {
+    let seconds = match interval {
+        Some(HistogramInterval::Minute) => 60,
+        Some(HistogramInterval::Hour) | None => 3600,
+        Some(HistogramInterval::Day) => 86400,
+    };
+    seconds * 1000000000
+}
+
+/// Fill in any buckets missing from the query results with a count of 0, extending to
+/// the provided bounds if any.
+fn fill_histogram_buckets(
+    rows: Vec<(i64, u64)>,
+    interval: i64,
+    bounds: Option<(i64, i64)>,
+) -> Vec<(i64, u64)> {
+    let (first, last) = match (bounds, rows.first(), rows.last()) {
+        (Some((min, max)), _, _) => ((min / interval) * interval, (max / interval) * interval),
+        (None, Some(first), Some(last)) => (first.0, last.0),
+        _ => return rows,
+    };
+    let counts: std::collections::HashMap<i64, u64> = rows.into_iter().collect();
+    let mut filled = Vec::new();
+    let mut bucket = first;
+    while bucket <= last {
+        filled.push((bucket, counts.get(&bucket).copied().unwrap_or(0)));
+        bucket += interval;
+    }
+    filled
+}
+
 fn sqlite_format_interval(duration: time::Duration) -> i64 {
     duration.whole_seconds()
 }
@@ -756,3 +1153,194 @@ fn nanos_to_rfc3339(nanos: i128) -> anyhow::Result<String> {
     let rfc3339 = ts.format(&time::format_description::well_known::Rfc3339)?;
     Ok(rfc3339)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::testing::sqlite_eventstore;
+
+    /// Import an alert, returning its event ID.
+    async fn add_alert(store: &SQLiteEventStore) -> String {
+        let mut importer = store.get_importer();
+        importer
+            .submit(json!({
+                "timestamp": "2022-05-01T12:00:00.000000+0000",
+                "event_type": "alert",
+                "src_ip": "10.16.1.10",
+                "dest_ip": "10.16.1.1",
+                "alert": {"signature_id": 2013028, "signature": "ET POLICY curl User-Agent"},
+            }))
+            .await
+            .unwrap();
+        importer.commit().await.unwrap();
+        let conn = store.connection.lock().unwrap();
+        let rowid: i64 = conn
+            .query_row("SELECT max(rowid) FROM events", params![], |row| row.get(0))
+            .unwrap();
+        rowid.to_string()
+    }
+
+    fn alert_group() -> AlertGroupSpec {
+        AlertGroupSpec {
+            signature_id: 2013028,
+            src_ip: "10.16.1.10".to_string(),
+            dest_ip: "10.16.1.1".to_string(),
+            min_timestamp: "2022-05-01T12:00:00.000000+0000".to_string(),
+            max_timestamp: "2022-05-01T12:00:00.000000+0000".to_string(),
+        }
+    }
+
+    fn session(username: &str) -> Arc<Session> {
+        let mut session = Session::new();
+        session.username = Some(username.to_string());
+        Arc::new(session)
+    }
+
+    async fn get_history(store: &SQLiteEventStore, event_id: &str) -> serde_json::Value {
+        let event = store
+            .get_event_by_id(event_id.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        event["_source"]["evebox"]["history"].clone()
+    }
+
+    #[tokio::test]
+    async fn test_comments() {
+        let store = sqlite_eventstore().await;
+        let event_id = add_alert(&store).await;
+
+        // No history yet.
+        let event = store
+            .get_event_by_id(event_id.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(event["_source"]["evebox"]["history"].is_null());
+
+        store
+            .comment_event_by_id(&event_id, "first".to_string(), session("alice"))
+            .await
+            .unwrap();
+        store
+            .comment_by_alert_group(alert_group(), "second".to_string(), session("bob"))
+            .await
+            .unwrap();
+
+        let history = get_history(&store, &event_id).await;
+        let history = history.as_array().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0]["action"], ACTION_COMMENT);
+        assert_eq!(history[0]["username"], "alice");
+        assert_eq!(history[0]["comment"], "first");
+        assert!(history[0]["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(history[1]["username"], "bob");
+        assert_eq!(history[1]["comment"], "second");
+
+        assert!(matches!(
+            store
+                .comment_event_by_id("999", "none".to_string(), session("alice"))
+                .await,
+            Err(DatastoreError::EventNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_query_string() {
+        let store = sqlite_eventstore().await;
+        add_alert(&store).await;
+        let query = |query_string: &str| {
+            store.event_query(crate::datastore::EventQueryParams {
+                query_string: Some(query_string.to_string()),
+                ..Default::default()
+            })
+        };
+
+        let response = query("alert.signature_id:2013028").await.unwrap();
+        assert_eq!(response["data"].as_array().unwrap().len(), 1);
+        let response = query("src_ip:10.16.1.10").await.unwrap();
+        assert_eq!(response["data"].as_array().unwrap().len(), 1);
+
+        // Keys are not interpolated into the SQL.
+        let response = query("src_ip')=1)+OR+(1:1").await.unwrap();
+        assert!(response["data"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fill_histogram_buckets() {
+        let interval = histogram_interval_nanos(Some(&HistogramInterval::Minute));
+        let rows = vec![(interval, 3), (interval * 3, 1)];
+
+        let filled = fill_histogram_buckets(rows.clone(), interval, None);
+        assert_eq!(
+            filled,
+            vec![(interval, 3), (interval * 2, 0), (interval * 3, 1)]
+        );
+
+        let filled = fill_histogram_buckets(rows, interval, Some((1, interval * 4 + 1)));
+        assert_eq!(
+            filled,
+            vec![
+                (0, 0),
+                (interval, 3),
+                (interval * 2, 0),
+                (interval * 3, 1),
+                (interval * 4, 0)
+            ]
+        );
+
+        assert!(fill_histogram_buckets(vec![], interval, None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_history_username() {
+        let store = sqlite_eventstore().await;
+        let event_id = add_alert(&store).await;
+
+        store
+            .escalate_by_alert_group(alert_group(), session("alice"))
+            .await
+            .unwrap();
+        store
+            .archive_event_by_id(&event_id, session("bob"))
+            .await
+            .unwrap();
+
+        let history = get_history(&store, &event_id).await;
+        let history = history.as_array().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0]["action"], ACTION_ESCALATED);
+        assert_eq!(history[0]["username"], "alice");
+        assert_eq!(history[1]["action"], ACTION_ARCHIVED);
+        assert_eq!(history[1]["username"], "bob");
+
+        // Already archived, so no history is added.
+        store
+            .archive_by_alert_group(alert_group(), session("carol"))
+            .await
+            .unwrap();
+        assert_eq!(
+            get_history(&store, &event_id)
+                .await
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_is_locked() {
+        let busy = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            None,
+        );
+        assert!(is_locked(&busy));
+        let constraint = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT),
+            None,
+        );
+        assert!(!is_locked(&constraint));
+        assert!(!is_locked(&rusqlite::Error::QueryReturnedNoRows));
+    }
+}
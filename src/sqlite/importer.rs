@@ -19,6 +19,7 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use crate::elastic::{TAG_ARCHIVED, TAG_ESCALATED};
 use crate::eve::eve::EveJson;
 use crate::eve::{self, Eve};
 use crate::prelude::*;
@@ -96,12 +97,33 @@ impl Importer {
         extract_values(&event, &mut values);
         reformat_timestamps(&mut event);
 
+        // Events archived or escalated by a filter are tagged, but that state
+        // is kept in its own columns.
+        let mut archived = false;
+        let mut escalated = false;
+        if let serde_json::Value::Array(tags) = &mut event["tags"] {
+            tags.retain(|tag| match tag.as_str() {
+                Some(TAG_ARCHIVED) => {
+                    archived = true;
+                    false
+                }
+                Some(TAG_ESCALATED) => {
+                    escalated = true;
+                    false
+                }
+                _ => true,
+            });
+        }
+
         eve::eve::add_evebox_metadata(&mut event, None);
 
         // Queue event insert.
-        let sql = "INSERT INTO events (timestamp, source) VALUES (?1, ?2)";
+        let sql =
+            "INSERT INTO events (timestamp, archived, escalated, source) VALUES (?1, ?2, ?3, ?4)";
         let params = vec![
             Value::I64(ts.timestamp_nanos()),
+            Value::I64(archived as i64),
+            Value::I64(escalated as i64),
             Value::String(event.to_string()),
         ];
         self.queue.push(QueuedRecord {
@@ -228,3 +250,32 @@ fn reformat_timestamp(ts: &str) -> String {
         ts.to_string()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_state_tags() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let mut importer = Importer::new(Arc::new(Mutex::new(conn)));
+        importer
+            .submit(json!({
+                "timestamp": "2022-05-01T12:00:00.000000+0000",
+                "event_type": "alert",
+                "tags": ["archived", "escalated", "evebox.escalated"],
+            }))
+            .await
+            .unwrap();
+        let params = &importer.queue[0].params;
+        assert!(matches!(params[1], Value::I64(0)));
+        assert!(matches!(params[2], Value::I64(1)));
+
+        // Only the namespaced tags are taken as state, others are kept.
+        let source = match &params[3] {
+            Value::String(source) => serde_json::from_str::<EveJson>(source).unwrap(),
+            _ => panic!("expected the source"),
+        };
+        assert_eq!(source["tags"], json!(["archived", "escalated"]));
+    }
+}
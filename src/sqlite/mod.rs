@@ -30,6 +30,7 @@ pub mod configrepo;
 pub mod eventstore;
 pub mod importer;
 pub mod queryparser;
+pub mod report;
 pub mod retention;
 
 pub async fn open_pool<T: Into<PathBuf>>(filename: T) -> anyhow::Result<deadpool_sqlite::Pool> {
@@ -86,7 +87,9 @@ impl ConnectionBuilder {
         let flags = OpenFlags::SQLITE_OPEN_READ_WRITE
             | OpenFlags::SQLITE_OPEN_CREATE
             | OpenFlags::SQLITE_OPEN_SHARED_CACHE
-            | OpenFlags::SQLITE_OPEN_FULL_MUTEX;
+            | OpenFlags::SQLITE_OPEN_FULL_MUTEX
+            // Allow a "file:" URI, such as a shared in-memory database for tests.
+            | OpenFlags::SQLITE_OPEN_URI;
         if let Some(filename) = &self.filename {
             let conn = rusqlite::Connection::open_with_flags(&filename, flags)?;
 
@@ -0,0 +1,240 @@
+// Copyright (C) 2022 Jason Ish
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use crate::datastore::{DatastoreError, EventQueryParams};
+use crate::sqlite::eventstore::{query_string_to_filters, QueryParam, SQLiteEventStore};
+use crate::types::JsonValue;
+use serde_json::json;
+
+pub async fn dhcp_report(
+    ds: &SQLiteEventStore,
+    what: &str,
+    params: &EventQueryParams,
+) -> Result<JsonValue, DatastoreError> {
+    let mut filters = vec!["json_extract(events.source, '$.event_type') = 'dhcp'".to_string()];
+    let mut args: Vec<Box<QueryParam>> = Vec::new();
+
+    if let Some(dt) = params.min_timestamp {
+        filters.push("timestamp >= ?".to_string());
+        args.push(Box::new(dt.timestamp_nanos()));
+    }
+
+    if let Some(query_string) = &params.query_string {
+        query_string_to_filters(query_string, &mut filters, &mut args);
+    }
+
+    match what {
+        "ack" => latest_by_client_mac(ds, "ack", filters, args).await,
+        "request" => latest_by_client_mac(ds, "request", filters, args).await,
+        "servers" => servers(ds, filters, args).await,
+        "mac" => mac(ds, filters, args).await,
+        "ip" => ip(ds, filters, args).await,
+        _ => Err(anyhow::anyhow!("No DHCP report for {}", what).into()),
+    }
+}
+
+/// Return the most recent event of the given DHCP type for each client MAC address.
+async fn latest_by_client_mac(
+    ds: &SQLiteEventStore,
+    dhcp_type: &str,
+    mut filters: Vec<String>,
+    mut args: Vec<Box<QueryParam>>,
+) -> Result<JsonValue, DatastoreError> {
+    filters.push("json_extract(events.source, '$.dhcp.dhcp_type') = ?".to_string());
+    args.push(Box::new(dhcp_type.to_string()));
+
+    // SQLite returns the bare source column from the row with the max timestamp.
+    let sql = format!(
+        "SELECT events.source, max(timestamp) AS maxts
+         FROM events
+         WHERE {}
+         GROUP BY json_extract(events.source, '$.dhcp.client_mac')
+         ORDER BY maxts DESC",
+        filters.join(" AND ")
+    );
+
+    let mapper = |row: &rusqlite::Row| -> Result<JsonValue, rusqlite::Error> {
+        let event: JsonValue = row.get(0)?;
+        Ok(map_dhcp_event(&event))
+    };
+    let results = ds.query(&sql, &args, mapper).await?;
+
+    Ok(json!({
+        "data": results,
+    }))
+}
+
+/// Return all IP addresses that appear to be DHCP servers.
+async fn servers(
+    ds: &SQLiteEventStore,
+    mut filters: Vec<String>,
+    args: Vec<Box<QueryParam>>,
+) -> Result<JsonValue, DatastoreError> {
+    filters.push("json_extract(events.source, '$.dhcp.type') = 'reply'".to_string());
+
+    let sql = format!(
+        "SELECT json_extract(events.source, '$.src_ip') AS ip, count(*) AS count
+         FROM events
+         WHERE {}
+         GROUP BY ip
+         ORDER BY count DESC",
+        filters.join(" AND ")
+    );
+
+    let mapper = |row: &rusqlite::Row| -> Result<JsonValue, rusqlite::Error> {
+        let ip: Option<String> = row.get(0)?;
+        let count: i64 = row.get(1)?;
+        Ok(json!({
+            "ip": ip,
+            "count": count,
+        }))
+    };
+    let results = ds.query(&sql, &args, mapper).await?;
+
+    Ok(json!({
+        "data": results,
+    }))
+}
+
+/// For each client MAC address seen, return a list of IP addresses the MAC has
+/// been assigned.
+async fn mac(
+    ds: &SQLiteEventStore,
+    mut filters: Vec<String>,
+    args: Vec<Box<QueryParam>>,
+) -> Result<JsonValue, DatastoreError> {
+    filters.push("json_extract(events.source, '$.dhcp.type') = 'reply'".to_string());
+    let pairs =
+        distinct_pairs(ds, "$.dhcp.client_mac", "$.dhcp.assigned_ip", filters, args).await?;
+
+    let mut results: Vec<JsonValue> = Vec::new();
+    for (mac, addrs) in group_pairs(pairs) {
+        // Not really interested in 0.0.0.0.
+        let addrs: Vec<String> = addrs.into_iter().filter(|a| a != "0.0.0.0").collect();
+        results.push(json!({
+            "mac": mac,
+            "addrs": addrs,
+        }));
+    }
+
+    Ok(json!({
+        "data": results,
+    }))
+}
+
+/// For each assigned IP address, return a list of MAC addresses that have been
+/// assigned that IP address.
+async fn ip(
+    ds: &SQLiteEventStore,
+    mut filters: Vec<String>,
+    args: Vec<Box<QueryParam>>,
+) -> Result<JsonValue, DatastoreError> {
+    filters.push("json_extract(events.source, '$.dhcp.type') = 'reply'".to_string());
+    filters.push("json_extract(events.source, '$.dhcp.assigned_ip') != '0.0.0.0'".to_string());
+    let pairs =
+        distinct_pairs(ds, "$.dhcp.assigned_ip", "$.dhcp.client_mac", filters, args).await?;
+
+    let mut results: Vec<JsonValue> = Vec::new();
+    for (ip, macs) in group_pairs(pairs) {
+        results.push(json!({
+            "ip": ip,
+            "macs": macs,
+        }));
+    }
+
+    Ok(json!({
+        "data": results,
+    }))
+}
+
+/// Select the distinct pairs of values for 2 fields, ordered by the number of events the
+/// first field was seen in.
+async fn distinct_pairs(
+    ds: &SQLiteEventStore,
+    key: &str,
+    value: &str,
+    filters: Vec<String>,
+    args: Vec<Box<QueryParam>>,
+) -> Result<Vec<(String, Option<String>)>, DatastoreError> {
+    let sql = format!(
+        "SELECT json_extract(events.source, '{key}') AS key,
+             json_extract(events.source, '{value}') AS value,
+             sum(count(*)) OVER (PARTITION BY json_extract(events.source, '{key}')) AS count
+         FROM events
+         WHERE {where} AND key IS NOT NULL
+         GROUP BY key, value
+         ORDER BY count DESC, key, value",
+        key = key,
+        value = value,
+        where = filters.join(" AND ")
+    );
+    let mapper = |row: &rusqlite::Row| -> Result<(String, Option<String>), rusqlite::Error> {
+        Ok((row.get(0)?, row.get(1)?))
+    };
+    ds.query(&sql, &args, mapper).await
+}
+
+/// Group sorted (key, value) pairs into a list of values per key, preserving order.
+fn group_pairs(pairs: Vec<(String, Option<String>)>) -> Vec<(String, Vec<String>)> {
+    let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+    for (key, value) in pairs {
+        match groups.last_mut() {
+            Some((last, values)) if *last == key => values.extend(value),
+            _ => groups.push((key, value.into_iter().collect())),
+        }
+    }
+    groups
+}
+
+fn map_dhcp_event(event: &JsonValue) -> JsonValue {
+    json!({
+        "timestamp": event["timestamp"],
+        "sensor": event["host"],
+        "client_mac": event["dhcp"]["client_mac"],
+        "hostname": event["dhcp"]["hostname"],
+        "lease_time": event["dhcp"]["lease_time"],
+        "assigned_ip": event["dhcp"]["assigned_ip"],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::group_pairs;
+
+    #[test]
+    fn test_group_pairs() {
+        let pairs = vec![
+            ("a".to_string(), Some("1".to_string())),
+            ("a".to_string(), Some("2".to_string())),
+            ("b".to_string(), None),
+            ("c".to_string(), Some("3".to_string())),
+        ];
+        let groups = group_pairs(pairs);
+        assert_eq!(
+            groups,
+            vec![
+                ("a".to_string(), vec!["1".to_string(), "2".to_string()]),
+                ("b".to_string(), vec![]),
+                ("c".to_string(), vec!["3".to_string()]),
+            ]
+        );
+    }
+}
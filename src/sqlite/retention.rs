@@ -20,6 +20,7 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use crate::prelude::*;
+use crate::shutdown::CancellationToken;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
@@ -33,24 +34,43 @@ pub struct RetentionConfig {
     pub days: u64,
 }
 
-pub fn retention_task(config: RetentionConfig, conn: Arc<Mutex<rusqlite::Connection>>) {
+/// Delete events older than the retention period until shutdown.
+pub async fn retention_task(
+    config: RetentionConfig,
+    conn: Arc<Mutex<rusqlite::Connection>>,
+    shutdown: CancellationToken,
+) {
+    let config = Arc::new(config);
     let default_delay = Duration::from_secs(DELAY);
     let report_interval = Duration::from_secs(60);
 
     // Delay on startup.
-    std::thread::sleep(default_delay);
+    let mut delay = default_delay;
 
     let mut last_report = Instant::now();
     let mut count: u64 = 0;
 
     loop {
-        let mut delay = default_delay;
-        match do_retention(&config, conn.clone()) {
-            Ok(n) => {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.cancelled() => break,
+        }
+        delay = default_delay;
+        let result = {
+            let config = config.clone();
+            let conn = conn.clone();
+            tokio::task::spawn_blocking(move || do_retention(&config, conn)).await
+        };
+        match result {
+            Ok(Ok(n)) => {
                 if n == LIMIT {
                     delay = Duration::from_secs(1);
                 }
                 count += n;
+                crate::metrics::RETENTION_DELETED.inc_by(n);
+            }
+            Ok(Err(err)) => {
+                error!("Database retention job failed: {}", err);
             }
             Err(err) => {
                 error!("Database retention job failed: {}", err);
@@ -61,7 +81,6 @@ pub fn retention_task(config: RetentionConfig, conn: Arc<Mutex<rusqlite::Connect
             count = 0;
             last_report = Instant::now();
         }
-        std::thread::sleep(delay);
     }
 }
 
@@ -146,16 +146,30 @@ export class ApiService {
             });
     }
 
-    login(username: string = "", password: string = ""): Promise<boolean> {
+    /**
+     * Login, resolving to true once logged in, or to the response if the
+     * login must be completed with loginTotp using its login_token.
+     */
+    login(username: string = "", password: string = ""): Promise<LoginResponse | boolean> {
         return this.client.login(username, password).toPromise()
-            .then((response: LoginResponse) => {
-                this.setSessionId(response.session_id);
-                console.log("Login successful, updating configuration");
-                return this.updateConfig()
-                    .then(() => {
-                        this.setAuthenticated(true);
-                        return true;
-                    });
+            .then((response: LoginResponse) => this.loggedIn(response));
+    }
+
+    loginTotp(loginToken: string, code: string): Promise<LoginResponse | boolean> {
+        return this.client.loginTotp(loginToken, code).toPromise()
+            .then((response: LoginResponse) => this.loggedIn(response));
+    }
+
+    private loggedIn(response: LoginResponse): Promise<LoginResponse | boolean> {
+        if (response.totp_required) {
+            return Promise.resolve(response);
+        }
+        this.setSessionId(response.session_id);
+        console.log("Login successful, updating configuration");
+        return this.updateConfig()
+            .then(() => {
+                this.setAuthenticated(true);
+                return true;
             });
     }
 
@@ -36,7 +36,10 @@ const SESSION_HEADER = "x-evebox-session-id";
 declare var localStorage: any;
 
 export interface LoginResponse {
-    session_id: string;
+    session_id?: string;
+    // Set when the login must be completed with a TOTP code.
+    totp_required?: boolean;
+    login_token?: string;
 }
 
 @Injectable()
@@ -132,9 +135,23 @@ export class ClientService {
         let params = new HttpParams()
             .append("username", username)
             .append("password", password);
+        return this.postLogin(params);
+    }
+
+    loginTotp(loginToken: string, code: string): Observable<LoginResponse> {
+        let params = new HttpParams()
+            .append("login_token", loginToken)
+            .append("totp", code);
+        return this.postLogin(params);
+    }
+
+    private postLogin(params: HttpParams): Observable<LoginResponse> {
         return this.http.post(this.buildUrl("api/1/login"), params)
             .pipe(
                 map((response: LoginResponse) => {
+                    if (response.totp_required) {
+                        return response;
+                    }
                     console.log(`Got session ID: ${response.session_id}`);
     //                this.setAuthenticated(true);
                     this.setSessionId(response.session_id);
@@ -27,7 +27,7 @@
 import {AfterViewInit, Component, OnInit} from "@angular/core";
 import {ActivatedRoute, Router} from "@angular/router";
 import {ApiService} from "../api.service";
-import {ClientService} from "../client.service";
+import {ClientService, LoginResponse} from "../client.service";
 
 declare var window: any;
 
@@ -39,10 +39,15 @@ export class LoginComponent implements OnInit, AfterViewInit {
     model: any = {
         username: "",
         password: "",
+        totp: "",
     };
 
+    // Set while waiting on the TOTP code to complete the login.
+    loginToken: string = null;
+
     username = false;
     password = false;
+    oidc = false;
 
     error: string;
 
@@ -78,6 +83,19 @@ export class LoginComponent implements OnInit, AfterViewInit {
                                 this.username = true;
                                 this.password = true;
                                 break;
+                            case "oidc":
+                                // Go straight to the identity provider,
+                                // unless returning from a failed login.
+                                this.oidc = true;
+                                if (!this.error) {
+                                    this.loginOidc();
+                                }
+                                break;
+                            case "reverse-proxy":
+                                // The proxy has already authenticated the
+                                // user, so just login.
+                                this.login();
+                                break;
                         }
                     }
                 }
@@ -101,19 +119,49 @@ export class LoginComponent implements OnInit, AfterViewInit {
         }
     }
 
+    loginOidc() {
+        window.location.href = "api/1/login/oidc";
+    }
+
     login() {
         console.log("Calling api.login...");
-        this.api.login(this.model.username, this.model.password)
-            .then(() => {
+        this.loginDone(this.api.login(this.model.username, this.model.password));
+    }
+
+    loginTotp() {
+        this.loginDone(this.api.loginTotp(this.loginToken, this.model.totp));
+    }
+
+    private loginDone(login: Promise<LoginResponse | boolean>) {
+        login
+            .then((response) => {
+                if (response !== true) {
+                    console.log("Login requires a TOTP code");
+                    this.loginToken = (<LoginResponse>response).login_token;
+                    this.error = null;
+                    setTimeout(() => {
+                        let em = document.getElementById("totp");
+                        if (em) {
+                            em.focus();
+                        }
+                    }, 0);
+                    return;
+                }
                 console.log("Login successful, redirecting to /");
                 this.router.navigate(["/"]);
             })
             .catch(error => {
                 console.log(`Login failed:`);
                 console.log(error);
+                this.model.totp = "";
                 if (error.status === 401) {
                     this.error = "Login failed";
                 }
+                else if (error.status === 429) {
+                    // Locked out, start over with the password.
+                    this.loginToken = null;
+                    this.error = "Too many failed logins, try again later";
+                }
                 else {
                     this.error = "Login failed: " + JSON.stringify(error);
                 }